@@ -1,8 +1,20 @@
 use crate::prelude::{Executor, StorageType, Trigger};
+use crate::runtime::history::RunHistory;
+use crate::runtime::limit::ActiveRuns;
+use crate::runtime::pool::ResourcePools;
+use crate::runtime::RunnableMetadata;
 use crate::service::Service;
-use crate::store::Storage;
+use crate::store::{RunnableKey, Storage};
 
+use crate::task::backfill::Backfill;
+use crate::task::dag::DAG;
+use crate::task::dag_run::DAGRun;
+use crate::task::state::DAGRunReport;
 use crate::task::RunnableHolder;
+use bronzeflow_time::prelude::ScheduleExpr;
+use bronzeflow_time::schedule_time::ScheduleTime;
+use bronzeflow_utils::{ayn_error, info, Result};
+use serde_json::Value;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 
@@ -12,6 +24,11 @@ pub struct ScheduleManager<SG: Storage + 'static, TG: Trigger + 'static, E: Exec
     executor: Arc<Mutex<E>>,
     dag_id: AtomicU64,
     task_id: AtomicU64,
+    active_runs: ActiveRuns,
+    /// The active runs of the submitted tasks, whose ids are apart from the DAG ids
+    active_task_runs: ActiveRuns,
+    pools: ResourcePools,
+    history: RunHistory,
 }
 
 impl<SG: Storage, TG: Trigger, E: Executor> ScheduleManager<SG, TG, E> {
@@ -23,17 +40,174 @@ impl<SG: Storage, TG: Trigger, E: Executor> ScheduleManager<SG, TG, E> {
             trigger: Arc::new(Mutex::new(trigger)),
             dag_id: AtomicU64::new(0),
             task_id: AtomicU64::new(0),
+            active_runs: ActiveRuns::new(),
+            active_task_runs: ActiveRuns::new(),
+            pools: ResourcePools::new(),
+            history: RunHistory::new(),
         }
     }
 
-    pub fn add_runnable(&mut self, mut runnable: RunnableHolder) {
+    /// The resource pools shared by the tasks of all submitted DAGs
+    pub fn pools(&self) -> &ResourcePools {
+        &self.pools
+    }
+
+    /// The records of the runs of the submitted DAGs
+    pub fn history(&self) -> &RunHistory {
+        &self.history
+    }
+
+    /// The active runs of the submitted DAGs, keyed by the DAG id
+    pub fn active_runs(&self) -> &ActiveRuns {
+        &self.active_runs
+    }
+
+    /// Add a runnable to the storage, return the id assigned to it
+    ///
+    /// Return an error if a task of the DAG is in a pool which does not exist or is too small
+    /// for it
+    pub fn add_runnable(&mut self, mut runnable: RunnableHolder) -> Result<u64> {
+        if let RunnableHolder::Dag(ref dag) = runnable {
+            self.check_pools(dag)?;
+        }
+        let id = match runnable {
+            RunnableHolder::Dag(_) => self.dag_id.fetch_add(1, Ordering::Relaxed),
+            RunnableHolder::Task(_) => self.task_id.fetch_add(1, Ordering::Relaxed),
+        };
+        self.attach(&mut runnable, id);
+        self.storage.lock().unwrap().save_runnable(runnable);
+        Ok(id)
+    }
+
+    /// Stop triggering the runnable of `key`, its runs in progress go on
+    pub fn pause(&self, key: &RunnableKey) -> Result<()> {
+        self.storage.lock().unwrap().set_paused(key, true)
+    }
+
+    /// Trigger the runnable of `key` again, from its next schedule time
+    pub fn resume(&self, key: &RunnableKey) -> Result<()> {
+        self.storage.lock().unwrap().set_paused(key, false)
+    }
+
+    pub fn update_schedule(&self, key: &RunnableKey, schedule: ScheduleExpr) -> Result<()> {
+        self.storage.lock().unwrap().set_schedule(key, schedule)
+    }
+
+    /// Replace the runnable of `key`, the new one keeps the id, the name, the run times and
+    /// the paused state of the old one. A DAG without a schedule keeps the schedule of the
+    /// old one
+    pub fn replace_runnable(
+        &mut self,
+        key: &RunnableKey,
+        mut runnable: RunnableHolder,
+    ) -> Result<()> {
+        if let RunnableHolder::Dag(ref dag) = runnable {
+            self.check_pools(dag)?;
+        }
+        let mut storage = self.storage.lock().unwrap();
+        let old = storage
+            .find_runnable(key)
+            .and_then(|mut r| r.time_holder())
+            .ok_or_else(|| ayn_error!("No runnable with {}", key))?;
+        let old = old.lock().unwrap().clone();
+        if let RunnableHolder::Dag(ref mut dag) = runnable {
+            match old.schedule {
+                Some(ref schedule) if !dag.has_schedule() => dag.prepare_with(schedule.clone()),
+                _ => dag.prepare(),
+            }
+        }
+        let meta = runnable
+            .time_holder()
+            .ok_or_else(|| ayn_error!("The new runnable of {} has no metadata", key))?;
+        {
+            let mut meta = meta.lock().unwrap();
+            meta.name = meta.name.take().or(old.name);
+            meta.run_times = old.run_times;
+            meta.paused = old.paused;
+            if meta.schedule.is_none() {
+                meta.schedule = old.schedule;
+            }
+        }
+        self.attach(&mut runnable, old.id.unwrap_or_default());
+        storage.replace_runnable(key, runnable)
+    }
+
+    /// Run the DAG of `key` now with `params`, whether it is paused or not. The run is
+    /// submitted to the executor like a scheduled run and recorded as manual, its id is
+    /// returned
+    pub fn trigger_now(
+        &self,
+        key: &RunnableKey,
+        params: Value,
+        logical_time: Option<ScheduleTime>,
+    ) -> Result<String> {
+        let dag = self.find_dag(key)?;
+        let mut run = DAGRun::new(&dag).manual().with_params(params);
+        if let Some(logical_time) = logical_time {
+            run = run.with_logical_time(logical_time);
+        }
+        let run_id = run.run_id().to_string();
+        info!("Trigger run {} of {:?} by hand", run_id, dag.name());
+        run.start(Arc::clone(&self.executor), true);
+        Ok(run_id)
+    }
+
+    /// Backfill the DAG of `key` with the executor of the manager, the runs are recorded in
+    /// the run history. Block until all runs have finished
+    pub fn backfill(
+        &self,
+        key: &RunnableKey,
+        backfill: &Backfill,
+    ) -> Result<Vec<(ScheduleTime, DAGRunReport)>> {
+        let dag = self.find_dag(key)?;
+        backfill.run(&dag, Arc::clone(&self.executor))
+    }
+
+    fn find_dag(&self, key: &RunnableKey) -> Result<DAG> {
+        match self.storage.lock().unwrap().find_runnable(key) {
+            Some(RunnableHolder::Dag(dag)) => Ok(dag),
+            Some(RunnableHolder::Task(_)) => Err(ayn_error!("Runnable with {} is not a DAG", key)),
+            None => Err(ayn_error!("No runnable with {}", key)),
+        }
+    }
+
+    /// Remove the runnable of `key`, its runs in progress go on
+    pub fn remove_runnable(&self, key: &RunnableKey) -> Result<RunnableHolder> {
+        self.storage.lock().unwrap().remove_runnable(key)
+    }
+
+    /// Check the pools of the tasks of `dag` and of its sub-DAGs
+    fn check_pools(&self, dag: &DAG) -> Result<()> {
+        for node in dag.index().nodes {
+            let node = node.lock().unwrap();
+            if let Some(RunnableMetadata {
+                pool: Some((ref pool, cost)),
+                ref name,
+                ..
+            }) = node.meta
+            {
+                self.pools
+                    .check(pool, cost)
+                    .map_err(|e| ayn_error!("Task {:?} can not run in its pool: {}", name, e))?;
+            }
+            if let Some(ref sub) = node.sub_dag {
+                self.check_pools(sub)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Set the id of the runnable and the ids of its tasks, share the state of the manager with it
+    fn attach(&self, runnable: &mut RunnableHolder, id: u64) {
         match runnable {
             RunnableHolder::Dag(ref mut dag) => {
                 // set dag id
                 if let Some(ref mut meta) = dag.meta {
-                    meta.lock()
-                        .unwrap()
-                        .set_id(self.dag_id.fetch_add(1, Ordering::Relaxed));
+                    let mut meta = meta.lock().unwrap();
+                    meta.set_id(id);
+                    meta.active_runs = Some(self.active_runs.clone());
+                    meta.pools = Some(self.pools.clone());
+                    meta.history = Some(self.history.clone());
                 }
                 // set task id for all tasks in this dag
                 dag.for_all_task(|task| {
@@ -45,13 +219,12 @@ impl<SG: Storage, TG: Trigger, E: Executor> ScheduleManager<SG, TG, E> {
             RunnableHolder::Task(ref mut t) => {
                 // set task id
                 if let Some(ref mut meta) = t.meta {
-                    meta.lock()
-                        .unwrap()
-                        .set_id(self.task_id.fetch_add(1, Ordering::Relaxed));
+                    let mut meta = meta.lock().unwrap();
+                    meta.set_id(id);
+                    meta.active_runs = Some(self.active_task_runs.clone());
                 }
             },
         }
-        self.storage.lock().unwrap().save_runnable(runnable)
     }
 
     fn start_loader(&mut self) {
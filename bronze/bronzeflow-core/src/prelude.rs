@@ -1,16 +1,32 @@
 pub use crate::dag;
 pub use crate::executor::{DefaultExecutor, Executor, ThreadExecutor};
+pub use crate::runtime::command::{CommandOutput, CommandTask};
+pub use crate::runtime::context::{CancellationToken, RunContext, TaskOutputs};
+pub use crate::runtime::external::ExternalDependency;
+pub use crate::runtime::history::{RunHistory, RunRecord, RunState};
+pub use crate::runtime::limit::{ActiveRuns, OverlapPolicy, ParallelismPolicy};
+pub use crate::runtime::pool::ResourcePools;
+pub use crate::runtime::retry::{Backoff, RetryPolicy};
+pub use crate::runtime::sensor::{Sensor, SensorMode};
+pub use crate::runtime::sla::{Sla, SlaMiss};
 #[cfg(feature = "async")]
-pub use crate::runtime::AsyncFn;
-pub use crate::runtime::{BronzeRuntime, Runnable, RuntimeJoinHandle, SyncFn};
+pub use crate::runtime::{AsyncContextFn, AsyncFn, TryAsyncFn};
+pub use crate::runtime::{
+    BranchFn, BronzeRuntime, Runnable, RunnableMetadata, RuntimeJoinHandle, SyncContextFn, SyncFn,
+    TrySyncFn,
+};
 pub use crate::session::{
     DefaultSessionFactory, LocalSession, LocalSessionFactory, Session, SessionBuilder,
 };
+pub use crate::task::backfill::Backfill;
 pub use crate::task::builder::DAGBuilder;
 pub use crate::task::dag::DAG;
+pub use crate::task::definition::{DAGDefinition, TaskRegistry};
+pub use crate::task::state::{DAGRunReport, TaskState, TriggerRule, WeightRule};
+pub use crate::task::validate::DAGError;
 pub use crate::task::TaskInfo;
 
-pub use crate::store::StorageType;
+pub use crate::store::{RunnableKey, StorageType};
 pub use crate::trigger::{ThreadTrigger, Trigger, TriggerCaller, TriggerCallerType};
 
 pub use bronzeflow_time::prelude::*;
@@ -0,0 +1,79 @@
+// This is a part of bronze.
+
+//! Callbacks, the functions called when the outcome of a task or a DAG run is known
+//!
+//! A callback receives the [`RunContext`] of the task and the error if the task failed or is
+//! retried. The callbacks of a DAG receive a context without a task name. The callbacks are
+//! submitted to the executor like the tasks, except `on_sla_miss`, which is called by the
+//! thread watching the deadlines, so a busy executor does not delay it. A panic in a callback
+//! is logged and ignored.
+
+use crate::runtime::context::RunContext;
+use crate::runtime::{Runnable, RuntimeJoinHandle};
+use bronzeflow_utils::{error, BronzeError};
+use std::fmt::{Debug, Formatter};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+
+pub type Callback = Arc<dyn Fn(&RunContext, Option<&BronzeError>) + Send + Sync>;
+
+#[derive(Clone, Default)]
+pub struct Callbacks {
+    pub(crate) on_success: Option<Callback>,
+    /// Called after the last attempt failed or timed out
+    pub(crate) on_failure: Option<Callback>,
+    /// Called when a failed attempt is retried
+    pub(crate) on_retry: Option<Callback>,
+    pub(crate) on_skip: Option<Callback>,
+    /// Called when the SLA deadline passed before the task or the run finished
+    pub(crate) on_sla_miss: Option<Callback>,
+}
+
+impl Callbacks {
+    /// Override the callbacks which are set in `other`
+    pub(crate) fn merge(&mut self, other: Callbacks) {
+        let Callbacks {
+            on_success,
+            on_failure,
+            on_retry,
+            on_skip,
+            on_sla_miss,
+        } = other;
+        self.on_success = on_success.or(self.on_success.take());
+        self.on_failure = on_failure.or(self.on_failure.take());
+        self.on_retry = on_retry.or(self.on_retry.take());
+        self.on_skip = on_skip.or(self.on_skip.take());
+        self.on_sla_miss = on_sla_miss.or(self.on_sla_miss.take());
+    }
+}
+
+impl Debug for Callbacks {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Callbacks")
+            .field("on_success", &self.on_success.is_some())
+            .field("on_failure", &self.on_failure.is_some())
+            .field("on_retry", &self.on_retry.is_some())
+            .field("on_skip", &self.on_skip.is_some())
+            .field("on_sla_miss", &self.on_sla_miss.is_some())
+            .finish()
+    }
+}
+
+/// A callback with its arguments, to be submitted to the executor
+pub(crate) struct CallbackRunner {
+    pub(crate) callback: Callback,
+    pub(crate) ctx: RunContext,
+    pub(crate) error: Option<Arc<BronzeError>>,
+}
+
+impl Runnable for CallbackRunner {
+    type Handle = RuntimeJoinHandle<()>;
+
+    fn run_async(&self) -> Self::Handle {
+        let run = || (self.callback)(&self.ctx, self.error.as_deref());
+        if panic::catch_unwind(AssertUnwindSafe(run)).is_err() {
+            error!("A callback of {:?} panicked", self.ctx.task_name());
+        }
+        RuntimeJoinHandle::SyncJobHandle
+    }
+}
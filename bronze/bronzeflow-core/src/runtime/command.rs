@@ -0,0 +1,352 @@
+// This is a part of bronze.
+
+//! CommandTask, run a program as a task
+//!
+//! The lines which the program writes to stdout and stderr are logged with the name of the
+//! task while it runs, and they are kept in the [`CommandOutput`] of the task with the exit
+//! code, so the downstream tasks could read them. A nonzero exit code fails the task, the
+//! output is saved anyway. The program is killed if it runs longer than the timeout of the
+//! command, or when the DAG run is cancelled, the output written before is saved too. On unix
+//! the program runs in its own process group, which is killed as a whole, so the processes it
+//! started are killed with it. The processes left in the group when the program exits are
+//! killed too, and the output is read for a bounded time, so a process which holds the pipes
+//! does not block the task.
+//!
+//! The program reads the run from the environment variables `BRONZE_RUN_ID`,
+//! `BRONZE_LOGICAL_TIME` and `BRONZE_PARAMS`, the parameters in JSON, so it could process the
+//! data of the logical time when it is backfilled or triggered by hand.
+
+use crate::runtime::context::RunContext;
+use crate::runtime::{Runnable, RuntimeJoinHandle};
+use bronzeflow_utils::{ayn_error, info, warn, BronzeError, Result};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Read};
+#[cfg(unix)]
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How often a running program is checked for exit, timeout and cancellation
+const WAIT_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How long the output of a finished or killed program is read before it is saved
+const DRAIN_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// The output of a finished program
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommandOutput {
+    /// `None` if the program was terminated by a signal
+    pub exit_code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct CommandTask {
+    program: String,
+    args: Vec<String>,
+    envs: Vec<(String, String)>,
+    current_dir: Option<PathBuf>,
+    timeout: Option<Duration>,
+}
+
+impl CommandTask {
+    pub fn new(program: &str) -> Self {
+        CommandTask {
+            program: program.to_string(),
+            args: vec![],
+            envs: vec![],
+            current_dir: None,
+            timeout: None,
+        }
+    }
+
+    /// Run `script` with `sh -c`, or `cmd /C` on Windows
+    pub fn shell(script: &str) -> Self {
+        if cfg!(windows) {
+            CommandTask::new("cmd").args(["/C", script])
+        } else {
+            CommandTask::new("sh").args(["-c", script])
+        }
+    }
+
+    pub fn arg(mut self, arg: &str) -> Self {
+        self.args.push(arg.to_string());
+        self
+    }
+
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.args
+            .extend(args.into_iter().map(|a| a.as_ref().to_string()));
+        self
+    }
+
+    /// Set an environment variable of the program, the program inherits the others
+    pub fn env(mut self, key: &str, value: &str) -> Self {
+        self.envs.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    /// The working directory of the program, the current directory by default
+    pub fn current_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.current_dir = Some(dir.into());
+        self
+    }
+
+    /// Kill the program if it runs longer than `timeout`
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Run the program and wait for it, an error is returned if it could not start or it was
+    /// killed, the output is returned whatever the exit code is. The output of a killed
+    /// program is saved in `ctx` before the error is returned
+    pub fn execute(&self, ctx: &RunContext) -> Result<CommandOutput> {
+        let name = ctx.task_name().unwrap_or(&self.program).to_string();
+        let mut command = Command::new(&self.program);
+        command.env("BRONZE_RUN_ID", ctx.run_id());
+        if let Some(t) = ctx.logical_time() {
+            command.env("BRONZE_LOGICAL_TIME", t.to_string());
+        }
+        if !ctx.params().is_null() {
+            command.env("BRONZE_PARAMS", ctx.params().to_string());
+        }
+        command
+            .args(&self.args)
+            .envs(self.envs.iter().map(|(k, v)| (k, v)))
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        if let Some(ref dir) = self.current_dir {
+            command.current_dir(dir);
+        }
+        #[cfg(unix)]
+        command.process_group(0);
+        let mut child = command
+            .spawn()
+            .map_err(|e| ayn_error!("Could not start command `{}`: {}", self.program, e))?;
+        let stdout = child.stdout.take().map(|out| {
+            let name = name.clone();
+            log_lines(out, move |line| info!("[{}] {}", name, line))
+        });
+        let stderr = child.stderr.take().map(|err| {
+            let name = name.clone();
+            log_lines(err, move |line| warn!("[{}] {}", name, line))
+        });
+
+        let started = Instant::now();
+        let status = loop {
+            if let Some(status) = child.try_wait().map_err(BronzeError::new)? {
+                break status;
+            }
+            let reason = match self.timeout {
+                _ if ctx.is_cancelled() => Some("the run was cancelled".to_string()),
+                Some(timeout) if started.elapsed() >= timeout => {
+                    Some(format!("it timed out after {:?}", timeout))
+                },
+                _ => None,
+            };
+            if let Some(reason) = reason {
+                kill(&mut child);
+                let status = child.wait().ok();
+                // The readers are not waited for long, a process which left the group may
+                // still hold the pipes
+                let output = CommandOutput {
+                    exit_code: status.and_then(|s| s.code()),
+                    stdout: drain(stdout),
+                    stderr: drain(stderr),
+                };
+                ctx.save_output(output)?;
+                return Err(ayn_error!(
+                    "Command `{}` was killed, {}",
+                    self.program,
+                    reason
+                ));
+            }
+            thread::sleep(WAIT_INTERVAL);
+        };
+        // The processes started in the background may still hold the pipes
+        #[cfg(unix)]
+        kill(&mut child);
+        Ok(CommandOutput {
+            exit_code: status.code(),
+            stdout: drain(stdout),
+            stderr: drain(stderr),
+        })
+    }
+}
+
+/// Kill the process group of `child` on unix, only `child` itself elsewhere
+fn kill(child: &mut Child) {
+    #[cfg(unix)]
+    {
+        // The group id is the id of the child, which is the leader of its group
+        if unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) } == 0 {
+            return;
+        }
+    }
+    child.kill().ok();
+}
+
+/// The reader thread of a pipe, with the lines read so far
+type Lines = (JoinHandle<()>, Arc<Mutex<String>>);
+
+/// Read `reader` line by line in a new thread, pass every line to `log` and collect them
+fn log_lines<R, F>(reader: R, log: F) -> Lines
+where
+    R: Read + Send + 'static,
+    F: Fn(&str) + Send + 'static,
+{
+    let all = Arc::new(Mutex::new(String::new()));
+    let lines = Arc::clone(&all);
+    let handle = thread::spawn(move || {
+        for line in BufReader::new(reader)
+            .lines()
+            .map_while(std::result::Result::ok)
+        {
+            log(&line);
+            let mut all = lines.lock().unwrap();
+            all.push_str(&line);
+            all.push('\n');
+        }
+    });
+    (handle, all)
+}
+
+/// The lines read by the reader of a finished or killed program, it is waited for at most
+/// [`DRAIN_TIMEOUT`]
+fn drain(lines: Option<Lines>) -> String {
+    let Some((handle, all)) = lines else {
+        return String::new();
+    };
+    let started = Instant::now();
+    while !handle.is_finished() && started.elapsed() < DRAIN_TIMEOUT {
+        thread::sleep(WAIT_INTERVAL);
+    }
+    let all = all.lock().unwrap();
+    all.clone()
+}
+
+impl Runnable for CommandTask {
+    type Handle = RuntimeJoinHandle<()>;
+
+    #[inline(always)]
+    fn run_async(&self) -> Self::Handle {
+        self.run_with_context(RunContext::default())
+    }
+
+    fn run_with_context(&self, ctx: RunContext) -> Self::Handle {
+        let result = self
+            .execute(&ctx)
+            .and_then(|output| match output.exit_code {
+                Some(0) => Ok(output),
+                code => {
+                    let error = match code {
+                        Some(code) => {
+                            ayn_error!("Command `{}` exited with code {}", self.program, code)
+                        },
+                        None => ayn_error!("Command `{}` was terminated by a signal", self.program),
+                    };
+                    // Keep the output of a failed command for the downstream tasks
+                    ctx.save_output(output)?;
+                    Err(error)
+                },
+            });
+        ctx.report(result);
+        RuntimeJoinHandle::SyncJobHandle
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::runtime::context::TaskOutputs;
+
+    fn context() -> RunContext {
+        RunContext::new(Some("cmd".to_string()), TaskOutputs::new())
+    }
+
+    #[test]
+    fn capture_output() {
+        let ctx = context();
+        CommandTask::shell("echo \"$GREETING\"; pwd; echo oops >&2")
+            .env("GREETING", "hello")
+            .current_dir("/")
+            .run_with_context(ctx.clone());
+        assert_eq!(None, ctx.error());
+        assert_eq!(
+            CommandOutput {
+                exit_code: Some(0),
+                stdout: "hello\n/\n".to_string(),
+                stderr: "oops\n".to_string(),
+            },
+            ctx.output::<CommandOutput>("cmd").unwrap()
+        );
+    }
+
+    #[test]
+    fn fail_on_nonzero_exit_code() {
+        let ctx = context();
+        CommandTask::new("sh")
+            .args(["-c", "echo partial; exit 3"])
+            .run_with_context(ctx.clone());
+        assert!(ctx.error().unwrap().contains("exited with code 3"));
+        let output = ctx.output::<CommandOutput>("cmd").unwrap();
+        assert_eq!(
+            (Some(3), "partial\n"),
+            (output.exit_code, &output.stdout[..])
+        );
+
+        let ctx = context();
+        CommandTask::new("bronze-no-such-program").run_with_context(ctx.clone());
+        assert!(ctx.error().unwrap().contains("Could not start"));
+    }
+
+    #[test]
+    fn kill_on_timeout() {
+        let ctx = context();
+        let started = Instant::now();
+        CommandTask::new("sleep")
+            .arg("10")
+            .timeout(Duration::from_millis(100))
+            .run_with_context(ctx.clone());
+        assert!(ctx.error().unwrap().contains("timed out"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn do_not_wait_for_background_process() {
+        let ctx = context();
+        let started = Instant::now();
+        CommandTask::shell("echo done; sleep 60 &").run_with_context(ctx.clone());
+        assert_eq!(None, ctx.error());
+        let output = ctx.output::<CommandOutput>("cmd").unwrap();
+        assert_eq!((Some(0), "done\n"), (output.exit_code, &output.stdout[..]));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn kill_process_group_and_keep_output() {
+        let ctx = context();
+        let marker = std::env::temp_dir().join(format!("bronze-cmd-{}", std::process::id()));
+        std::fs::remove_file(&marker).ok();
+        let script = format!("echo started; (sleep 1; touch {}) & wait", marker.display());
+        CommandTask::shell(&script)
+            .timeout(Duration::from_millis(300))
+            .run_with_context(ctx.clone());
+        assert!(ctx.error().unwrap().contains("timed out"));
+        let output = ctx.output::<CommandOutput>("cmd").unwrap();
+        assert_eq!((None, "started\n"), (output.exit_code, &output.stdout[..]));
+        // The background process was killed with the shell
+        thread::sleep(Duration::from_millis(1500));
+        assert!(!marker.exists());
+    }
+}
@@ -0,0 +1,320 @@
+// This is a part of bronze.
+
+//! RunContext, the information of the current run which is passed to a runnable
+//!
+//! The outputs of all tasks in one DAG run are saved in the same [`TaskOutputs`], so a task
+//! could read the output of its upstream tasks by their names. The information of the run,
+//! like the run id and the logical schedule time, is shared by all tasks of the run, so a task
+//! could partition its work by the logical time and stay idempotent when it is run again.
+
+use crate::runtime::history::RunHistory;
+use bronzeflow_time::schedule_time::ScheduleTime;
+use bronzeflow_utils::{ayn_error, error, BronzeError, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The outputs of the tasks in one run, keyed by task name
+#[derive(Debug, Clone, Default)]
+pub struct TaskOutputs(Arc<Mutex<HashMap<String, Value>>>);
+
+impl TaskOutputs {
+    pub fn new() -> Self {
+        TaskOutputs::default()
+    }
+
+    /// Read the output of task `task_name` as type `T`
+    pub fn get<T: DeserializeOwned>(&self, task_name: &str) -> Result<T> {
+        let value = self
+            .0
+            .lock()
+            .unwrap()
+            .get(task_name)
+            .cloned()
+            .ok_or_else(|| ayn_error!("No output of task `{}`", task_name))?;
+        serde_json::from_value(value).map_err(BronzeError::new)
+    }
+
+    pub fn contains(&self, task_name: &str) -> bool {
+        self.0.lock().unwrap().contains_key(task_name)
+    }
+
+    /// All outputs as an object keyed by task name
+    pub(crate) fn to_value(&self) -> Value {
+        Value::Object(self.0.lock().unwrap().clone().into_iter().collect())
+    }
+
+    pub fn set<T: Serialize>(&self, task_name: &str, value: T) -> Result<()> {
+        let value = serde_json::to_value(value).map_err(BronzeError::new)?;
+        self.0.lock().unwrap().insert(task_name.to_string(), value);
+        Ok(())
+    }
+}
+
+/// Tell the tasks of a run to stop, a task has to check [`CancellationToken::is_cancelled`]
+/// by itself
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        CancellationToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// The information shared by all tasks of one DAG run
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RunInfo {
+    pub(crate) dag_id: Option<u64>,
+    pub(crate) dag_name: Option<String>,
+    pub(crate) run_id: String,
+    /// The schedule time which triggered the run, `None` for a manual run without a logical time
+    pub(crate) logical_time: Option<ScheduleTime>,
+    /// Whether the run was triggered by hand, not by the schedule
+    pub(crate) manual: bool,
+    pub(crate) params: Value,
+    pub(crate) cancel: CancellationToken,
+    /// The run history of the manager, to find the runs of other DAGs
+    pub(crate) history: Option<RunHistory>,
+}
+
+impl RunInfo {
+    /// Create a run id which is unique in the current process, like `run_1700000000000_0`
+    pub(crate) fn new_run_id() -> String {
+        static SEQ: AtomicU64 = AtomicU64::new(0);
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis());
+        format!("run_{}_{}", millis, SEQ.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RunContext {
+    pub(crate) run: Arc<RunInfo>,
+    pub(crate) task_id: Option<u64>,
+    pub(crate) task_name: Option<String>,
+    pub(crate) outputs: TaskOutputs,
+    /// The error reported by the task, shared by all copies of the context
+    pub(crate) error: Arc<Mutex<Option<Arc<BronzeError>>>>,
+    /// The number of the current attempt, starts from 1
+    pub(crate) attempt: u32,
+    /// The names of the children to follow, chosen by a branch task
+    pub(crate) branch: Arc<Mutex<Option<Vec<String>>>>,
+    /// The index and the item of an instance of a mapped task
+    pub(crate) map_index: Option<usize>,
+    pub(crate) map_item: Option<Value>,
+    /// The delay asked by a task to be submitted again, `None` if the runner of the task
+    /// could not submit it again
+    pub(crate) reschedule: Option<Arc<Mutex<Option<Duration>>>>,
+    /// The time a rescheduled task was first submitted
+    pub(crate) first_poke: Option<Instant>,
+}
+
+impl Default for RunContext {
+    fn default() -> Self {
+        RunContext::new(None, TaskOutputs::new())
+    }
+}
+
+impl RunContext {
+    pub fn new(task_name: Option<String>, outputs: TaskOutputs) -> Self {
+        RunContext {
+            run: Arc::new(RunInfo::default()),
+            task_id: None,
+            task_name,
+            outputs,
+            error: Arc::new(Mutex::new(None)),
+            attempt: 1,
+            branch: Arc::new(Mutex::new(None)),
+            map_index: None,
+            map_item: None,
+            reschedule: None,
+            first_poke: None,
+        }
+    }
+
+    /// The id of the DAG, it is set when the DAG is submitted to a session
+    pub fn dag_id(&self) -> Option<u64> {
+        self.run.dag_id
+    }
+
+    pub fn dag_name(&self) -> Option<&str> {
+        self.run.dag_name.as_deref()
+    }
+
+    /// The id of the task, it is set when the DAG is submitted to a session
+    pub fn task_id(&self) -> Option<u64> {
+        self.task_id
+    }
+
+    /// The name of the task which is running
+    pub fn task_name(&self) -> Option<&str> {
+        self.task_name.as_deref()
+    }
+
+    /// The id of the current DAG run, shared by all tasks of the run
+    pub fn run_id(&self) -> &str {
+        &self.run.run_id
+    }
+
+    /// The schedule time which triggered the run, `None` if the run was not scheduled
+    pub fn logical_time(&self) -> Option<&ScheduleTime> {
+        self.run.logical_time.as_ref()
+    }
+
+    /// Whether the run was triggered by hand, not by the schedule
+    pub fn is_manual(&self) -> bool {
+        self.run.manual
+    }
+
+    /// The parameters of the run
+    pub fn params(&self) -> &Value {
+        &self.run.params
+    }
+
+    /// Read the parameter `key` of the run as type `T`
+    pub fn param<T: DeserializeOwned>(&self, key: &str) -> Result<T> {
+        let value = self
+            .run
+            .params
+            .get(key)
+            .cloned()
+            .ok_or_else(|| ayn_error!("No parameter `{}` in the run", key))?;
+        serde_json::from_value(value).map_err(BronzeError::new)
+    }
+
+    pub fn cancel_token(&self) -> &CancellationToken {
+        &self.run.cancel
+    }
+
+    /// Whether the run was cancelled, a long running task should stop when it is
+    pub fn is_cancelled(&self) -> bool {
+        self.run.cancel.is_cancelled()
+    }
+
+    /// The number of the current attempt, it is greater than 1 if the task is retried
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// The index of the current instance of a mapped task
+    pub fn map_index(&self) -> Option<usize> {
+        self.map_index
+    }
+
+    /// Read the item of the current instance of a mapped task as type `T`
+    pub fn map_item<T: DeserializeOwned>(&self) -> Result<T> {
+        let item = self
+            .map_item
+            .clone()
+            .ok_or_else(|| ayn_error!("Task {:?} is not a mapped instance", self.task_name()))?;
+        serde_json::from_value(item).map_err(BronzeError::new)
+    }
+
+    pub fn outputs(&self) -> &TaskOutputs {
+        &self.outputs
+    }
+
+    /// Read the output of an upstream task in the same run
+    pub fn output<T: DeserializeOwned>(&self, task_name: &str) -> Result<T> {
+        self.outputs.get(task_name)
+    }
+
+    /// Report the result of the current task
+    ///
+    /// The `Ok` value is saved as the output of the task, an error marks the task as failed
+    pub fn report<T: Serialize>(&self, result: Result<T>) {
+        if let Err(e) = result.and_then(|v| self.save_output(v)) {
+            error!("Task {:?} failed: {}", self.task_name(), e);
+            *self.error.lock().unwrap() = Some(Arc::new(e));
+        }
+    }
+
+    /// Only run the children named in `children` after the current task, the other children
+    /// and the tasks which only depend on them are skipped
+    pub fn follow<S: AsRef<str>>(&self, children: &[S]) {
+        *self.branch.lock().unwrap() =
+            Some(children.iter().map(|c| c.as_ref().to_string()).collect());
+    }
+
+    /// The children chosen by [`RunContext::follow`], `None` means all children are followed
+    pub fn followed(&self) -> Option<Vec<String>> {
+        self.branch.lock().unwrap().clone()
+    }
+
+    /// Ask to submit the current task again after `delay` instead of finishing it, the task
+    /// gives its worker back in the meantime. Return false if the task could not be submitted
+    /// again, like when it does not run in a DAG run
+    pub fn reschedule(&self, delay: Duration) -> bool {
+        match self.reschedule {
+            Some(ref reschedule) => {
+                *reschedule.lock().unwrap() = Some(delay);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// The delay asked by [`RunContext::reschedule`]
+    pub(crate) fn rescheduled(&self) -> Option<Duration> {
+        self.reschedule.as_ref().and_then(|r| *r.lock().unwrap())
+    }
+
+    /// The error reported by the current task
+    pub fn error(&self) -> Option<String> {
+        self.failure().map(|e| e.to_string())
+    }
+
+    pub(crate) fn failure(&self) -> Option<Arc<BronzeError>> {
+        self.error.lock().unwrap().clone()
+    }
+
+    /// Save the output of the current task, a nameless task has no output
+    pub(crate) fn save_output<T: Serialize>(&self, value: T) -> Result<()> {
+        match self.task_name {
+            Some(ref name) => self.outputs.set(name, value),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_and_write_outputs() {
+        let outputs = TaskOutputs::new();
+        let ctx = RunContext::new(Some("A".to_string()), outputs.clone());
+        ctx.save_output(vec![1, 2, 3]).unwrap();
+
+        let other = RunContext::new(Some("B".to_string()), outputs);
+        assert_eq!(vec![1, 2, 3], other.output::<Vec<i32>>("A").unwrap());
+        assert!(other.output::<String>("A").is_err());
+        assert!(other.output::<i32>("C").is_err());
+    }
+
+    #[test]
+    fn report_error() {
+        let ctx = RunContext::new(Some("A".to_string()), TaskOutputs::new());
+        ctx.clone().report(Ok(1));
+        assert_eq!(None, ctx.error());
+
+        ctx.clone()
+            .report::<i32>(Err(BronzeError::msg("could not connect")));
+        assert_eq!(Some("could not connect".to_string()), ctx.error());
+    }
+}
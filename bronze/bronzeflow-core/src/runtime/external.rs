@@ -0,0 +1,214 @@
+// This is a part of bronze.
+
+//! ExternalDependency, a task which waits for a run of another DAG
+//!
+//! The other DAG is found by its name in the [`RunHistory`] of the manager which both DAGs are
+//! submitted to. By default the dependency waits for the run of the other DAG with the same
+//! logical time as the current run, the logical time could be mapped, like to the day
+//! before. A run which was not scheduled waits for the latest run of the other DAG.
+//!
+//! The dependency is a [`Sensor`], it succeeds when the other run or its task succeeded, and
+//! fails when it failed or was skipped, or when the timeout has passed.
+//!
+//! [`RunHistory`]: crate::runtime::history::RunHistory
+
+use crate::runtime::context::RunContext;
+use crate::runtime::history::RunState;
+use crate::runtime::sensor::{Sensor, SensorMode};
+use crate::runtime::{Runnable, RuntimeJoinHandle};
+use bronzeflow_time::schedule_time::ScheduleTime;
+use bronzeflow_utils::{ayn_error, Result};
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub type LogicalTimeMapping = Arc<dyn Fn(&ScheduleTime) -> ScheduleTime + Send + Sync>;
+
+#[derive(Clone)]
+pub struct ExternalDependency {
+    dag_name: String,
+    task_name: Option<String>,
+    logical_time: Option<LogicalTimeMapping>,
+    interval: Duration,
+    timeout: Option<Duration>,
+    mode: SensorMode,
+}
+
+impl ExternalDependency {
+    /// Wait for the run of DAG `dag_name`
+    pub fn new(dag_name: &str) -> Self {
+        ExternalDependency {
+            dag_name: dag_name.to_string(),
+            task_name: None,
+            logical_time: None,
+            interval: Duration::from_secs(10),
+            timeout: None,
+            mode: SensorMode::default(),
+        }
+    }
+
+    /// Only wait for task `task_name` of the other run
+    pub fn task(mut self, task_name: &str) -> Self {
+        self.task_name = Some(task_name.to_string());
+        self
+    }
+
+    /// Wait for the run whose logical time is `offset` earlier than the current one
+    pub fn offset(self, offset: chrono::Duration) -> Self {
+        self.logical_time(move |t| ScheduleTime::new(t.datetime() - offset))
+    }
+
+    /// Map the logical time of the current run to the logical time of the other run
+    pub fn logical_time<F>(mut self, mapping: F) -> Self
+    where
+        F: Fn(&ScheduleTime) -> ScheduleTime + Send + Sync + 'static,
+    {
+        self.logical_time = Some(Arc::new(mapping));
+        self
+    }
+
+    /// The time to wait between two checks, 10 seconds by default
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Fail if the other run has not finished after `timeout`
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn mode(mut self, mode: SensorMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Whether the other run or its task succeeded, an error if it failed
+    fn check(&self, ctx: &RunContext) -> Result<bool> {
+        let history =
+            ctx.run.history.as_ref().ok_or_else(|| {
+                ayn_error!("No run history, the DAG is not submitted to a session")
+            })?;
+        let logical_time = match (ctx.logical_time(), &self.logical_time) {
+            (Some(t), Some(mapping)) => Some(mapping(t)),
+            (t, _) => t.cloned(),
+        };
+        let Some(run) = history.latest(&self.dag_name, logical_time.as_ref()) else {
+            return Ok(false);
+        };
+        let Some(ref task_name) = self.task_name else {
+            return match run.state {
+                RunState::Running => Ok(false),
+                RunState::Success => Ok(true),
+                state => Err(ayn_error!(
+                    "Run {} of DAG `{}` is {:?}",
+                    run.run_id,
+                    self.dag_name,
+                    state
+                )),
+            };
+        };
+        match run.task_state(task_name) {
+            Some(state) if state.is_success() => Ok(true),
+            Some(state) => Err(ayn_error!(
+                "Task `{}` of DAG `{}` is {:?} in run {}",
+                task_name,
+                self.dag_name,
+                state,
+                run.run_id
+            )),
+            None if run.state.is_finished() => Err(ayn_error!(
+                "No task `{}` in run {} of DAG `{}`",
+                task_name,
+                run.run_id,
+                self.dag_name
+            )),
+            None => Ok(false),
+        }
+    }
+
+    fn sensor(&self) -> Sensor {
+        let dependency = self.clone();
+        let sensor = Sensor::new(move |ctx| dependency.check(ctx))
+            .interval(self.interval)
+            .mode(self.mode);
+        match self.timeout {
+            Some(timeout) => sensor.timeout(timeout),
+            None => sensor,
+        }
+    }
+}
+
+impl Runnable for ExternalDependency {
+    type Handle = RuntimeJoinHandle<()>;
+
+    #[inline(always)]
+    fn run_async(&self) -> Self::Handle {
+        self.run_with_context(RunContext::default())
+    }
+
+    fn run_with_context(&self, ctx: RunContext) -> Self::Handle {
+        self.sensor().run_with_context(ctx)
+    }
+}
+
+impl Debug for ExternalDependency {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ExternalDependency")
+            .field("dag_name", &self.dag_name)
+            .field("task_name", &self.task_name)
+            .field("interval", &self.interval)
+            .field("timeout", &self.timeout)
+            .field("mode", &self.mode)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::context::{RunInfo, TaskOutputs};
+    use crate::runtime::history::RunHistory;
+    use crate::task::state::{DAGRunReport, TaskState};
+
+    fn day(d: u32) -> ScheduleTime {
+        format!("2024-01-0{}T00:00:00Z", d).parse().unwrap()
+    }
+
+    #[test]
+    fn check_the_run_of_mapped_logical_time() {
+        let history = RunHistory::new();
+        let ctx = RunContext {
+            run: Arc::new(RunInfo {
+                logical_time: Some(day(2)),
+                history: Some(history.clone()),
+                ..RunInfo::default()
+            }),
+            ..RunContext::new(Some("wait".to_string()), TaskOutputs::new())
+        };
+        let yesterday = ExternalDependency::new("ingest").offset(chrono::Duration::days(1));
+        assert!(!yesterday.check(&ctx).unwrap());
+
+        let name = Some("ingest".to_string());
+        history.start(None, name.clone(), "r1", Some(day(1)), false);
+        history.start(None, name.clone(), "r2", Some(day(2)), false);
+        assert!(!yesterday.check(&ctx).unwrap());
+        history.set_task("r1", "load", &TaskState::Failed("oops".to_string()));
+        let report = DAGRunReport::new(vec![("load".to_string(), TaskState::Success)]);
+        history.finish("r2", RunState::Success, &report);
+        // The run of the same logical time has finished, but it is not the one to wait for
+        assert!(!yesterday.check(&ctx).unwrap());
+        assert!(ExternalDependency::new("ingest").check(&ctx).unwrap());
+
+        let err = yesterday.clone().task("load").check(&ctx).unwrap_err();
+        assert!(err.to_string().contains("Failed"));
+        assert!(!yesterday.clone().task("clean").check(&ctx).unwrap());
+        history.finish("r1", RunState::Failed, &report);
+        assert!(yesterday.check(&ctx).is_err());
+
+        assert!(ExternalDependency::new("ingest")
+            .check(&RunContext::default())
+            .is_err());
+    }
+}
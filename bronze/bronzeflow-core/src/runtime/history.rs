@@ -0,0 +1,294 @@
+// This is a part of bronze.
+
+//! RunHistory, the records of the DAG runs of a manager
+//!
+//! A DAG run is recorded when it starts, the states of its tasks are updated while they
+//! finish, and the state of the run is updated when the run has finished. The records are
+//! shared by all DAGs submitted to the same manager, so a task could wait for a run of
+//! another DAG, see [`ExternalDependency`]. The SLA misses of a run are recorded with it.
+//!
+//! The records are kept by DAG name, only the latest [`DEFAULT_MAX_RUNS`] finished runs of
+//! every DAG are kept by default, the older ones are dropped when a run finishes.
+//!
+//! [`ExternalDependency`]: crate::runtime::external::ExternalDependency
+
+use crate::runtime::sla::SlaMiss;
+use crate::task::state::{DAGRunReport, TaskState};
+use bronzeflow_time::schedule_time::ScheduleTime;
+use chrono::{DateTime, Utc};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+/// The number of runs of every DAG which are kept by default
+pub const DEFAULT_MAX_RUNS: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunState {
+    Running,
+    /// All tasks succeeded or were skipped
+    Success,
+    Failed,
+    /// The run was skipped as a whole
+    Skipped,
+}
+
+impl RunState {
+    #[inline(always)]
+    pub fn is_finished(&self) -> bool {
+        !matches!(self, RunState::Running)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RunRecord {
+    pub dag_id: Option<u64>,
+    pub dag_name: Option<String>,
+    pub run_id: String,
+    pub logical_time: Option<ScheduleTime>,
+    /// Whether the run was triggered by hand, not by the schedule
+    pub manual: bool,
+    pub state: RunState,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    /// The states of the tasks which have finished
+    pub tasks: Vec<(String, TaskState)>,
+    pub sla_misses: Vec<SlaMiss>,
+}
+
+impl RunRecord {
+    /// The state of task `name`, `None` if it has not finished
+    pub fn task_state(&self, name: &str) -> Option<&TaskState> {
+        self.tasks.iter().find(|(n, _)| n == name).map(|(_, s)| s)
+    }
+}
+
+#[derive(Debug)]
+struct Records {
+    /// The runs of every DAG, the oldest first, the runs of unnamed DAGs are kept under `None`
+    by_dag: HashMap<Option<String>, VecDeque<RunRecord>>,
+    /// The DAG name of every run
+    dag_names: HashMap<String, Option<String>>,
+    max_runs: usize,
+}
+
+impl Default for Records {
+    fn default() -> Self {
+        Records {
+            by_dag: HashMap::new(),
+            dag_names: HashMap::new(),
+            max_runs: DEFAULT_MAX_RUNS,
+        }
+    }
+}
+
+impl Records {
+    /// Drop the oldest finished runs of DAG `dag_name` over the maximum, the running ones are
+    /// kept
+    fn retain(&mut self, dag_name: &Option<String>) {
+        let Some(runs) = self.by_dag.get_mut(dag_name) else {
+            return;
+        };
+        let finished = runs.iter().filter(|r| r.state.is_finished()).count();
+        let mut over = finished.saturating_sub(self.max_runs);
+        while over > 0 {
+            let Some(i) = runs.iter().position(|r| r.state.is_finished()) else {
+                break;
+            };
+            let run = runs.remove(i).unwrap();
+            self.dag_names.remove(&run.run_id);
+            over -= 1;
+        }
+    }
+
+    fn find_mut(&mut self, run_id: &str) -> Option<&mut RunRecord> {
+        let dag_name = self.dag_names.get(run_id)?;
+        self.by_dag
+            .get_mut(dag_name)?
+            .iter_mut()
+            .rev()
+            .find(|r| r.run_id == run_id)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RunHistory(Arc<Mutex<Records>>);
+
+impl RunHistory {
+    pub fn new() -> Self {
+        RunHistory::default()
+    }
+
+    /// Keep the latest `max_runs` finished runs of every DAG, [`DEFAULT_MAX_RUNS`] by default
+    pub fn set_max_runs(&self, max_runs: usize) {
+        let mut records = self.0.lock().unwrap();
+        records.max_runs = max_runs;
+        let names: Vec<_> = records.by_dag.keys().cloned().collect();
+        names.iter().for_each(|name| records.retain(name));
+    }
+
+    /// The records of the runs of DAG `dag_name`, the oldest first
+    pub fn runs(&self, dag_name: &str) -> Vec<RunRecord> {
+        self.0
+            .lock()
+            .unwrap()
+            .by_dag
+            .get(&Some(dag_name.to_string()))
+            .map(|runs| runs.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn get(&self, run_id: &str) -> Option<RunRecord> {
+        self.0.lock().unwrap().find_mut(run_id).map(|r| r.clone())
+    }
+
+    /// The latest run of DAG `dag_name` for `logical_time`, or the latest run of the DAG if
+    /// `logical_time` is `None`
+    pub fn latest(&self, dag_name: &str, logical_time: Option<&ScheduleTime>) -> Option<RunRecord> {
+        self.0
+            .lock()
+            .unwrap()
+            .by_dag
+            .get(&Some(dag_name.to_string()))?
+            .iter()
+            .rev()
+            .find(|r| logical_time.is_none_or(|t| r.logical_time.as_ref() == Some(t)))
+            .cloned()
+    }
+
+    /// The SLA misses of all runs of DAG `dag_name`, the oldest first
+    pub fn sla_misses(&self, dag_name: &str) -> Vec<SlaMiss> {
+        self.runs(dag_name)
+            .into_iter()
+            .flat_map(|r| r.sla_misses)
+            .collect()
+    }
+
+    /// Record a run which has started
+    pub(crate) fn start(
+        &self,
+        dag_id: Option<u64>,
+        dag_name: Option<String>,
+        run_id: &str,
+        logical_time: Option<ScheduleTime>,
+        manual: bool,
+    ) {
+        let mut records = self.0.lock().unwrap();
+        records
+            .dag_names
+            .insert(run_id.to_string(), dag_name.clone());
+        records
+            .by_dag
+            .entry(dag_name.clone())
+            .or_default()
+            .push_back(RunRecord {
+                dag_id,
+                dag_name: dag_name.clone(),
+                run_id: run_id.to_string(),
+                logical_time,
+                manual,
+                state: RunState::Running,
+                started_at: Utc::now(),
+                finished_at: None,
+                tasks: vec![],
+                sla_misses: vec![],
+            });
+    }
+
+    /// Task `name` of run `run_id` has finished with `state`
+    pub(crate) fn set_task(&self, run_id: &str, name: &str, state: &TaskState) {
+        self.update(run_id, |r| {
+            match r.tasks.iter_mut().find(|(n, _)| n == name) {
+                Some((_, s)) => *s = state.clone(),
+                None => r.tasks.push((name.to_string(), state.clone())),
+            }
+        });
+    }
+
+    pub(crate) fn add_sla_miss(&self, miss: SlaMiss) {
+        self.update(&miss.run_id.clone(), |r| r.sla_misses.push(miss));
+    }
+
+    /// Run `run_id` has finished with `report`
+    pub(crate) fn finish(&self, run_id: &str, state: RunState, report: &DAGRunReport) {
+        let mut records = self.0.lock().unwrap();
+        let Some(r) = records.find_mut(run_id) else {
+            return;
+        };
+        r.state = state;
+        r.finished_at = Some(Utc::now());
+        r.tasks = report.states().to_vec();
+        let dag_name = r.dag_name.clone();
+        records.retain(&dag_name);
+    }
+
+    fn update<F: FnOnce(&mut RunRecord)>(&self, run_id: &str, f: F) {
+        if let Some(r) = self.0.lock().unwrap().find_mut(run_id) {
+            f(r)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_runs() {
+        let history = RunHistory::new();
+        let day = |d: &str| {
+            format!("2024-01-0{}T00:00:00Z", d)
+                .parse::<ScheduleTime>()
+                .unwrap()
+        };
+        let name = Some("ingest".to_string());
+        history.start(Some(1), name.clone(), "r1", Some(day("1")), false);
+        history.start(Some(1), name.clone(), "r2", Some(day("2")), true);
+        history.set_task("r2", "load", &TaskState::Success);
+
+        let latest = history.latest("ingest", None).unwrap();
+        assert_eq!(
+            ("r2", RunState::Running),
+            (&latest.run_id[..], latest.state)
+        );
+        assert_eq!(Some(&TaskState::Success), latest.task_state("load"));
+        assert!(latest.manual);
+
+        let report = DAGRunReport::new(vec![("load".to_string(), TaskState::Skipped)]);
+        history.finish("r1", RunState::Failed, &report);
+        let first = history.latest("ingest", Some(&day("1"))).unwrap();
+        assert_eq!(RunState::Failed, first.state);
+        assert!(first.finished_at.is_some());
+        assert!(history.latest("ingest", Some(&day("3"))).is_none());
+        assert!(history.latest("report", None).is_none());
+        assert_eq!(2, history.runs("ingest").len());
+    }
+
+    #[test]
+    fn keep_latest_finished_runs() {
+        let history = RunHistory::new();
+        history.set_max_runs(2);
+        let report = DAGRunReport::new(vec![]);
+        let name = Some("ingest".to_string());
+        history.start(None, name.clone(), "r1", None, false);
+        for id in ["r2", "r3", "r4"] {
+            history.start(None, name.clone(), id, None, false);
+            history.finish(id, RunState::Success, &report);
+        }
+        history.start(None, Some("report".to_string()), "r5", None, false);
+
+        // The running one is kept with the latest finished ones
+        let runs: Vec<_> = history
+            .runs("ingest")
+            .into_iter()
+            .map(|r| r.run_id)
+            .collect();
+        assert_eq!(vec!["r1", "r3", "r4"], runs);
+        assert!(history.get("r2").is_none());
+        assert_eq!("r5", history.latest("report", None).unwrap().run_id);
+
+        history.finish("r1", RunState::Failed, &report);
+        history.set_max_runs(1);
+        assert_eq!(1, history.runs("ingest").len());
+        assert!(history.get("r1").is_none());
+    }
+}
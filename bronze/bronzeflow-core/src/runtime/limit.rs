@@ -0,0 +1,543 @@
+// This is a part of bronze.
+
+//! Limit the runs of a runnable which are in progress at the same time
+//!
+//! Every run takes a slot from the [`RunSlots`] of its runnable and gives it back when it has
+//! finished, a run which finds no free slot waits for one or is skipped, according to the
+//! [`ParallelismPolicy`] of the runnable. A waiting run takes a thread, so the number of
+//! waiting runs is bounded. The tasks of a DAG with their own maximum parallelism wait in the
+//! loop of their run instead, they are woken when a slot is given back. The waiting runs and
+//! tasks are queued by their priorities, the free slots always go to the waiting one with the
+//! highest priority, the ones with the same priority get them in turn.
+//!
+//! The [`OverlapPolicy`] decides what happens when a run fires while the previous runs of the
+//! same runnable are still active, the active runs are tracked in [`ActiveRuns`] by the id of
+//! the runnable.
+
+use crate::runtime::context::CancellationToken;
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+
+/// The number of runs which could wait for a slot by default
+pub const DEFAULT_QUEUED_RUNS: usize = 16;
+
+/// Called once when the slots it waits for are given back
+pub(crate) type Waker = Box<dyn Fn() + Send>;
+
+/// What to do with a run when `maximum_parallelism` runs are in progress
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParallelismPolicy {
+    /// Wait until a running one has finished, at most this many runs are waiting at the same
+    /// time, the others are skipped
+    Queue(usize),
+    /// Do not run it
+    Skip,
+}
+
+impl Default for ParallelismPolicy {
+    fn default() -> Self {
+        ParallelismPolicy::Queue(DEFAULT_QUEUED_RUNS)
+    }
+}
+
+#[derive(Default)]
+struct Slots {
+    running: u32,
+    /// The number of runs blocking their threads in the queue
+    queued: usize,
+    /// The tickets of the waiting runs and tasks, with their priorities
+    waiting: Vec<(u64, i64)>,
+    wakers: Vec<Waker>,
+}
+
+impl Slots {
+    /// The ticket which gets the free slot first
+    fn first(&self) -> Option<u64> {
+        self.waiting
+            .iter()
+            .max_by_key(|(ticket, priority)| (*priority, std::cmp::Reverse(*ticket)))
+            .map(|(ticket, _)| *ticket)
+    }
+
+    fn wake(&mut self) {
+        self.wakers.drain(..).for_each(|wake| wake());
+    }
+}
+
+/// The number of runs in progress, shared by all copies of the same metadata
+#[derive(Clone, Default)]
+pub struct RunSlots(Arc<(Mutex<Slots>, Condvar)>);
+
+/// A taken slot, it is given back when dropped
+#[derive(Debug)]
+pub struct RunSlot(RunSlots);
+
+/// A run or a task waiting in the queue of the slots, it leaves the queue when dropped
+#[derive(Debug)]
+pub struct SlotTicket {
+    slots: RunSlots,
+    ticket: u64,
+}
+
+/// Whether a new run could take a slot
+pub(crate) enum SlotAdmission {
+    Run(RunSlot),
+    Wait(QueuedSlot),
+    Skip,
+}
+
+/// A run waiting for a slot in a thread, it leaves the queue when dropped
+pub(crate) struct QueuedSlot {
+    ticket: SlotTicket,
+    max: u32,
+}
+
+impl RunSlots {
+    pub fn running(&self) -> u32 {
+        self.0 .0.lock().unwrap().running
+    }
+
+    /// The number of runs waiting for a slot in their threads
+    pub fn queued(&self) -> usize {
+        self.0 .0.lock().unwrap().queued
+    }
+
+    /// The number of runs and tasks waiting for a slot
+    pub fn waiting(&self) -> usize {
+        self.0 .0.lock().unwrap().waiting.len()
+    }
+
+    /// Queue a run or a task with `priority`, the free slots go to the waiting one with the
+    /// highest priority, the ones with the same priority get them in turn
+    pub fn request(&self, priority: i64) -> SlotTicket {
+        static TICKETS: AtomicU64 = AtomicU64::new(0);
+        let ticket = TICKETS.fetch_add(1, Ordering::Relaxed);
+        self.0 .0.lock().unwrap().waiting.push((ticket, priority));
+        SlotTicket {
+            slots: self.clone(),
+            ticket,
+        }
+    }
+
+    /// Take a slot if less than `max` runs are in progress and nothing is waiting for it
+    pub fn try_acquire(&self, max: u32) -> Option<RunSlot> {
+        self.request(0).try_take(max).ok()
+    }
+
+    /// Take a slot, block until less than `max` runs are in progress
+    pub fn acquire(&self, max: u32) -> RunSlot {
+        let ticket = self.request(0);
+        ticket.wait(max)
+    }
+
+    /// Decide whether a new run with `priority` could take one of `max` slots according to
+    /// `policy`, it does not take a free slot before the waiting runs
+    pub(crate) fn admit(
+        &self,
+        max: u32,
+        policy: ParallelismPolicy,
+        priority: i64,
+    ) -> SlotAdmission {
+        {
+            let mut slots = self.0 .0.lock().unwrap();
+            if slots.running < max && slots.waiting.is_empty() {
+                slots.running += 1;
+                return SlotAdmission::Run(RunSlot(self.clone()));
+            }
+            match policy {
+                ParallelismPolicy::Queue(limit) if slots.queued < limit => slots.queued += 1,
+                _ => return SlotAdmission::Skip,
+            }
+        }
+        SlotAdmission::Wait(QueuedSlot {
+            ticket: self.request(priority),
+            max,
+        })
+    }
+}
+
+impl Debug for RunSlots {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let slots = self.0 .0.lock().unwrap();
+        f.debug_struct("RunSlots")
+            .field("running", &slots.running)
+            .field("queued", &slots.queued)
+            .field("waiting", &slots.waiting)
+            .finish()
+    }
+}
+
+impl SlotTicket {
+    /// Take a slot if this one is the first in the queue and less than `max` runs are in
+    /// progress, otherwise give the ticket back
+    pub fn try_take(self, max: u32) -> Result<RunSlot, SlotTicket> {
+        self.take(max, None)
+    }
+
+    /// Take a slot like [`SlotTicket::try_take`], otherwise call `wake` once when a slot is
+    /// given back or the queue changes
+    pub(crate) fn try_take_or_wake(self, max: u32, wake: Waker) -> Result<RunSlot, SlotTicket> {
+        self.take(max, Some(wake))
+    }
+
+    fn take(self, max: u32, wake: Option<Waker>) -> Result<RunSlot, SlotTicket> {
+        let taken = {
+            let mut slots = self.slots.0 .0.lock().unwrap();
+            if slots.first() == Some(self.ticket) && slots.running < max {
+                slots.running += 1;
+                true
+            } else {
+                slots.wakers.extend(wake);
+                false
+            }
+        };
+        if !taken {
+            return Err(self);
+        }
+        Ok(RunSlot(self.slots.clone()))
+    }
+
+    /// Block until this one is the first in the queue and less than `max` runs are in
+    /// progress, then take a slot
+    fn wait(&self, max: u32) -> RunSlot {
+        {
+            let (lock, changed) = &*self.slots.0;
+            let mut slots = changed
+                .wait_while(lock.lock().unwrap(), |slots| {
+                    slots.first() != Some(self.ticket) || slots.running >= max
+                })
+                .unwrap();
+            slots.running += 1;
+        }
+        RunSlot(self.slots.clone())
+    }
+}
+
+impl Drop for SlotTicket {
+    fn drop(&mut self) {
+        let (lock, changed) = &*self.slots.0;
+        let mut slots = lock.lock().unwrap();
+        let first = slots.first() == Some(self.ticket);
+        slots.waiting.retain(|(ticket, _)| *ticket != self.ticket);
+        // The next one in the queue may take a free slot now
+        if first {
+            slots.wake();
+            changed.notify_all();
+        }
+    }
+}
+
+impl QueuedSlot {
+    /// Block until a slot is free for this run, then take it
+    pub(crate) fn wait(self) -> RunSlot {
+        self.ticket.wait(self.max)
+    }
+}
+
+impl Drop for QueuedSlot {
+    fn drop(&mut self) {
+        self.ticket.slots.0 .0.lock().unwrap().queued -= 1;
+    }
+}
+
+impl Drop for RunSlot {
+    fn drop(&mut self) {
+        let (lock, changed) = &*self.0 .0;
+        let mut slots = lock.lock().unwrap();
+        slots.running -= 1;
+        slots.wake();
+        changed.notify_all();
+    }
+}
+
+/// What to do when a run fires while the previous runs of the same runnable are active
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverlapPolicy {
+    /// Run it anyway
+    #[default]
+    Allow,
+    /// Do not run it
+    Skip,
+    /// Wait until the active runs have finished, at most this many runs are waiting at the
+    /// same time, the others are skipped
+    Queue(usize),
+    /// Cancel the active runs and run it at once
+    CancelPrevious,
+}
+
+#[derive(Debug, Default)]
+struct Runs {
+    /// The run ids and the cancellation tokens of the active runs
+    active: Vec<(String, CancellationToken)>,
+    queued: usize,
+}
+
+/// The active runs of all runnables, keyed by the id of the runnable
+#[derive(Debug, Clone, Default)]
+pub struct ActiveRuns(Arc<(Mutex<HashMap<u64, Runs>>, Condvar)>);
+
+/// Whether a new run could start
+pub(crate) enum Admission {
+    Run(ActiveRun),
+    Wait(QueuedRun),
+    Skip,
+}
+
+/// A registered active run, it is removed when dropped
+pub(crate) struct ActiveRun {
+    runs: ActiveRuns,
+    id: u64,
+    run_id: String,
+}
+
+/// A run waiting for the active runs to finish
+pub(crate) struct QueuedRun {
+    runs: ActiveRuns,
+    id: u64,
+    run_id: String,
+    cancel: CancellationToken,
+}
+
+impl ActiveRuns {
+    pub fn new() -> Self {
+        ActiveRuns::default()
+    }
+
+    /// The run ids of the active runs of runnable `id`
+    pub fn active(&self, id: u64) -> Vec<String> {
+        self.0
+             .0
+            .lock()
+            .unwrap()
+            .get(&id)
+            .map(|r| r.active.iter().map(|(run_id, _)| run_id.clone()).collect())
+            .unwrap_or_default()
+    }
+
+    /// The number of runs of runnable `id` waiting for the active runs
+    pub fn queued(&self, id: u64) -> usize {
+        self.0 .0.lock().unwrap().get(&id).map_or(0, |r| r.queued)
+    }
+
+    /// Decide whether run `run_id` of runnable `id` could start according to `policy`
+    pub(crate) fn admit(
+        &self,
+        id: u64,
+        policy: OverlapPolicy,
+        run_id: &str,
+        cancel: &CancellationToken,
+    ) -> Admission {
+        let mut map = self.0 .0.lock().unwrap();
+        let runs = map.entry(id).or_default();
+        let idle = runs.active.is_empty() && runs.queued == 0;
+        match policy {
+            OverlapPolicy::Skip if !idle => return Admission::Skip,
+            OverlapPolicy::Queue(max) if !idle => {
+                if runs.queued >= max {
+                    return Admission::Skip;
+                }
+                runs.queued += 1;
+                return Admission::Wait(QueuedRun {
+                    runs: self.clone(),
+                    id,
+                    run_id: run_id.to_string(),
+                    cancel: cancel.clone(),
+                });
+            },
+            OverlapPolicy::CancelPrevious => {
+                runs.active.iter().for_each(|(_, token)| token.cancel());
+            },
+            _ => {},
+        }
+        runs.active.push((run_id.to_string(), cancel.clone()));
+        Admission::Run(ActiveRun {
+            runs: self.clone(),
+            id,
+            run_id: run_id.to_string(),
+        })
+    }
+}
+
+impl QueuedRun {
+    /// Block until no run of the runnable is active, then become the active run
+    pub(crate) fn wait(self) -> ActiveRun {
+        let (lock, changed) = &*self.runs.0;
+        let mut map = changed
+            .wait_while(lock.lock().unwrap(), |map| {
+                map.get(&self.id).is_some_and(|r| !r.active.is_empty())
+            })
+            .unwrap();
+        let runs = map.entry(self.id).or_default();
+        runs.queued -= 1;
+        runs.active.push((self.run_id.clone(), self.cancel.clone()));
+        ActiveRun {
+            runs: self.runs.clone(),
+            id: self.id,
+            run_id: self.run_id.clone(),
+        }
+    }
+}
+
+impl Drop for ActiveRun {
+    fn drop(&mut self) {
+        let (lock, changed) = &*self.runs.0;
+        let mut map = lock.lock().unwrap();
+        if let Some(runs) = map.get_mut(&self.id) {
+            runs.active.retain(|(run_id, _)| *run_id != self.run_id);
+            if runs.active.is_empty() && runs.queued == 0 {
+                map.remove(&self.id);
+            }
+        }
+        changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn acquire_and_release_slots() {
+        let slots = RunSlots::default();
+        let first = slots.try_acquire(2).unwrap();
+        let _second = slots.try_acquire(2).unwrap();
+        assert!(slots.try_acquire(2).is_none());
+        assert_eq!(2, slots.running());
+
+        let waiting = {
+            let slots = slots.clone();
+            thread::spawn(move || {
+                let _slot = slots.acquire(2);
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!waiting.is_finished());
+        drop(first);
+        waiting.join().unwrap();
+        assert_eq!(1, slots.running());
+    }
+
+    #[test]
+    fn bound_queued_runs() {
+        let slots = RunSlots::default();
+        let policy = ParallelismPolicy::Queue(1);
+        let SlotAdmission::Run(first) = slots.admit(1, policy, 0) else {
+            panic!("The first run should start");
+        };
+        let SlotAdmission::Wait(queued) = slots.admit(1, policy, 0) else {
+            panic!("The run should be queued");
+        };
+        assert!(matches!(slots.admit(1, policy, 0), SlotAdmission::Skip));
+        assert!(matches!(
+            slots.admit(1, ParallelismPolicy::Skip, 0),
+            SlotAdmission::Skip
+        ));
+        assert_eq!(1, slots.queued());
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let ticket = slots
+            .request(0)
+            .try_take_or_wake(1, Box::new(move || tx.send(()).unwrap()))
+            .unwrap_err();
+        let waiting = thread::spawn(move || queued.wait());
+        drop(first);
+        rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        let second = waiting.join().unwrap();
+        assert_eq!(
+            (1, 0, 1),
+            (slots.running(), slots.queued(), slots.waiting())
+        );
+        let ticket = ticket.try_take(1).unwrap_err();
+        drop(second);
+        assert!(ticket.try_take(1).is_ok());
+        assert_eq!((0, 0), (slots.running(), slots.waiting()));
+    }
+
+    #[test]
+    fn give_slots_by_priority() {
+        let slots = RunSlots::default();
+        let held = slots.try_acquire(1).unwrap();
+        let SlotAdmission::Wait(low) = slots.admit(1, ParallelismPolicy::Queue(2), 1) else {
+            panic!("The run should be queued");
+        };
+        let low = thread::spawn(move || {
+            let _slot = low.wait();
+        });
+        let (tx, rx) = std::sync::mpsc::channel();
+        let high = slots
+            .request(5)
+            .try_take_or_wake(1, Box::new(move || tx.send(()).unwrap()))
+            .unwrap_err();
+        let same = slots.request(5);
+        assert!(slots.try_acquire(1).is_none());
+
+        drop(held);
+        rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        let same = same.try_take(1).unwrap_err();
+        let high = high.try_take(1).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert!(!low.is_finished());
+        drop(high);
+        let same = same.try_take(1).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert!(!low.is_finished());
+        drop(same);
+        low.join().unwrap();
+        assert_eq!(
+            (0, 0, 0),
+            (slots.running(), slots.queued(), slots.waiting())
+        );
+    }
+
+    #[test]
+    fn overlap_policies() {
+        let runs = ActiveRuns::new();
+        let first_token = CancellationToken::new();
+        let admit =
+            |policy, run_id: &str, token: &CancellationToken| runs.admit(1, policy, run_id, token);
+        let Admission::Run(first) = admit(OverlapPolicy::Allow, "r1", &first_token) else {
+            panic!("The first run should start");
+        };
+        assert!(matches!(
+            admit(OverlapPolicy::Skip, "r2", &CancellationToken::new()),
+            Admission::Skip
+        ));
+        // Other runnables are not affected
+        assert!(matches!(
+            runs.admit(2, OverlapPolicy::Skip, "r3", &CancellationToken::new()),
+            Admission::Run(_)
+        ));
+
+        let Admission::Wait(queued) =
+            admit(OverlapPolicy::Queue(1), "r4", &CancellationToken::new())
+        else {
+            panic!("The run should be queued");
+        };
+        assert!(matches!(
+            admit(OverlapPolicy::Queue(1), "r5", &CancellationToken::new()),
+            Admission::Skip
+        ));
+        assert_eq!(1, runs.queued(1));
+        let waiting = thread::spawn(move || queued.wait().run_id.clone());
+        thread::sleep(Duration::from_millis(50));
+        assert!(!waiting.is_finished());
+        drop(first);
+        assert_eq!("r4", waiting.join().unwrap());
+        assert!(runs.active(1).is_empty());
+
+        let previous = admit(OverlapPolicy::Allow, "r6", &first_token);
+        let _next = admit(
+            OverlapPolicy::CancelPrevious,
+            "r7",
+            &CancellationToken::new(),
+        );
+        assert!(first_token.is_cancelled());
+        assert_eq!(vec!["r6".to_string(), "r7".to_string()], runs.active(1));
+        drop(previous);
+        assert_eq!(vec!["r7".to_string()], runs.active(1));
+    }
+}
@@ -11,21 +11,42 @@
 //!
 // TODO Add more examples to use the runnable and runtime
 
+pub mod callback;
+pub mod command;
+pub mod context;
 pub mod event_loop;
+pub mod external;
+pub mod history;
+pub mod limit;
+pub mod pool;
+pub mod retry;
+pub mod sensor;
+pub mod sla;
 #[cfg(feature = "async_tokio")]
 pub mod tokio_runtime;
 
 use std::any::{type_name, TypeId};
 use std::fmt::Debug;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
 use std::thread;
+use std::time::Duration;
 
 #[allow(unused_imports)]
 #[cfg(feature = "async")]
 use futures::executor as executor_executor;
 
+use crate::runtime::callback::Callbacks;
+use crate::runtime::context::RunContext;
 use crate::runtime::event_loop::{EventSender, TaskEvent};
+use crate::runtime::history::RunHistory;
+use crate::runtime::limit::{ActiveRuns, OverlapPolicy, ParallelismPolicy, RunSlots};
+use crate::runtime::pool::ResourcePools;
+use crate::runtime::retry::RetryPolicy;
+use crate::runtime::sla::Sla;
+use crate::task::state::WeightRule;
 use bronzeflow_time::schedule_time::ScheduleTimeHolder;
+use bronzeflow_utils::{BronzeError, Result};
+use serde::Serialize;
 #[cfg(feature = "async_tokio")]
 use tokio;
 #[cfg(feature = "async_tokio")]
@@ -44,6 +65,7 @@ pub enum RuntimeJoinHandle<T> {
     _Unreachable(std::convert::Infallible, std::marker::PhantomData<T>),
 }
 
+#[allow(dead_code)]
 pub(crate) trait NotFnRunnable {}
 
 #[cfg(feature = "async")]
@@ -55,6 +77,48 @@ pub struct AsyncFn<F: Fn() -> U + Send + Clone + 'static, U: std::future::Future
 #[derive(Debug, Clone)]
 pub struct SyncFn<F: Fn() + Send + 'static + Clone>(pub F);
 
+/// A synchronous function which could fail, the task is marked as failed if it returns an
+/// error, otherwise the returned value is saved as the output of the task
+#[derive(Debug, Clone)]
+pub struct TrySyncFn<F, T>(pub F)
+where
+    F: Fn() -> Result<T> + Send + Clone + 'static,
+    T: Serialize + 'static;
+
+/// An asynchronous function which could fail, see [`TrySyncFn`]
+#[cfg(feature = "async")]
+#[derive(Debug, Clone)]
+pub struct TryAsyncFn<F, U, T>(pub F)
+where
+    F: Fn() -> U + Send + Clone + 'static,
+    U: std::future::Future<Output = Result<T>> + Send + 'static,
+    T: Serialize + 'static;
+
+/// A synchronous function which receives the [`RunContext`], the returned value is saved as
+/// the output of the task
+#[derive(Debug, Clone)]
+pub struct SyncContextFn<F, T>(pub F)
+where
+    F: Fn(&RunContext) -> Result<T> + Send + Clone + 'static,
+    T: Serialize + 'static;
+
+/// A branch task, returns the names of the children to follow, see [`RunContext::follow`]
+#[derive(Debug, Clone)]
+pub struct BranchFn<F, S>(pub F)
+where
+    F: Fn(&RunContext) -> Result<Vec<S>> + Send + Clone + 'static,
+    S: AsRef<str> + 'static;
+
+/// An asynchronous function which receives the [`RunContext`], the returned value is saved as
+/// the output of the task
+#[cfg(feature = "async")]
+#[derive(Debug, Clone)]
+pub struct AsyncContextFn<F, U, T>(pub F)
+where
+    F: Fn(RunContext) -> U + Send + Clone + 'static,
+    U: std::future::Future<Output = Result<T>> + Send + 'static,
+    T: Serialize + 'static;
+
 #[cfg(feature = "async")]
 impl<F: Fn() -> U + Send + Clone + 'static, U: std::future::Future + Send + 'static> From<F>
     for AsyncFn<F, U>
@@ -99,6 +163,12 @@ pub trait Runnable: 'static {
 
     fn run_async(&self) -> Self::Handle;
 
+    /// Run with the context of current run, the context is ignored by default
+    #[inline(always)]
+    fn run_with_context(&self, _: RunContext) -> Self::Handle {
+        self.run_async()
+    }
+
     #[inline(always)]
     fn is_async(&self) -> bool {
         false
@@ -206,12 +276,154 @@ impl<F: Fn() + Send + 'static + Clone> Runnable for F {
     }
 }
 
+impl<F, T> Runnable for SyncContextFn<F, T>
+where
+    F: Fn(&RunContext) -> Result<T> + Send + Clone + 'static,
+    T: Serialize + 'static,
+{
+    type Handle = RuntimeJoinHandle<()>;
+
+    #[inline(always)]
+    fn run_async(&self) -> Self::Handle {
+        self.run_with_context(RunContext::default())
+    }
+
+    fn run_with_context(&self, ctx: RunContext) -> Self::Handle {
+        ctx.report(self.0(&ctx));
+        RuntimeJoinHandle::SyncJobHandle
+    }
+}
+
+impl<F, S> Runnable for BranchFn<F, S>
+where
+    F: Fn(&RunContext) -> Result<Vec<S>> + Send + Clone + 'static,
+    S: AsRef<str> + 'static,
+{
+    type Handle = RuntimeJoinHandle<()>;
+
+    #[inline(always)]
+    fn run_async(&self) -> Self::Handle {
+        self.run_with_context(RunContext::default())
+    }
+
+    fn run_with_context(&self, ctx: RunContext) -> Self::Handle {
+        let children = self.0(&ctx).map(|children| {
+            ctx.follow(&children);
+            ctx.followed()
+        });
+        ctx.report(children);
+        RuntimeJoinHandle::SyncJobHandle
+    }
+}
+
+#[cfg(feature = "async")]
+impl<F, U, T> Runnable for AsyncContextFn<F, U, T>
+where
+    F: Fn(RunContext) -> U + Send + Clone + 'static,
+    U: std::future::Future<Output = Result<T>> + Send + 'static,
+    T: Serialize + 'static,
+{
+    type Handle = RuntimeJoinHandle<()>;
+
+    #[inline(always)]
+    fn run_async(&self) -> Self::Handle {
+        self.run_with_context(RunContext::default())
+    }
+
+    fn run_with_context(&self, ctx: RunContext) -> Self::Handle {
+        let f = self.0.clone();
+        run_async(&move || {
+            let ctx = ctx.clone();
+            let fut = f(ctx.clone());
+            async move { ctx.report(fut.await) }
+        })
+    }
+
+    #[inline(always)]
+    fn is_async(&self) -> bool {
+        true
+    }
+}
+
+impl<F, T> Runnable for TrySyncFn<F, T>
+where
+    F: Fn() -> Result<T> + Send + Clone + 'static,
+    T: Serialize + 'static,
+{
+    type Handle = RuntimeJoinHandle<()>;
+
+    #[inline(always)]
+    fn run_async(&self) -> Self::Handle {
+        self.run_with_context(RunContext::default())
+    }
+
+    fn run_with_context(&self, ctx: RunContext) -> Self::Handle {
+        ctx.report(self.0());
+        RuntimeJoinHandle::SyncJobHandle
+    }
+}
+
+#[cfg(feature = "async")]
+impl<F, U, T> Runnable for TryAsyncFn<F, U, T>
+where
+    F: Fn() -> U + Send + Clone + 'static,
+    U: std::future::Future<Output = Result<T>> + Send + 'static,
+    T: Serialize + 'static,
+{
+    type Handle = RuntimeJoinHandle<()>;
+
+    #[inline(always)]
+    fn run_async(&self) -> Self::Handle {
+        self.run_with_context(RunContext::default())
+    }
+
+    fn run_with_context(&self, ctx: RunContext) -> Self::Handle {
+        let f = self.0.clone();
+        run_async(&move || {
+            let ctx = ctx.clone();
+            let fut = f();
+            async move { ctx.report(fut.await) }
+        })
+    }
+
+    #[inline(always)]
+    fn is_async(&self) -> bool {
+        true
+    }
+}
+
 pub type RunnerType = Box<dyn Runnable<Handle = RuntimeJoinHandle<()>> + 'static + Send>;
 
 pub struct WrappedRunner(pub RunnerType);
 
+/// A runner shared by the clones of a task, one run at a time
+///
+/// The type and the kind of the runner are read when it is created, so they could be checked
+/// while the runner is still running, like a task which timed out
 #[derive(Clone)]
-pub struct SafeWrappedRunner(pub(crate) Arc<Mutex<WrappedRunner>>);
+pub struct SafeWrappedRunner {
+    runner: Arc<Mutex<WrappedRunner>>,
+    is_async: bool,
+    type_name: String,
+    type_id: TypeId,
+}
+
+impl SafeWrappedRunner {
+    pub fn new(runner: WrappedRunner) -> Self {
+        SafeWrappedRunner {
+            is_async: runner.is_async(),
+            type_name: runner.run_type_name(),
+            type_id: runner.run_type_id(),
+            runner: Arc::new(Mutex::new(runner)),
+        }
+    }
+
+    /// A panic in the last run poisons the lock, the runner could still be run again
+    #[inline(always)]
+    fn lock_runner(&self) -> MutexGuard<'_, WrappedRunner> {
+        self.runner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
 
 impl Runnable for WrappedRunner {
     type Handle = RuntimeJoinHandle<()>;
@@ -221,6 +433,16 @@ impl Runnable for WrappedRunner {
         self.0.run_async()
     }
 
+    #[inline(always)]
+    fn run_with_context(&self, ctx: RunContext) -> Self::Handle {
+        self.0.run_with_context(ctx)
+    }
+
+    #[inline(always)]
+    fn is_async(&self) -> bool {
+        self.0.is_async()
+    }
+
     #[inline(always)]
     fn run_type_name(&self) -> String {
         self.0.run_type_name()
@@ -237,17 +459,26 @@ impl Runnable for SafeWrappedRunner {
 
     #[inline(always)]
     fn run_async(&self) -> Self::Handle {
-        self.0.lock().unwrap().0.run_async()
+        self.lock_runner().0.run_async()
+    }
+
+    #[inline(always)]
+    fn run_with_context(&self, ctx: RunContext) -> Self::Handle {
+        self.lock_runner().0.run_with_context(ctx)
+    }
+
+    #[inline(always)]
+    fn is_async(&self) -> bool {
+        self.is_async
     }
 
     #[inline(always)]
     fn run_type_name(&self) -> String {
-        // self.0.as_ref().lock().unwrap().type_name()
-        self.0.lock().unwrap().0.run_type_name()
+        self.type_name.clone()
     }
     #[inline(always)]
     fn run_type_id(&self) -> TypeId {
-        self.0.lock().unwrap().0.run_type_id()
+        self.type_id
     }
 }
 
@@ -302,12 +533,52 @@ pub struct RunnableMetadata {
     pub(crate) id: Option<u64>,
     #[allow(dead_code)]
     pub(crate) name: Option<String>,
-    #[allow(dead_code)]
+    /// Stop scheduling the runnable after it has run this many times
     pub(crate) maximum_run_times: Option<u64>,
-    #[allow(dead_code)]
+    /// The maximum number of runs in progress at the same time
     pub(crate) maximum_parallelism: Option<u32>,
+    #[builder(default)]
+    pub(crate) parallelism_policy: Option<ParallelismPolicy>,
+    /// What to do when a run fires while the previous runs are active
+    #[builder(default)]
+    pub(crate) overlap_policy: Option<OverlapPolicy>,
     #[allow(dead_code)]
     pub(crate) schedule: Option<ScheduleTimeHolder>,
+    pub(crate) retry: Option<RetryPolicy>,
+    /// The maximum time of one attempt
+    pub(crate) timeout: Option<Duration>,
+    /// The deadline to finish, the task or the run is not stopped when it is missed
+    #[builder(default)]
+    pub(crate) sla: Option<Sla>,
+    /// The resource pool of the task and the number of slots it takes
+    #[builder(default)]
+    pub(crate) pool: Option<(String, u32)>,
+    /// The priority of the run or task when it waits for pool slots or a slot of its maximum
+    /// parallelism, higher runs first, the ready tasks of a run are also submitted by it. The
+    /// weight of a DAG is added to the priorities of all its tasks
+    #[builder(default)]
+    pub(crate) priority_weight: Option<i64>,
+    #[builder(default)]
+    pub(crate) weight_rule: Option<WeightRule>,
+    #[builder(default)]
+    pub(crate) callbacks: Callbacks,
+    /// The number of times the runnable was triggered
+    #[builder(default)]
+    pub(crate) run_times: u64,
+    /// A paused runnable is not triggered until it is resumed
+    #[builder(default)]
+    pub(crate) paused: bool,
+    #[builder(default)]
+    pub(crate) slots: RunSlots,
+    /// The active runs tracked by the manager which the runnable is submitted to
+    #[builder(default)]
+    pub(crate) active_runs: Option<ActiveRuns>,
+    /// The resource pools of the manager which the runnable is submitted to
+    #[builder(default)]
+    pub(crate) pools: Option<ResourcePools>,
+    /// The run history of the manager which the runnable is submitted to
+    #[builder(default)]
+    pub(crate) history: Option<RunHistory>,
 }
 
 impl Default for RunnableMetadata {
@@ -318,6 +589,8 @@ impl Default for RunnableMetadata {
             .maximum_run_times(None)
             .maximum_parallelism(None)
             .schedule(None)
+            .retry(None)
+            .timeout(None)
             .build()
             .unwrap()
     }
@@ -344,10 +617,212 @@ impl RunnableMetadata {
         self
     }
 
+    /// What to do with a run when `maximum_parallelism` runs are in progress
+    pub fn set_parallelism_policy(&mut self, policy: ParallelismPolicy) -> &mut Self {
+        self.parallelism_policy = Some(policy);
+        self
+    }
+
+    pub fn set_overlap_policy(&mut self, policy: OverlapPolicy) -> &mut Self {
+        self.overlap_policy = Some(policy);
+        self
+    }
+
+    /// Whether the runnable has run `maximum_run_times` times
+    pub fn is_retired(&self) -> bool {
+        self.maximum_run_times.is_some_and(|m| self.run_times >= m)
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
     pub fn set_schedule(&mut self, schedule: ScheduleTimeHolder) -> &mut Self {
         self.schedule = Some(schedule);
         self
     }
+
+    pub fn set_retry(&mut self, retry: RetryPolicy) -> &mut Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn set_sla(&mut self, sla: Sla) -> &mut Self {
+        self.sla = Some(sla);
+        self
+    }
+
+    pub fn with_sla(mut self, sla: Sla) -> Self {
+        self.sla = Some(sla);
+        self
+    }
+
+    /// Run the task in resource pool `name`, the task takes `slots` slots of the pool
+    pub fn set_pool(&mut self, name: &str, slots: u32) -> &mut Self {
+        self.pool = Some((name.to_string(), slots));
+        self
+    }
+
+    pub fn with_pool(mut self, name: &str, slots: u32) -> Self {
+        self.set_pool(name, slots);
+        self
+    }
+
+    pub fn set_priority_weight(&mut self, weight: i64) -> &mut Self {
+        self.priority_weight = Some(weight);
+        self
+    }
+
+    pub fn with_priority_weight(mut self, weight: i64) -> Self {
+        self.priority_weight = Some(weight);
+        self
+    }
+
+    pub fn set_weight_rule(&mut self, rule: WeightRule) -> &mut Self {
+        self.weight_rule = Some(rule);
+        self
+    }
+
+    pub fn with_weight_rule(mut self, rule: WeightRule) -> Self {
+        self.weight_rule = Some(rule);
+        self
+    }
+
+    /// Called when the task succeeded
+    pub fn set_on_success<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&RunContext, Option<&BronzeError>) + Send + Sync + 'static,
+    {
+        self.callbacks.on_success = Some(Arc::new(f));
+        self
+    }
+
+    pub fn with_on_success<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&RunContext, Option<&BronzeError>) + Send + Sync + 'static,
+    {
+        self.set_on_success(f);
+        self
+    }
+
+    /// Called when the last attempt of the task failed or timed out, with the error
+    pub fn set_on_failure<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&RunContext, Option<&BronzeError>) + Send + Sync + 'static,
+    {
+        self.callbacks.on_failure = Some(Arc::new(f));
+        self
+    }
+
+    pub fn with_on_failure<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&RunContext, Option<&BronzeError>) + Send + Sync + 'static,
+    {
+        self.set_on_failure(f);
+        self
+    }
+
+    /// Called when a failed attempt is retried, with the error
+    pub fn set_on_retry<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&RunContext, Option<&BronzeError>) + Send + Sync + 'static,
+    {
+        self.callbacks.on_retry = Some(Arc::new(f));
+        self
+    }
+
+    pub fn with_on_retry<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&RunContext, Option<&BronzeError>) + Send + Sync + 'static,
+    {
+        self.set_on_retry(f);
+        self
+    }
+
+    /// Called when the task is skipped
+    pub fn set_on_skip<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&RunContext, Option<&BronzeError>) + Send + Sync + 'static,
+    {
+        self.callbacks.on_skip = Some(Arc::new(f));
+        self
+    }
+
+    pub fn with_on_skip<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&RunContext, Option<&BronzeError>) + Send + Sync + 'static,
+    {
+        self.set_on_skip(f);
+        self
+    }
+
+    /// Called when the SLA deadline passed before the task finished
+    pub fn set_on_sla_miss<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&RunContext, Option<&BronzeError>) + Send + Sync + 'static,
+    {
+        self.callbacks.on_sla_miss = Some(Arc::new(f));
+        self
+    }
+
+    pub fn with_on_sla_miss<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&RunContext, Option<&BronzeError>) + Send + Sync + 'static,
+    {
+        self.set_on_sla_miss(f);
+        self
+    }
+
+    /// Override the fields which are set in `other`
+    pub(crate) fn merge(&mut self, other: RunnableMetadata) -> &mut Self {
+        let RunnableMetadata {
+            id,
+            name,
+            maximum_run_times,
+            maximum_parallelism,
+            parallelism_policy,
+            overlap_policy,
+            schedule,
+            retry,
+            timeout,
+            sla,
+            pool,
+            priority_weight,
+            weight_rule,
+            callbacks,
+            ..
+        } = other;
+        self.id = id.or(self.id.take());
+        self.name = name.or(self.name.take());
+        self.maximum_run_times = maximum_run_times.or(self.maximum_run_times.take());
+        self.maximum_parallelism = maximum_parallelism.or(self.maximum_parallelism.take());
+        self.parallelism_policy = parallelism_policy.or(self.parallelism_policy.take());
+        self.overlap_policy = overlap_policy.or(self.overlap_policy.take());
+        self.schedule = schedule.or(self.schedule.take());
+        self.retry = retry.or(self.retry.take());
+        self.timeout = timeout.or(self.timeout.take());
+        self.sla = sla.or(self.sla.take());
+        self.pool = pool.or(self.pool.take());
+        self.priority_weight = priority_weight.or(self.priority_weight.take());
+        self.weight_rule = weight_rule.or(self.weight_rule.take());
+        self.callbacks.merge(callbacks);
+        self
+    }
 }
 
 impl From<&str> for RunnableMetadata {
@@ -515,9 +990,7 @@ mod tests {
         }
         test_basic_runnable(CustomRunnable::new(), false, false, check_run_result);
 
-        let s = SafeWrappedRunner(Arc::new(Mutex::new(WrappedRunner(Box::new(
-            CustomRunnable::new(),
-        )))));
+        let s = SafeWrappedRunner::new(WrappedRunner(Box::new(CustomRunnable::new())));
         test_basic_runnable(s, false, false, check_run_result);
     }
 
@@ -0,0 +1,325 @@
+// This is a part of bronze.
+
+//! ResourcePools, named pools of slots which limit the tasks running at the same time
+//!
+//! The pools are shared by all DAGs submitted to the same manager. A task in a pool takes
+//! `pool_slots` slots from the pool before it starts and gives them back when it has finished,
+//! a task waits until enough slots are free.
+//!
+//! The waiting tasks are queued by their priorities, the free slots always go to the waiting
+//! task with the highest priority, the tasks with the same priority get them in turn. A task
+//! which could not take its slots leaves a waker, the wakers are called when slots are given
+//! back or the first task of the queue changes.
+
+use crate::runtime::limit::Waker;
+use bronzeflow_utils::{ayn_error, Result};
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+struct Pool {
+    slots: u32,
+    used: u32,
+    /// The tickets of the waiting tasks, with their priorities and their costs
+    waiting: Vec<(u64, i64, u32)>,
+    wakers: Vec<Waker>,
+}
+
+impl Pool {
+    /// The ticket which gets the free slots first
+    fn first(&self) -> Option<u64> {
+        self.waiting
+            .iter()
+            .max_by_key(|(ticket, priority, _)| (*priority, std::cmp::Reverse(*ticket)))
+            .map(|(ticket, _, _)| *ticket)
+    }
+
+    fn wake(&mut self) {
+        self.wakers.drain(..).for_each(|wake| wake());
+    }
+}
+
+impl Debug for Pool {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Pool")
+            .field("slots", &self.slots)
+            .field("used", &self.used)
+            .field("waiting", &self.waiting)
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ResourcePools(Arc<Mutex<HashMap<String, Pool>>>);
+
+/// The slots taken by a task, they are given back when dropped
+#[derive(Debug)]
+pub struct PoolSlot {
+    pools: ResourcePools,
+    name: String,
+    cost: u32,
+}
+
+/// A task waiting in the queue of a pool, it leaves the queue when dropped
+#[derive(Debug)]
+pub struct PoolTicket {
+    pools: ResourcePools,
+    name: String,
+    cost: u32,
+    ticket: u64,
+}
+
+impl ResourcePools {
+    pub fn new() -> Self {
+        ResourcePools::default()
+    }
+
+    /// Create pool `name` with `slots` slots, or resize it if it exists
+    ///
+    /// Return an error if a task waiting in the queue of the pool takes more than `slots`
+    /// slots, it could never run in the resized pool
+    pub fn set_pool(&self, name: &str, slots: u32) -> Result<()> {
+        let mut pools = self.0.lock().unwrap();
+        let Some(pool) = pools.get_mut(name) else {
+            pools.insert(
+                name.to_string(),
+                Pool {
+                    slots,
+                    used: 0,
+                    waiting: vec![],
+                    wakers: vec![],
+                },
+            );
+            return Ok(());
+        };
+        if let Some((_, _, cost)) = pool.waiting.iter().find(|(_, _, cost)| *cost > slots) {
+            return Err(ayn_error!(
+                "Pool `{}` could not be resized to {} slot(s), a waiting task takes {}",
+                name,
+                slots,
+                cost
+            ));
+        }
+        pool.slots = slots;
+        pool.wake();
+        Ok(())
+    }
+
+    /// Return an error if pool `name` does not exist or it could never have `cost` free slots
+    pub fn check(&self, name: &str, cost: u32) -> Result<()> {
+        Self::checked(&mut self.0.lock().unwrap(), name, cost).map(|_| ())
+    }
+
+    fn checked<'a>(
+        pools: &'a mut HashMap<String, Pool>,
+        name: &str,
+        cost: u32,
+    ) -> Result<&'a mut Pool> {
+        let pool = pools
+            .get_mut(name)
+            .ok_or_else(|| ayn_error!("No pool named `{}`", name))?;
+        if cost > pool.slots {
+            return Err(ayn_error!(
+                "Pool `{}` has {} slot(s), less than {}",
+                name,
+                pool.slots,
+                cost
+            ));
+        }
+        Ok(pool)
+    }
+
+    /// The number of slots of pool `name`
+    pub fn slots(&self, name: &str) -> Option<u32> {
+        self.0.lock().unwrap().get(name).map(|p| p.slots)
+    }
+
+    /// The number of slots in use of pool `name`
+    pub fn used(&self, name: &str) -> Option<u32> {
+        self.0.lock().unwrap().get(name).map(|p| p.used)
+    }
+
+    /// The number of tasks waiting for slots of pool `name`
+    pub fn waiting(&self, name: &str) -> Option<usize> {
+        self.0.lock().unwrap().get(name).map(|p| p.waiting.len())
+    }
+
+    /// Queue a task which takes `cost` slots of pool `name` with `priority`
+    ///
+    /// Return an error if the pool does not exist or it could never have `cost` free slots
+    pub fn request(&self, name: &str, cost: u32, priority: i64) -> Result<PoolTicket> {
+        static TICKETS: AtomicU64 = AtomicU64::new(0);
+        let mut pools = self.0.lock().unwrap();
+        let pool = Self::checked(&mut pools, name, cost)?;
+        let ticket = TICKETS.fetch_add(1, Ordering::Relaxed);
+        pool.waiting.push((ticket, priority, cost));
+        Ok(PoolTicket {
+            pools: self.clone(),
+            name: name.to_string(),
+            cost,
+            ticket,
+        })
+    }
+
+    /// Take `cost` slots from pool `name` if they are free and no task is waiting for them
+    pub fn try_acquire(&self, name: &str, cost: u32) -> Result<Option<PoolSlot>> {
+        Ok(self.request(name, cost, 0)?.try_take().ok())
+    }
+}
+
+impl PoolTicket {
+    /// Take the slots if this task is the first in the queue and enough slots are free,
+    /// otherwise give the ticket back
+    pub fn try_take(self) -> std::result::Result<PoolSlot, PoolTicket> {
+        self.take(None)
+    }
+
+    /// Take the slots like [`PoolTicket::try_take`], otherwise call `wake` once when slots are
+    /// given back or the queue changes
+    pub(crate) fn try_take_or_wake(self, wake: Waker) -> std::result::Result<PoolSlot, PoolTicket> {
+        self.take(Some(wake))
+    }
+
+    fn take(self, wake: Option<Waker>) -> std::result::Result<PoolSlot, PoolTicket> {
+        let taken = match self.pools.0.lock().unwrap().get_mut(&self.name) {
+            Some(pool)
+                if pool.first() == Some(self.ticket) && pool.used + self.cost <= pool.slots =>
+            {
+                pool.used += self.cost;
+                true
+            },
+            Some(pool) => {
+                pool.wakers.extend(wake);
+                false
+            },
+            None => false,
+        };
+        if !taken {
+            return Err(self);
+        }
+        Ok(PoolSlot {
+            pools: self.pools.clone(),
+            name: self.name.clone(),
+            cost: self.cost,
+        })
+    }
+}
+
+impl Drop for PoolTicket {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pools.0.lock().unwrap().get_mut(&self.name) {
+            let first = pool.first() == Some(self.ticket);
+            pool.waiting.retain(|(ticket, _, _)| *ticket != self.ticket);
+            // The next task in the queue may take the free slots now
+            if first {
+                pool.wake();
+            }
+        }
+    }
+}
+
+impl Drop for PoolSlot {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pools.0.lock().unwrap().get_mut(&self.name) {
+            pool.used -= self.cost;
+            pool.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn take_slots_by_cost() {
+        let pools = ResourcePools::new();
+        pools.set_pool("db", 3).unwrap();
+
+        let heavy = pools.try_acquire("db", 2).unwrap().unwrap();
+        let light = pools.try_acquire("db", 1).unwrap().unwrap();
+        assert!(pools.try_acquire("db", 1).unwrap().is_none());
+        assert_eq!(Some(3), pools.used("db"));
+
+        drop(heavy);
+        assert!(pools.try_acquire("db", 2).unwrap().is_some());
+        drop(light);
+        assert_eq!(Some(0), pools.used("db"));
+
+        assert!(pools.try_acquire("db", 4).is_err());
+        assert!(pools.try_acquire("cache", 1).is_err());
+        assert_eq!(Some(0), pools.waiting("db"));
+    }
+
+    #[test]
+    fn give_slots_by_priority() {
+        let pools = ResourcePools::new();
+        pools.set_pool("db", 2).unwrap();
+        let running = pools.try_acquire("db", 2).unwrap().unwrap();
+
+        let low = pools.request("db", 1, 1).unwrap();
+        let high = pools.request("db", 2, 10).unwrap();
+        let high_later = pools.request("db", 1, 10).unwrap();
+        assert_eq!(Some(3), pools.waiting("db"));
+
+        drop(running);
+        // The first task with the highest priority blocks the others until it has the slots
+        let low = low.try_take().unwrap_err();
+        let high_later = high_later.try_take().unwrap_err();
+        let high = high.try_take().unwrap();
+        drop(high);
+        let high_later = high_later.try_take().unwrap();
+        let low = low.try_take().unwrap();
+        assert_eq!(Some(2), pools.used("db"));
+        drop((low, high_later));
+        assert_eq!(Some(0), pools.waiting("db"));
+    }
+
+    #[test]
+    fn wake_waiting_tasks() {
+        let pools = ResourcePools::new();
+        pools.set_pool("db", 1).unwrap();
+        let woken = Arc::new(AtomicU64::new(0));
+        let waker = || -> Waker {
+            let woken = Arc::clone(&woken);
+            Box::new(move || {
+                woken.fetch_add(1, Ordering::SeqCst);
+            })
+        };
+
+        let running = pools.try_acquire("db", 1).unwrap().unwrap();
+        let ticket = pools.request("db", 1, 0).unwrap();
+        let ticket = ticket.try_take_or_wake(waker()).unwrap_err();
+        assert_eq!(0, woken.load(Ordering::SeqCst));
+        drop(running);
+        assert_eq!(1, woken.load(Ordering::SeqCst));
+
+        // A task behind the first one is woken when the first one leaves the queue
+        let later = pools.request("db", 1, 0).unwrap();
+        let later = later.try_take_or_wake(waker()).unwrap_err();
+        drop(ticket);
+        assert_eq!(2, woken.load(Ordering::SeqCst));
+        assert!(later.try_take().is_ok());
+
+        assert!(pools.check("db", 1).is_ok());
+        assert!(pools.check("db", 2).is_err());
+        assert!(pools.check("cache", 1).is_err());
+    }
+
+    #[test]
+    fn reject_resize_below_waiting_cost() {
+        let pools = ResourcePools::new();
+        pools.set_pool("db", 3).unwrap();
+        let running = pools.try_acquire("db", 3).unwrap().unwrap();
+        let ticket = pools.request("db", 2, 0).unwrap();
+
+        assert!(pools.set_pool("db", 1).is_err());
+        assert_eq!(Some(3), pools.slots("db"));
+        pools.set_pool("db", 2).unwrap();
+        drop(running);
+        assert!(ticket.try_take().is_ok());
+        // Nothing is waiting any more
+        pools.set_pool("db", 1).unwrap();
+    }
+}
@@ -0,0 +1,142 @@
+// This is a part of bronze.
+
+//! RetryPolicy, re-run a failed task before it is marked as failed
+//!
+//! The delay between two attempts is fixed or grows exponentially, and a random jitter could
+//! be added to the delay, so the tasks which failed together are not retried at the same time.
+
+use bronzeflow_utils::BronzeError;
+use rand::Rng;
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Backoff {
+    /// Wait the same time before every retry
+    Fixed(Duration),
+    /// Double the delay after every attempt, start from `initial` and never exceed `max`
+    Exponential { initial: Duration, max: Duration },
+}
+
+pub type RetryPredicate = Arc<dyn Fn(&BronzeError) -> bool + Send + Sync>;
+
+#[derive(Clone)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    backoff: Backoff,
+    jitter: bool,
+    retry_if: Option<RetryPredicate>,
+}
+
+impl RetryPolicy {
+    /// Run the task at most `max_attempts` times, the first run included
+    pub fn new(max_attempts: u32, backoff: Backoff) -> Self {
+        RetryPolicy {
+            max_attempts,
+            backoff,
+            jitter: false,
+            retry_if: None,
+        }
+    }
+
+    pub fn fixed(max_attempts: u32, delay: Duration) -> Self {
+        RetryPolicy::new(max_attempts, Backoff::Fixed(delay))
+    }
+
+    pub fn exponential(max_attempts: u32, initial: Duration, max: Duration) -> Self {
+        RetryPolicy::new(max_attempts, Backoff::Exponential { initial, max })
+    }
+
+    /// Wait a random time between the half of the delay and the delay
+    pub fn jitter(mut self, jitter: bool) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Only retry the errors which match `predicate`, all errors are retried by default
+    pub fn retry_if<P>(mut self, predicate: P) -> Self
+    where
+        P: Fn(&BronzeError) -> bool + Send + Sync + 'static,
+    {
+        self.retry_if = Some(Arc::new(predicate));
+        self
+    }
+
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    pub fn backoff(&self) -> &Backoff {
+        &self.backoff
+    }
+
+    /// Whether the task should run again after `attempt` failed with `error`
+    pub fn should_retry(&self, attempt: u32, error: &BronzeError) -> bool {
+        attempt < self.max_attempts && self.retry_if.as_ref().is_none_or(|p| p(error))
+    }
+
+    /// The time to wait before the next attempt, after `attempt` failed
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let delay = match self.backoff {
+            Backoff::Fixed(delay) => delay,
+            Backoff::Exponential { initial, max } => {
+                let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+                initial.saturating_mul(factor).min(max)
+            },
+        };
+        if self.jitter && !delay.is_zero() {
+            let half = delay / 2;
+            half + rand::thread_rng().gen_range(Duration::ZERO..=delay - half)
+        } else {
+            delay
+        }
+    }
+}
+
+impl Debug for RetryPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("max_attempts", &self.max_attempts)
+            .field("backoff", &self.backoff)
+            .field("jitter", &self.jitter)
+            .field("retry_if", &self.retry_if.is_some())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exponential_backoff() {
+        let policy =
+            RetryPolicy::exponential(5, Duration::from_millis(100), Duration::from_millis(500));
+        assert_eq!(Duration::from_millis(100), policy.delay(1));
+        assert_eq!(Duration::from_millis(200), policy.delay(2));
+        assert_eq!(Duration::from_millis(400), policy.delay(3));
+        assert_eq!(Duration::from_millis(500), policy.delay(4));
+        assert_eq!(Duration::from_millis(500), policy.delay(100));
+    }
+
+    #[test]
+    fn backoff_with_jitter() {
+        let policy = RetryPolicy::fixed(3, Duration::from_millis(100)).jitter(true);
+        for _ in 0..100 {
+            let delay = policy.delay(1);
+            assert!(delay >= Duration::from_millis(50) && delay <= Duration::from_millis(100));
+        }
+    }
+
+    #[test]
+    fn retry_matched_errors() {
+        let policy =
+            RetryPolicy::fixed(3, Duration::ZERO).retry_if(|e| e.to_string().contains("timeout"));
+        let timeout = BronzeError::msg("connect timeout");
+        assert!(policy.should_retry(1, &timeout));
+        assert!(policy.should_retry(2, &timeout));
+        assert!(!policy.should_retry(3, &timeout));
+        assert!(!policy.should_retry(1, &BronzeError::msg("bad input")));
+    }
+}
@@ -0,0 +1,265 @@
+// This is a part of bronze.
+
+//! Sensor, a task which waits for an external condition
+//!
+//! A sensor checks its condition every `interval` until the condition is true, and fails if
+//! the condition is still false after `timeout`. In [`SensorMode::Poke`] mode the sensor sleeps
+//! between two checks and keeps its worker. In [`SensorMode::Reschedule`] mode it gives the
+//! worker back: in a DAG run the task is submitted again after `interval`, like a retry which
+//! does not count as an attempt, on tokio it waits with a tokio timer.
+
+use crate::runtime::context::RunContext;
+use crate::runtime::{Runnable, RuntimeJoinHandle};
+use bronzeflow_utils::{ayn_error, debug, Result};
+use std::fmt::{Debug, Formatter};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long to wait for a TCP connection in [`Sensor::port_open`]
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How a sensor waits between two checks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SensorMode {
+    /// Sleep and keep the worker
+    #[default]
+    Poke,
+    /// Give the worker back and check again later
+    Reschedule,
+}
+
+pub type SensorCondition = Arc<dyn Fn(&RunContext) -> Result<bool> + Send + Sync>;
+
+#[derive(Clone)]
+pub struct Sensor {
+    condition: SensorCondition,
+    interval: Duration,
+    timeout: Option<Duration>,
+    mode: SensorMode,
+}
+
+/// The result of one check
+enum Poke {
+    Done,
+    Again(Duration),
+}
+
+impl Sensor {
+    /// Wait until `condition` returns true, an error fails the sensor at once
+    pub fn new<F>(condition: F) -> Self
+    where
+        F: Fn(&RunContext) -> Result<bool> + Send + Sync + 'static,
+    {
+        Sensor {
+            condition: Arc::new(condition),
+            interval: Duration::from_secs(10),
+            timeout: None,
+            mode: SensorMode::default(),
+        }
+    }
+
+    /// Wait until the file or directory `path` exists
+    pub fn file_exists<P: Into<PathBuf>>(path: P) -> Self {
+        let path = path.into();
+        Sensor::new(move |_| Ok(path.exists()))
+    }
+
+    /// Wait until a TCP connection to `addr`, like `localhost:5432`, could be opened
+    pub fn port_open(addr: &str) -> Self {
+        let addr = addr.to_string();
+        Sensor::new(move |_| {
+            // The address is resolved in every check, the host may not be known yet
+            let open = addr.to_socket_addrs().is_ok_and(|mut addrs| {
+                addrs.any(|a| TcpStream::connect_timeout(&a, CONNECT_TIMEOUT).is_ok())
+            });
+            Ok(open)
+        })
+    }
+
+    /// The time to wait between two checks, 10 seconds by default
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Fail if the condition is still false after `timeout`, the sensor waits forever by
+    /// default
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn mode(mut self, mode: SensorMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Check the condition once, `started` is the time of the first check
+    fn poke(&self, ctx: &RunContext, started: Instant) -> Result<Poke> {
+        if ctx.is_cancelled() {
+            return Err(ayn_error!("The sensor was cancelled"));
+        }
+        if (self.condition)(ctx)? {
+            return Ok(Poke::Done);
+        }
+        let elapsed = started.elapsed();
+        match self.timeout {
+            Some(timeout) if elapsed >= timeout => Err(ayn_error!(
+                "The condition of sensor {:?} was not met in {:?}",
+                ctx.task_name(),
+                timeout
+            )),
+            // Do not wait longer than the timeout
+            Some(timeout) => Ok(Poke::Again(self.interval.min(timeout - elapsed))),
+            None => Ok(Poke::Again(self.interval)),
+        }
+    }
+
+    /// Check the condition until it is met, sleep between two checks
+    fn poke_until_done(&self, ctx: &RunContext, started: Instant) -> Result<()> {
+        loop {
+            match self.poke(ctx, started)? {
+                Poke::Done => return Ok(()),
+                Poke::Again(delay) => thread::sleep(delay),
+            }
+        }
+    }
+
+    /// Check the condition on tokio until it is met, wait with a tokio timer between two
+    /// checks
+    #[cfg(feature = "async_tokio")]
+    fn poke_on_tokio(&self, ctx: RunContext, started: Instant) -> Option<RuntimeJoinHandle<()>> {
+        let handle = tokio::runtime::Handle::try_current().ok()?;
+        let sensor = self.clone();
+        Some(RuntimeJoinHandle::AsyncTokioJoinHandle(handle.spawn(
+            async move {
+                let result = loop {
+                    match sensor.poke(&ctx, started) {
+                        Ok(Poke::Again(delay)) => tokio::time::sleep(delay).await,
+                        Ok(Poke::Done) => break Ok(()),
+                        Err(e) => break Err(e),
+                    }
+                };
+                ctx.report(result);
+            },
+        )))
+    }
+}
+
+impl Runnable for Sensor {
+    type Handle = RuntimeJoinHandle<()>;
+
+    #[inline(always)]
+    fn run_async(&self) -> Self::Handle {
+        self.run_with_context(RunContext::default())
+    }
+
+    fn run_with_context(&self, ctx: RunContext) -> Self::Handle {
+        let started = ctx.first_poke.unwrap_or_else(Instant::now);
+        if self.mode == SensorMode::Reschedule {
+            match self.poke(&ctx, started) {
+                // Submitted again by the DAG run
+                Ok(Poke::Again(delay)) if ctx.reschedule(delay) => {
+                    debug!("Reschedule sensor {:?} after {:?}", ctx.task_name(), delay);
+                    return RuntimeJoinHandle::SyncJobHandle;
+                },
+                Ok(Poke::Again(_)) => {
+                    #[cfg(feature = "async_tokio")]
+                    if let Some(handle) = self.poke_on_tokio(ctx.clone(), started) {
+                        return handle;
+                    }
+                },
+                result => {
+                    ctx.report(result.map(|_| ()));
+                    return RuntimeJoinHandle::SyncJobHandle;
+                },
+            }
+        }
+        ctx.report(self.poke_until_done(&ctx, started));
+        RuntimeJoinHandle::SyncJobHandle
+    }
+}
+
+impl Debug for Sensor {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Sensor")
+            .field("interval", &self.interval)
+            .field("timeout", &self.timeout)
+            .field("mode", &self.mode)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    #[test]
+    fn poke_until_condition_is_met() {
+        let checks = Arc::new(AtomicU32::new(0));
+        let sensor = {
+            let checks = Arc::clone(&checks);
+            Sensor::new(move |_| Ok(checks.fetch_add(1, Ordering::SeqCst) >= 2))
+                .interval(Duration::from_millis(10))
+        };
+        let ctx = RunContext::default();
+        sensor.run_with_context(ctx.clone());
+        assert_eq!(None, ctx.error());
+        assert_eq!(3, checks.load(Ordering::SeqCst));
+
+        let ctx = RunContext::default();
+        let started = Instant::now();
+        Sensor::new(|_| Ok(false))
+            .interval(Duration::from_millis(10))
+            .timeout(Duration::from_millis(50))
+            .run_with_context(ctx.clone());
+        assert!(ctx.error().unwrap().contains("was not met"));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn built_in_conditions() {
+        let ctx = RunContext::default();
+        let missing = std::env::temp_dir().join("bronze_sensor_missing_file");
+        assert!(matches!(
+            Sensor::file_exists(missing).poke(&ctx, Instant::now()),
+            Ok(Poke::Again(_))
+        ));
+        assert!(matches!(
+            Sensor::file_exists(std::env::temp_dir()).poke(&ctx, Instant::now()),
+            Ok(Poke::Done)
+        ));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        assert!(matches!(
+            Sensor::port_open(&addr).poke(&ctx, Instant::now()),
+            Ok(Poke::Done)
+        ));
+    }
+
+    #[cfg(feature = "async_tokio")]
+    #[tokio::test]
+    async fn reschedule_on_tokio() {
+        let checks = Arc::new(AtomicU32::new(0));
+        let sensor = {
+            let checks = Arc::clone(&checks);
+            Sensor::new(move |_| Ok(checks.fetch_add(1, Ordering::SeqCst) >= 2))
+                .interval(Duration::from_millis(10))
+                .mode(SensorMode::Reschedule)
+        };
+        let ctx = RunContext::default();
+        let RuntimeJoinHandle::AsyncTokioJoinHandle(handle) = sensor.run_with_context(ctx.clone())
+        else {
+            panic!("The sensor should wait on tokio");
+        };
+        handle.await.unwrap();
+        assert_eq!(None, ctx.error());
+        assert_eq!(3, checks.load(Ordering::SeqCst));
+    }
+}
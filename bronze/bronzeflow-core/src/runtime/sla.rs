@@ -0,0 +1,71 @@
+// This is a part of bronze.
+
+//! Sla, the deadline of a task or a DAG run
+//!
+//! A deadline is computed from the logical time of the run, which is the last run time of the
+//! schedule of the DAG, or from the time the run started if it was not scheduled. When the
+//! deadline passes while the task or the run has not finished, the miss is logged, recorded in
+//! the [`RunHistory`] and the `on_sla_miss` callback is called. The deadlines are watched by a
+//! thread of their own, so a miss is reported on time while a task is still running. The task
+//! or the run is not stopped.
+//!
+//! [`RunHistory`]: crate::runtime::history::RunHistory
+
+use chrono::{DateTime, Duration as ChronoDuration, NaiveTime, Utc};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sla {
+    /// Finish within this time after the logical time
+    Within(Duration),
+    /// Finish by this time of day in UTC, the first one at or after the logical time
+    By(NaiveTime),
+}
+
+impl Sla {
+    /// The deadline of a run whose logical time is `base`
+    pub fn deadline(&self, base: DateTime<Utc>) -> DateTime<Utc> {
+        match *self {
+            Sla::Within(d) => ChronoDuration::from_std(d)
+                .ok()
+                .and_then(|d| base.checked_add_signed(d))
+                .unwrap_or(DateTime::<Utc>::MAX_UTC),
+            Sla::By(time) => {
+                let same_day = base.date_naive().and_time(time).and_utc();
+                match same_day < base {
+                    true => same_day + ChronoDuration::days(1),
+                    false => same_day,
+                }
+            },
+        }
+    }
+}
+
+/// A deadline which passed before the task or the run finished
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlaMiss {
+    pub run_id: String,
+    /// `None` if the run missed its deadline
+    pub task: Option<String>,
+    pub deadline: DateTime<Utc>,
+    pub detected_at: DateTime<Utc>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compute_deadlines() {
+        let at = |s: &str| s.parse::<DateTime<Utc>>().unwrap();
+        let base = at("2024-01-01T05:00:00Z");
+        assert_eq!(
+            at("2024-01-01T05:20:00Z"),
+            Sla::Within(Duration::from_secs(20 * 60)).deadline(base)
+        );
+        let six = NaiveTime::from_hms_opt(6, 0, 0).unwrap();
+        assert_eq!(at("2024-01-01T06:00:00Z"), Sla::By(six).deadline(base));
+        let four = NaiveTime::from_hms_opt(4, 0, 0).unwrap();
+        assert_eq!(at("2024-01-02T04:00:00Z"), Sla::By(four).deadline(base));
+    }
+}
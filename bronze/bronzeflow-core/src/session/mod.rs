@@ -1,15 +1,25 @@
 use crate::executor::DefaultExecutor;
 use crate::manager::ScheduleManager;
 use crate::prelude::{Executor, ThreadTrigger, Trigger, DAG};
+use crate::runtime::history::RunHistory;
 use crate::service::Service;
-use crate::store::{MemoryStorage, Storage};
+use crate::store::{MemoryStorage, RunnableKey, Storage};
+use crate::task::backfill::Backfill;
+use crate::task::definition::{DAGDefinition, TaskRegistry};
+use crate::task::state::DAGRunReport;
 use crate::task::RunnableHolder;
 use bronzeflow_time::prelude::ScheduleExpr;
-use bronzeflow_utils::{debug, BronzeError, Result};
+use bronzeflow_time::schedule_time::ScheduleTime;
+use bronzeflow_utils::{ayn_error, debug, BronzeError, Result};
+use serde_json::Value;
 use std::fmt::Debug;
 
 pub trait Session: Service {
-    fn submit<D, S>(&mut self, s: S, try_into_dag: D) -> Result<()>
+    /// Submit a DAG with schedule `s`, return the id of the DAG in the session
+    ///
+    /// A DAG with a single task is submitted as a DAG too, it is not turned into a task any
+    /// more, so the task gets the retry policy, the run history and the manual runs of a DAG
+    fn submit<D, S>(&mut self, s: S, try_into_dag: D) -> Result<u64>
     where
         D: Into<DAG>,
         S: TryInto<ScheduleExpr>,
@@ -19,18 +29,119 @@ pub trait Session: Service {
         let mut dag = try_into_dag.into();
         dag.set_schedule(schedule_expr);
         dag.prepare();
+        debug!("Submit dag with {} task(s)", dag.cal_task_nums());
+        self.submit_runnable(RunnableHolder::Dag(dag))
+    }
 
-        let runnable = if dag.cal_task_nums() == 1 {
-            debug!("Dag has one task, transform it to single task");
-            let task = dag.to_single_task()?;
-            RunnableHolder::Task(task)
-        } else {
-            RunnableHolder::Dag(dag)
-        };
-        self.submit_runnable(runnable)
+    /// Build the DAG of `definition` and submit it with the schedule of the definition
+    fn submit_definition(
+        &mut self,
+        definition: &DAGDefinition,
+        registry: &TaskRegistry,
+    ) -> Result<u64> {
+        let schedule = definition
+            .schedule
+            .as_deref()
+            .ok_or_else(|| BronzeError::msg("DAG definition has no schedule"))?;
+        self.submit(schedule, definition.build(registry)?)
+    }
+
+    fn submit_runnable(&mut self, runnable: RunnableHolder) -> Result<u64>;
+
+    /// Stop triggering the DAG or task of `key`, which is its id or its name. The runs in
+    /// progress go on
+    fn pause<K: Into<RunnableKey>>(&mut self, key: K) -> Result<()> {
+        self.set_paused(&key.into(), true)
+    }
+
+    /// Trigger the paused DAG or task of `key` again, the schedule times missed while it was
+    /// paused are skipped
+    fn resume<K: Into<RunnableKey>>(&mut self, key: K) -> Result<()> {
+        self.set_paused(&key.into(), false)
     }
 
-    fn submit_runnable(&mut self, runnable: RunnableHolder) -> Result<()>;
+    /// Replace the schedule of the DAG or task of `key`, it takes effect on the next tick of
+    /// the trigger
+    fn update_schedule<K, S>(&mut self, key: K, s: S) -> Result<()>
+    where
+        K: Into<RunnableKey>,
+        S: TryInto<ScheduleExpr>,
+        BronzeError: From<S::Error>,
+    {
+        let schedule_expr = s.try_into()?;
+        self.set_schedule(&key.into(), schedule_expr)
+    }
+
+    /// Replace the DAG or task of `key` with a new DAG, which keeps the id, the schedule and
+    /// the paused state of the old one. The schedule is replaced too if the new DAG has one
+    fn update<K, D>(&mut self, key: K, try_into_dag: D) -> Result<()>
+    where
+        K: Into<RunnableKey>,
+        D: Into<DAG>,
+    {
+        self.replace_runnable(&key.into(), RunnableHolder::Dag(try_into_dag.into()))
+    }
+
+    /// Run the DAG of `key` now, outside its schedule. The tasks read `params` from their
+    /// [`RunContext`], the run is recorded as manual in the run history. The run has no
+    /// logical time, its external dependencies wait for the latest runs of the other DAGs. The
+    /// id of the run is returned
+    ///
+    /// [`RunContext`]: crate::runtime::context::RunContext
+    fn trigger_now<K: Into<RunnableKey>>(&mut self, key: K, params: Value) -> Result<String> {
+        self.trigger_runnable(&key.into(), params, None)
+    }
+
+    /// Run the DAG of `key` now for `logical_time`, like [`Session::trigger_now`]
+    fn trigger_at<K: Into<RunnableKey>>(
+        &mut self,
+        key: K,
+        params: Value,
+        logical_time: ScheduleTime,
+    ) -> Result<String> {
+        self.trigger_runnable(&key.into(), params, Some(logical_time))
+    }
+
+    /// Run the DAG of `key` for its schedule times in the range of `backfill`, block until
+    /// all runs have finished. The reports are returned with the logical times of the runs
+    fn backfill<K: Into<RunnableKey>>(
+        &mut self,
+        key: K,
+        backfill: &Backfill,
+    ) -> Result<Vec<(ScheduleTime, DAGRunReport)>> {
+        self.backfill_runnable(&key.into(), backfill)
+    }
+
+    /// Remove the DAG or task of `key`, the runs in progress go on
+    fn remove<K: Into<RunnableKey>>(&mut self, key: K) -> Result<()> {
+        self.remove_runnable(&key.into())
+    }
+
+    fn set_paused(&mut self, key: &RunnableKey, paused: bool) -> Result<()>;
+
+    fn set_schedule(&mut self, key: &RunnableKey, schedule_expr: ScheduleExpr) -> Result<()>;
+
+    fn replace_runnable(&mut self, key: &RunnableKey, runnable: RunnableHolder) -> Result<()>;
+
+    fn remove_runnable(&mut self, key: &RunnableKey) -> Result<()>;
+
+    fn trigger_runnable(
+        &mut self,
+        key: &RunnableKey,
+        params: Value,
+        logical_time: Option<ScheduleTime>,
+    ) -> Result<String>;
+
+    fn backfill_runnable(
+        &mut self,
+        key: &RunnableKey,
+        backfill: &Backfill,
+    ) -> Result<Vec<(ScheduleTime, DAGRunReport)>>;
+
+    /// Create resource pool `name` with `slots` slots, or resize it if it exists. A DAG whose
+    /// tasks are in a pool is only submitted after the pool is created. A pool is not resized
+    /// below the slots of a task waiting for it
+    fn set_pool(&mut self, name: &str, slots: u32) -> Result<()>;
 
     fn build_session(&mut self) -> Result<()>;
 }
@@ -51,6 +162,17 @@ impl<SG: Storage, TG: Trigger, E: Executor> LocalSession<SG, TG, E> {
             executor,
         }
     }
+
+    /// The records of the runs in the session, an error if the session is not built
+    pub fn history(&mut self) -> Result<&RunHistory> {
+        self.manager().map(|m| m.history())
+    }
+
+    fn manager(&mut self) -> Result<&mut ScheduleManager<SG, TG, E>> {
+        self.manager
+            .as_mut()
+            .ok_or_else(|| BronzeError::msg("Session is not built"))
+    }
 }
 
 impl<SG: Storage, TG: Trigger, E: Executor> Service for LocalSession<SG, TG, E> {
@@ -72,9 +194,49 @@ impl<SG: Storage, TG: Trigger, E: Executor> Drop for LocalSession<SG, TG, E> {
 }
 
 impl<SG: Storage, TG: Trigger, E: Executor> Session for LocalSession<SG, TG, E> {
-    fn submit_runnable(&mut self, runnable: RunnableHolder) -> Result<()> {
-        self.manager.as_mut().unwrap().add_runnable(runnable);
-        Ok(())
+    fn submit_runnable(&mut self, runnable: RunnableHolder) -> Result<u64> {
+        self.manager()?.add_runnable(runnable)
+    }
+
+    fn set_paused(&mut self, key: &RunnableKey, paused: bool) -> Result<()> {
+        let manager = self.manager()?;
+        match paused {
+            true => manager.pause(key),
+            false => manager.resume(key),
+        }
+    }
+
+    fn set_schedule(&mut self, key: &RunnableKey, schedule_expr: ScheduleExpr) -> Result<()> {
+        self.manager()?.update_schedule(key, schedule_expr)
+    }
+
+    fn replace_runnable(&mut self, key: &RunnableKey, runnable: RunnableHolder) -> Result<()> {
+        self.manager()?.replace_runnable(key, runnable)
+    }
+
+    fn remove_runnable(&mut self, key: &RunnableKey) -> Result<()> {
+        self.manager()?.remove_runnable(key).map(|_| ())
+    }
+
+    fn trigger_runnable(
+        &mut self,
+        key: &RunnableKey,
+        params: Value,
+        logical_time: Option<ScheduleTime>,
+    ) -> Result<String> {
+        self.manager()?.trigger_now(key, params, logical_time)
+    }
+
+    fn backfill_runnable(
+        &mut self,
+        key: &RunnableKey,
+        backfill: &Backfill,
+    ) -> Result<Vec<(ScheduleTime, DAGRunReport)>> {
+        self.manager()?.backfill(key, backfill)
+    }
+
+    fn set_pool(&mut self, name: &str, slots: u32) -> Result<()> {
+        self.manager()?.pools().set_pool(name, slots)
     }
 
     fn build_session(&mut self) -> Result<()> {
@@ -239,15 +401,26 @@ impl<SG: Storage, TG: Trigger, E: Executor> RemoteSession<SG, TG, E> {
             executor,
         }
     }
+
+    fn unsupported<T>(what: &str) -> Result<T> {
+        Err(ayn_error!(
+            "{} is not supported by the remote session yet",
+            what
+        ))
+    }
 }
 
 impl<SG: Storage, TG: Trigger, E: Executor> Service for RemoteSession<SG, TG, E> {
     fn start(&mut self) {
-        todo!()
+        if let Some(ref mut m) = self.manager {
+            m.start();
+        }
     }
 
     fn stop(&mut self) {
-        todo!()
+        if let Some(ref mut m) = self.manager {
+            m.stop();
+        }
     }
 }
 
@@ -260,8 +433,45 @@ impl<SG: Storage, TG: Trigger, E: Executor> Drop for RemoteSession<SG, TG, E> {
 }
 
 impl<SG: Storage, TG: Trigger, E: Executor> Session for RemoteSession<SG, TG, E> {
-    fn submit_runnable(&mut self, _: RunnableHolder) -> Result<()> {
-        todo!()
+    fn submit_runnable(&mut self, _: RunnableHolder) -> Result<u64> {
+        Self::unsupported("Submitting a runnable")
+    }
+
+    fn set_paused(&mut self, _: &RunnableKey, _: bool) -> Result<()> {
+        Self::unsupported("Pausing a runnable")
+    }
+
+    fn set_schedule(&mut self, _: &RunnableKey, _: ScheduleExpr) -> Result<()> {
+        Self::unsupported("Updating a schedule")
+    }
+
+    fn replace_runnable(&mut self, _: &RunnableKey, _: RunnableHolder) -> Result<()> {
+        Self::unsupported("Replacing a runnable")
+    }
+
+    fn remove_runnable(&mut self, _: &RunnableKey) -> Result<()> {
+        Self::unsupported("Removing a runnable")
+    }
+
+    fn trigger_runnable(
+        &mut self,
+        _: &RunnableKey,
+        _: Value,
+        _: Option<ScheduleTime>,
+    ) -> Result<String> {
+        Self::unsupported("Triggering a run")
+    }
+
+    fn backfill_runnable(
+        &mut self,
+        _: &RunnableKey,
+        _: &Backfill,
+    ) -> Result<Vec<(ScheduleTime, DAGRunReport)>> {
+        Self::unsupported("Backfilling a DAG")
+    }
+
+    fn set_pool(&mut self, _: &str, _: u32) -> Result<()> {
+        Self::unsupported("Setting a pool")
     }
 
     fn build_session(&mut self) -> Result<()> {
@@ -309,6 +519,9 @@ mod tests {
     use super::*;
     use crate::dag;
     use crate::prelude::*;
+    use crate::runtime::history::RunRecord;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
     use std::{thread, time};
 
     fn get_dag() -> DAG {
@@ -321,6 +534,15 @@ mod tests {
         .unwrap()
     }
 
+    fn wait_finished(history: &RunHistory, run_id: &str) -> RunRecord {
+        let finished = || history.get(run_id).filter(|r| r.state.is_finished());
+        let started = time::Instant::now();
+        while finished().is_none() && started.elapsed() < time::Duration::from_secs(10) {
+            thread::sleep(time::Duration::from_millis(20));
+        }
+        finished().unwrap()
+    }
+
     fn test_local_session(
         d: DAG,
         trigger: impl Trigger + 'static,
@@ -410,6 +632,171 @@ mod tests {
         thread::sleep(time::Duration::from_secs(2));
     }
 
+    #[test]
+    fn manage_submitted_dags() {
+        let counter = |count: &Arc<AtomicUsize>| {
+            let count = Arc::clone(count);
+            move || {
+                count.fetch_add(1, Ordering::SeqCst);
+            }
+        };
+        let (first, second) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let mut s = SessionBuilder::default().build().unwrap();
+        let mut d = DAG::from(counter(&first));
+        d.set_name("count");
+        let id = s.submit("* * * * * *", d).unwrap();
+        s.pause("count").unwrap();
+        // A run may have been triggered before the pause
+        thread::sleep(time::Duration::from_millis(1200));
+        let paused = first.load(Ordering::SeqCst);
+        thread::sleep(time::Duration::from_millis(1500));
+        assert_eq!(paused, first.load(Ordering::SeqCst));
+
+        s.resume(id).unwrap();
+        s.update("count", counter(&second)).unwrap();
+        thread::sleep(time::Duration::from_millis(2500));
+        assert_eq!(paused, first.load(Ordering::SeqCst));
+        assert!(second.load(Ordering::SeqCst) > 0);
+
+        s.update_schedule(id, "0 0 0 1 1 *").unwrap();
+        thread::sleep(time::Duration::from_millis(600));
+        let updated = second.load(Ordering::SeqCst);
+        thread::sleep(time::Duration::from_millis(1500));
+        assert_eq!(updated, second.load(Ordering::SeqCst));
+
+        s.remove("count").unwrap();
+        assert!(s.pause(id).is_err());
+        assert!(s.remove("count").is_err());
+    }
+
+    #[test]
+    fn trigger_run_now() {
+        let dates = Arc::new(Mutex::new(vec![]));
+        let task = {
+            let dates = Arc::clone(&dates);
+            move |ctx: &RunContext| {
+                let date = ctx.param::<String>("date")?;
+                dates.lock().unwrap().push((date, ctx.is_manual()));
+                Ok(())
+            }
+        };
+        let mut s = SessionBuilder::default().build().unwrap();
+        let mut d = DAG::from(SyncContextFn(task));
+        d.set_name("report");
+        let id = s.submit("0 0 0 1 1 *", d).unwrap();
+        s.pause(id).unwrap();
+        let run_id = s
+            .trigger_now(id, serde_json::json!({"date": "2024-01-02"}))
+            .unwrap();
+
+        let history = s.history().unwrap().clone();
+        let record = wait_finished(&history, &run_id);
+        assert_eq!(
+            (RunState::Success, true, Some("report")),
+            (record.state, record.manual, record.dag_name.as_deref())
+        );
+        assert!(record.logical_time.is_none());
+        assert_eq!(
+            vec![("2024-01-02".to_string(), true)],
+            *dates.lock().unwrap()
+        );
+
+        let day: ScheduleTime = "2024-01-03T00:00:00Z".parse().unwrap();
+        let params = serde_json::json!({"date": "2024-01-03"});
+        let run_id = s.trigger_at(id, params, day.clone()).unwrap();
+        assert_eq!(Some(day), wait_finished(&history, &run_id).logical_time);
+        assert!(s.trigger_now("no-such-dag", Value::Null).is_err());
+    }
+
+    #[test]
+    fn trigger_run_with_external_dependency() {
+        let mut s = SessionBuilder::default().build().unwrap();
+        let mut ingest = DAG::from(|| {});
+        ingest.set_name("ingest");
+        s.submit("0 0 0 1 1 *", ingest).unwrap();
+        let wait = ExternalDependency::new("ingest")
+            .interval(time::Duration::from_millis(20))
+            .timeout(time::Duration::from_secs(5));
+        let mut report = DAG::from(wait);
+        report.set_name("report");
+        s.submit("0 0 0 1 1 *", report).unwrap();
+
+        let history = s.history().unwrap().clone();
+        let upstream = s.trigger_now("ingest", Value::Null).unwrap();
+        wait_finished(&history, &upstream);
+        // The manual run waits for the latest run of the other DAG
+        let run_id = s.trigger_now("report", Value::Null).unwrap();
+        assert_eq!(RunState::Success, wait_finished(&history, &run_id).state);
+    }
+
+    #[test]
+    fn submit_single_task_as_dag() {
+        let mut s = SessionBuilder::default().build().unwrap();
+        let first = s.submit("0 0 0 1 1 *", get_dag()).unwrap();
+        let mut d = DAG::from(|| {});
+        d.set_name("single");
+        let second = s.submit("0 0 0 1 1 *", d).unwrap();
+        // Both are DAGs, so they take ids in turn and could be triggered by hand
+        assert_eq!(first + 1, second);
+        let run_id = s.trigger_now("single", Value::Null).unwrap();
+        let history = s.history().unwrap().clone();
+        assert_eq!(
+            Some("single"),
+            wait_finished(&history, &run_id).dag_name.as_deref()
+        );
+    }
+
+    #[test]
+    fn fail_on_session_not_built() {
+        let mut s = LocalSession::new(
+            Some(MemoryStorage::new()),
+            Some(ThreadTrigger::new()),
+            Some(DefaultExecutor::new()),
+        );
+        assert!(s.submit("0 0 0 1 1 *", get_dag()).is_err());
+        assert!(s.set_pool("db", 1).is_err());
+        assert!(s.history().is_err());
+    }
+
+    #[test]
+    fn reject_dag_with_unknown_pool() {
+        let pooled = |name: &str, slots: u32| {
+            DAGBuilder::new()
+                .task("load", || {})
+                .pool(name, slots)
+                .build()
+                .unwrap()
+        };
+        let mut s = SessionBuilder::default().build().unwrap();
+        s.set_pool("db", 2).unwrap();
+        assert!(s.submit("0 0 0 1 1 *", pooled("cache", 1)).is_err());
+        assert!(s.submit("0 0 0 1 1 *", pooled("db", 3)).is_err());
+        assert!(s.submit("0 0 0 1 1 *", pooled("db", 2)).is_ok());
+    }
+
+    #[test]
+    fn backfill_submitted_dag() {
+        let mut s = SessionBuilder::default().build().unwrap();
+        let mut d = DAG::from(SyncContextFn(|ctx: &RunContext| {
+            Ok(ctx.logical_time().map(|t| t.to_string()))
+        }));
+        d.set_name("daily");
+        s.submit("0 0 0 * * *", d).unwrap();
+        let day = |d: &str| format!("2024-01-0{}T00:00:00Z", d).parse().unwrap();
+        let reports = s
+            .backfill("daily", &Backfill::new(day("1"), day("3")))
+            .unwrap();
+        assert_eq!(3, reports.len());
+        assert!(reports.iter().all(|(_, r)| r.is_success()));
+
+        let runs = s.history().unwrap().runs("daily");
+        let times: Vec<_> = runs.iter().filter_map(|r| r.logical_time.clone()).collect();
+        assert_eq!(vec![day("1"), day("2"), day("3")], times);
+        assert!(runs
+            .iter()
+            .all(|r| r.state == RunState::Success && !r.manual));
+    }
+
     #[cfg(feature = "async_tokio")]
     #[tokio::test]
     async fn run_async_unction() {
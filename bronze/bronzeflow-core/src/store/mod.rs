@@ -3,14 +3,86 @@
 //! Storage, storage the runnable object
 //!
 //!  Storage is a common layer for storing scheduling tasks. Now, just support the ```MemoryStorage```
+//!
+//! The trigger loads the runnables from the storage at every tick, so a runnable which is
+//! paused, removed or updated in the storage takes effect on the next tick. The runs in
+//! progress are not affected.
+//!
+//! The methods to manage the saved runnables are not supported by a storage by default.
 
 use crate::task::RunnableHolder;
+use bronzeflow_time::prelude::ScheduleExpr;
+use bronzeflow_time::schedule_time::{ScheduleTimeHolder, ScheduleTimeOp};
+use bronzeflow_utils::{ayn_error, Result};
+use std::fmt::{Display, Formatter};
 use std::sync::{Arc, Mutex};
 
+/// The key of a submitted runnable, the id assigned by the manager or the name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RunnableKey {
+    Id(u64),
+    Name(String),
+}
+
+impl From<u64> for RunnableKey {
+    fn from(id: u64) -> Self {
+        RunnableKey::Id(id)
+    }
+}
+
+impl From<&str> for RunnableKey {
+    fn from(name: &str) -> Self {
+        RunnableKey::Name(name.to_string())
+    }
+}
+
+impl Display for RunnableKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RunnableKey::Id(id) => write!(f, "id {}", id),
+            RunnableKey::Name(name) => write!(f, "name `{}`", name),
+        }
+    }
+}
+
 pub trait Storage: Send {
     fn save_runnable(&mut self, runnable: RunnableHolder);
 
     fn load_runnable(&self) -> Vec<RunnableHolder>;
+
+    fn find_runnable(&self, _: &RunnableKey) -> Option<RunnableHolder> {
+        None
+    }
+
+    /// Pause or resume the runnable, a resumed runnable is next triggered at its first
+    /// schedule time after now, the times missed while it was paused are skipped
+    fn set_paused(&mut self, _: &RunnableKey, _: bool) -> Result<()> {
+        Err(ayn_error!(
+            "Pausing a runnable is not supported by the storage"
+        ))
+    }
+
+    /// Replace the schedule of the runnable, it is next triggered at the first time of
+    /// `schedule` after now. A schedule which is not a cron expression, or which has no time
+    /// after now, is rejected
+    fn set_schedule(&mut self, _: &RunnableKey, _: ScheduleExpr) -> Result<()> {
+        Err(ayn_error!(
+            "Updating a schedule is not supported by the storage"
+        ))
+    }
+
+    /// Replace the runnable in place, it keeps its position in the storage
+    fn replace_runnable(&mut self, _: &RunnableKey, _: RunnableHolder) -> Result<()> {
+        Err(ayn_error!(
+            "Replacing a runnable is not supported by the storage"
+        ))
+    }
+
+    fn remove_runnable(&mut self, _: &RunnableKey) -> Result<RunnableHolder> {
+        Err(ayn_error!(
+            "Removing a runnable is not supported by the storage"
+        ))
+    }
 }
 
 pub type StorageType<SG> = Arc<Mutex<SG>>;
@@ -24,6 +96,18 @@ impl MemoryStorage {
     pub fn new() -> Self {
         MemoryStorage { runs: vec![] }
     }
+
+    fn position(&self, key: &RunnableKey) -> Result<usize> {
+        self.runs
+            .iter()
+            .position(|r| r.matches(key))
+            .ok_or_else(|| ayn_error!("No runnable with {}", key))
+    }
+
+    fn get_mut(&mut self, key: &RunnableKey) -> Result<&mut RunnableHolder> {
+        let i = self.position(key)?;
+        Ok(&mut self.runs[i])
+    }
 }
 
 impl Storage for MemoryStorage {
@@ -34,4 +118,101 @@ impl Storage for MemoryStorage {
     fn load_runnable(&self) -> Vec<RunnableHolder> {
         self.runs.iter().map(RunnableHolder::clone).collect()
     }
+
+    fn find_runnable(&self, key: &RunnableKey) -> Option<RunnableHolder> {
+        self.runs.iter().find(|r| r.matches(key)).cloned()
+    }
+
+    fn set_paused(&mut self, key: &RunnableKey, paused: bool) -> Result<()> {
+        let meta = self
+            .get_mut(key)?
+            .time_holder()
+            .ok_or_else(|| ayn_error!("Runnable with {} has no metadata", key))?;
+        let mut meta = meta.lock().unwrap();
+        if meta.paused && !paused {
+            if let Some(ref mut schedule) = meta.schedule {
+                schedule
+                    .try_init()
+                    .map_err(|e| ayn_error!("Could not resume runnable with {}: {}", key, e))?;
+            }
+        }
+        meta.paused = paused;
+        Ok(())
+    }
+
+    fn set_schedule(&mut self, key: &RunnableKey, schedule: ScheduleExpr) -> Result<()> {
+        let mut time_holder = ScheduleTimeHolder::new(schedule);
+        time_holder
+            .try_init()
+            .map_err(|e| ayn_error!("Invalid schedule for runnable with {}: {}", key, e))?;
+        let meta = self
+            .get_mut(key)?
+            .time_holder()
+            .ok_or_else(|| ayn_error!("Runnable with {} has no metadata", key))?;
+        let mut meta = meta.lock().unwrap();
+        if let Some(last_run) = meta.schedule.as_ref().and_then(|s| s.last_run()) {
+            time_holder.set_last_run(&last_run);
+        }
+        meta.set_schedule(time_holder);
+        Ok(())
+    }
+
+    fn replace_runnable(&mut self, key: &RunnableKey, runnable: RunnableHolder) -> Result<()> {
+        *self.get_mut(key)? = runnable;
+        Ok(())
+    }
+
+    fn remove_runnable(&mut self, key: &RunnableKey) -> Result<RunnableHolder> {
+        let i = self.position(key)?;
+        Ok(self.runs.remove(i))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::*;
+    use bronzeflow_time::schedule_time::ScheduleTime;
+    use chrono::{Duration, Utc};
+
+    fn holder(name: &str, id: u64) -> RunnableHolder {
+        let mut d = DAG::from(|| {});
+        d.set_name(name);
+        d.set_schedule("* * * * * *".try_into().unwrap());
+        d.prepare();
+        d.meta.as_ref().unwrap().lock().unwrap().set_id(id);
+        RunnableHolder::Dag(d)
+    }
+
+    #[test]
+    fn manage_runnables() {
+        let mut storage = MemoryStorage::new();
+        storage.save_runnable(holder("a", 0));
+        storage.save_runnable(holder("b", 1));
+        let later = ScheduleTime::new(Utc::now() + Duration::days(1));
+
+        storage.set_paused(&"a".into(), true).unwrap();
+        assert!(!storage.find_runnable(&0.into()).unwrap().is_due(&later));
+        storage.set_paused(&0.into(), false).unwrap();
+        assert!(storage.find_runnable(&"a".into()).unwrap().is_due(&later));
+
+        let yearly: ScheduleExpr = "0 0 0 1 1 *".try_into().unwrap();
+        storage.set_schedule(&"b".into(), yearly).unwrap();
+        let soon = ScheduleTime::new(Utc::now() + Duration::seconds(2));
+        assert!(!storage.find_runnable(&1.into()).unwrap().is_due(&soon));
+        let once: ScheduleExpr = "@once".try_into().unwrap();
+        assert!(storage.set_schedule(&"b".into(), once).is_err());
+        let past: ScheduleExpr = "0 0 0 1 1 * 2000".try_into().unwrap();
+        assert!(storage.set_schedule(&"b".into(), past).is_err());
+        assert!(!storage.find_runnable(&1.into()).unwrap().is_due(&soon));
+
+        storage
+            .replace_runnable(&"a".into(), holder("c", 0))
+            .unwrap();
+        assert!(storage.find_runnable(&"a".into()).is_none());
+        storage.remove_runnable(&"c".into()).unwrap();
+        assert_eq!(1, storage.load_runnable().len());
+        assert!(storage.remove_runnable(&"c".into()).is_err());
+        assert!(storage.set_paused(&7.into(), true).is_err());
+    }
 }
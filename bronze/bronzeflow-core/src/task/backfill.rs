@@ -0,0 +1,198 @@
+// This is a part of bronze.
+
+//! Backfill, run a DAG for the past times of its schedule
+//!
+//! A backfill creates one run for every time of the cron schedule of the DAG from the start to
+//! the end, the logical time of the run is the schedule time. At most `max_active_runs` runs
+//! are in progress at the same time, the oldest logical time runs first unless the backfill is
+//! reversed. The runs are created one by one when they start, so a long range does not hold
+//! all of them at once. The maximum parallelism of the DAG applies to the backfill runs, the
+//! overlap policy and the SLAs of the DAG do not, since the logical times are in the past, and
+//! a failed run does not stop the others.
+
+use crate::task::dag::DAG;
+use crate::task::dag_run::DAGRun;
+use crate::task::state::DAGRunReport;
+use crate::trigger::{TriggerCaller, TriggerCallerType};
+use bronzeflow_time::schedule_time::ScheduleTime;
+use bronzeflow_utils::{ayn_error, info, Result};
+use serde_json::Value;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+#[derive(Debug, Clone)]
+pub struct Backfill {
+    start: ScheduleTime,
+    end: ScheduleTime,
+    max_active_runs: usize,
+    reverse: bool,
+    params: Value,
+}
+
+impl Backfill {
+    /// Backfill the schedule times from `start` to `end`, both inclusive
+    pub fn new(start: ScheduleTime, end: ScheduleTime) -> Self {
+        Backfill {
+            start,
+            end,
+            max_active_runs: 1,
+            reverse: false,
+            params: Value::Null,
+        }
+    }
+
+    /// The maximum number of backfill runs in progress at the same time, 1 by default
+    pub fn max_active_runs(mut self, max_active_runs: usize) -> Self {
+        self.max_active_runs = max_active_runs.max(1);
+        self
+    }
+
+    /// Run the latest logical time first
+    pub fn reverse(mut self, reverse: bool) -> Self {
+        self.reverse = reverse;
+        self
+    }
+
+    /// The parameters of every run
+    pub fn params(mut self, params: Value) -> Self {
+        self.params = params;
+        self
+    }
+
+    /// The logical times of the runs of `dag`, in the order they start. It fails if the start
+    /// is after the end
+    pub fn logical_times(
+        &self,
+        dag: &DAG,
+    ) -> Result<Box<dyn Iterator<Item = ScheduleTime> + Send>> {
+        let schedule = dag
+            .schedule_expr()
+            .ok_or_else(|| ayn_error!("DAG {:?} has no schedule to backfill", dag.name()))?;
+        schedule.times_between(&self.start, &self.end, self.reverse)
+    }
+
+    /// Run `dag` for all logical times and block until the runs have finished, the reports
+    /// are returned in the order the runs started
+    pub fn run<TC>(
+        &self,
+        dag: &DAG,
+        trigger_caller: TriggerCallerType<TC>,
+    ) -> Result<Vec<(ScheduleTime, DAGRunReport)>>
+    where
+        TC: TriggerCaller + 'static,
+    {
+        info!(
+            "Backfill DAG {:?} from {} to {}",
+            dag.name(),
+            self.start,
+            self.end
+        );
+        let times = Mutex::new(self.logical_times(dag)?.enumerate());
+        let reports = Mutex::new(vec![]);
+        thread::scope(|s| {
+            for _ in 0..self.max_active_runs {
+                s.spawn(|| loop {
+                    let Some((i, t)) = times.lock().unwrap().next() else {
+                        break;
+                    };
+                    let run = DAGRun::new(dag)
+                        .with_logical_time(t.clone())
+                        .with_params(self.params.clone())
+                        .without_sla();
+                    let report = run.run(Arc::clone(&trigger_caller), true);
+                    reports.lock().unwrap().push((i, t, report));
+                });
+            }
+        });
+        let mut reports = reports.into_inner().unwrap();
+        reports.sort_by_key(|(i, ..)| *i);
+        Ok(reports.into_iter().map(|(_, t, r)| (t, r)).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::*;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+
+    fn day(d: u32) -> ScheduleTime {
+        format!("2024-01-0{}T00:00:00Z", d).parse().unwrap()
+    }
+
+    #[test]
+    fn backfill_logical_times() {
+        // The runs are active from the first poke of their sensor until it is done, the
+        // sensors are rescheduled so they do not hold the executor
+        let (open, peak) = (
+            Arc::new(Mutex::new(HashMap::new())),
+            Arc::new(AtomicUsize::new(0)),
+        );
+        let done = Arc::new(Mutex::new(vec![]));
+        let sensor = {
+            let (open, peak, done) = (Arc::clone(&open), Arc::clone(&peak), Arc::clone(&done));
+            Sensor::new(move |ctx| {
+                let mut open = open.lock().unwrap();
+                let first = *open
+                    .entry(ctx.run_id().to_string())
+                    .or_insert_with(Instant::now);
+                peak.fetch_max(open.len(), Ordering::SeqCst);
+                if first.elapsed() < Duration::from_millis(80) {
+                    return Ok(false);
+                }
+                open.remove(ctx.run_id());
+                let source = ctx.param::<String>("source")?;
+                done.lock()
+                    .unwrap()
+                    .push((ctx.logical_time().unwrap().clone(), source));
+                Ok(true)
+            })
+            .interval(Duration::from_millis(10))
+            .mode(SensorMode::Reschedule)
+        };
+        let mut d = DAG::from(sensor);
+        d.set_schedule("0 0 0 * * *".try_into().unwrap());
+        // All logical times are long past, the SLA is not checked for backfill runs
+        let missed = Arc::new(AtomicUsize::new(0));
+        let m = Arc::clone(&missed);
+        d.set_sla(Sla::Within(Duration::from_millis(1)));
+        d.set_on_sla_miss(move |_, _| {
+            m.fetch_add(1, Ordering::SeqCst);
+        });
+        d.prepare();
+
+        let backfill = Backfill::new(day(1), day(5))
+            .max_active_runs(2)
+            .reverse(true)
+            .params(serde_json::json!({"source": "s3"}));
+        assert_eq!(
+            vec![day(5), day(4), day(3), day(2), day(1)],
+            backfill.logical_times(&d).unwrap().collect::<Vec<_>>()
+        );
+        let reports = backfill
+            .run(&d, Arc::new(Mutex::new(DefaultExecutor::new())))
+            .unwrap();
+        assert_eq!(5, reports.len());
+        assert!(reports.iter().all(|(_, r)| r.is_success()));
+        assert_eq!(day(5), reports[0].0);
+        assert_eq!(2, peak.load(Ordering::SeqCst));
+        assert_eq!(0, missed.load(Ordering::SeqCst));
+        let mut done = done.lock().unwrap().clone();
+        done.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        assert_eq!(
+            (1..=5)
+                .map(|d| (day(d), "s3".to_string()))
+                .collect::<Vec<_>>(),
+            done
+        );
+
+        let unscheduled = DAG::from(|| {});
+        assert!(backfill.logical_times(&unscheduled).is_err());
+        let backwards = Backfill::new(day(5), day(1));
+        assert!(backwards
+            .run(&d, Arc::new(Mutex::new(DefaultExecutor::new())))
+            .is_err());
+    }
+}
@@ -1,14 +1,20 @@
+use crate::runtime::context::RunContext;
+use crate::runtime::retry::RetryPolicy;
+use crate::runtime::sla::Sla;
 use crate::runtime::RunnableMetadata;
-use crate::task::dag::{DepTaskNode, TaskNode, DAG};
+use crate::task::dag::{DepTaskNode, SubDAG, TaskNode, DAG};
+use crate::task::state::{TriggerRule, WeightRule};
 use crate::task::TryIntoTask;
-use bronzeflow_utils::Result;
+use bronzeflow_utils::{BronzeError, Result};
 use std::borrow::BorrowMut;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 #[derive(Default)]
 pub struct DAGBuilder {
     curr_node: Option<DepTaskNode>,
     roots: Vec<DepTaskNode>,
+    require_names: bool,
 }
 
 impl DAGBuilder {
@@ -16,9 +22,16 @@ impl DAGBuilder {
         DAGBuilder {
             curr_node: None,
             roots: vec![],
+            require_names: false,
         }
     }
 
+    /// Reject the DAG in [`DAGBuilder::build`] if any task has no name
+    pub fn require_names(mut self, require_names: bool) -> Self {
+        self.require_names = require_names;
+        self
+    }
+
     /// Add a task to DAG as dag node
     ///
     pub fn task<M, T>(mut self, meta: M, task: T) -> Self
@@ -53,6 +66,131 @@ impl DAGBuilder {
         self
     }
 
+    /// Override the metadata of current task with the fields which are set in `meta`
+    pub fn set_meta<M: Into<RunnableMetadata>>(self, meta: M) -> Self {
+        if let Some(ref node) = self.curr_node {
+            let mut node = node.as_ref().lock().unwrap();
+            let meta = meta.into();
+            match node.meta {
+                Some(ref mut m) => {
+                    m.merge(meta);
+                },
+                None => node.meta = Some(meta),
+            }
+        }
+        self
+    }
+
+    /// Add a mapped task, which runs once for every item in the output list of task `over`
+    ///
+    /// Task `over` must be a parent of the mapped task. Every instance reads its item by
+    /// [`RunContext::map_item`], the outputs of all instances are collected into a list as the
+    /// output of the mapped task.
+    ///
+    /// [`RunContext::map_item`]: crate::runtime::context::RunContext::map_item
+    pub fn map<M, T>(self, meta: M, over: &str, task: T) -> Self
+    where
+        M: Into<RunnableMetadata>,
+        T: TryIntoTask,
+    {
+        let builder = self.task(meta, task);
+        if let Some(ref node) = builder.curr_node {
+            node.as_ref().lock().unwrap().map_over = Some(over.to_string());
+        }
+        builder
+    }
+
+    /// Add a built DAG as one task, the task finishes when all tasks of the DAG have finished
+    ///
+    /// The tasks of the sub-DAG are not visible to the other tasks, they could use the same
+    /// names. The output of the task is an object of the outputs of the sub-DAG's tasks.
+    pub fn sub_dag<M: Into<RunnableMetadata>>(self, meta: M, dag: DAG) -> Self {
+        let builder = self.task(meta, SubDAG(dag.clone()));
+        if let Some(ref node) = builder.curr_node {
+            node.as_ref().lock().unwrap().sub_dag = Some(dag);
+        }
+        builder
+    }
+
+    /// Set the retry policy of current task
+    pub fn retry(self, retry: RetryPolicy) -> Self {
+        self.set_meta(RunnableMetadata::default().with_retry(retry))
+    }
+
+    /// Set the timeout of every attempt of current task
+    pub fn timeout(self, timeout: Duration) -> Self {
+        self.set_meta(RunnableMetadata::default().with_timeout(timeout))
+    }
+
+    /// Run current task in resource pool `name`, it takes `slots` slots of the pool
+    pub fn pool(self, name: &str, slots: u32) -> Self {
+        self.set_meta(RunnableMetadata::default().with_pool(name, slots))
+    }
+
+    /// Set the priority weight of current task, the task with the higher priority gets the
+    /// pool slots and the slots of the maximum parallelism first
+    pub fn priority_weight(self, weight: i64) -> Self {
+        self.set_meta(RunnableMetadata::default().with_priority_weight(weight))
+    }
+
+    /// Set how the effective priority of current task is computed
+    pub fn weight_rule(self, rule: WeightRule) -> Self {
+        self.set_meta(RunnableMetadata::default().with_weight_rule(rule))
+    }
+
+    /// Called when current task succeeded
+    pub fn on_success<F>(self, f: F) -> Self
+    where
+        F: Fn(&RunContext, Option<&BronzeError>) + Send + Sync + 'static,
+    {
+        self.set_meta(RunnableMetadata::default().with_on_success(f))
+    }
+
+    /// Called when the last attempt of current task failed or timed out, with the error
+    pub fn on_failure<F>(self, f: F) -> Self
+    where
+        F: Fn(&RunContext, Option<&BronzeError>) + Send + Sync + 'static,
+    {
+        self.set_meta(RunnableMetadata::default().with_on_failure(f))
+    }
+
+    /// Called when a failed attempt is retried, with the error
+    pub fn on_retry<F>(self, f: F) -> Self
+    where
+        F: Fn(&RunContext, Option<&BronzeError>) + Send + Sync + 'static,
+    {
+        self.set_meta(RunnableMetadata::default().with_on_retry(f))
+    }
+
+    /// Called when current task is skipped
+    pub fn on_skip<F>(self, f: F) -> Self
+    where
+        F: Fn(&RunContext, Option<&BronzeError>) + Send + Sync + 'static,
+    {
+        self.set_meta(RunnableMetadata::default().with_on_skip(f))
+    }
+
+    /// Set the deadline of current task
+    pub fn sla(self, sla: Sla) -> Self {
+        self.set_meta(RunnableMetadata::default().with_sla(sla))
+    }
+
+    /// Called when current task has not finished by its SLA deadline
+    pub fn on_sla_miss<F>(self, f: F) -> Self
+    where
+        F: Fn(&RunContext, Option<&BronzeError>) + Send + Sync + 'static,
+    {
+        self.set_meta(RunnableMetadata::default().with_on_sla_miss(f))
+    }
+
+    /// Set when current task runs by the states of its parents
+    pub fn trigger_rule(self, rule: TriggerRule) -> Self {
+        if let Some(ref node) = self.curr_node {
+            node.as_ref().lock().unwrap().trigger_rule = rule;
+        }
+        self
+    }
+
     /// Add a parent task to current task
     pub fn parent<F>(mut self, mut bf: F) -> Self
     where
@@ -100,14 +238,12 @@ impl DAGBuilder {
         self.roots
     }
 
+    /// Build the DAG, return a [`DAGError`] if the DAG is invalid
+    ///
+    /// [`DAGError`]: crate::task::validate::DAGError
     pub fn build(self) -> Result<DAG> {
-        let vec = self.build_vec();
-        let mut roots = vec![];
-        let mut f = |node: DepTaskNode| {
-            roots.push(node);
-        };
-        DAG::handle_top_node(&vec, &mut f);
-        Ok(DAG::new(roots))
+        let require_names = self.require_names;
+        DAG::validated(self.build_vec(), require_names)
     }
 }
 
@@ -139,7 +275,7 @@ macro_rules! dag {
             let mut builder = $crate::task::builder::DAGBuilder::new();
             $(
                 let task = dag!{$task};
-                let tmp_builder = $crate::task::builder::DAGBuilder::from(task).set_name($task_name);
+                let tmp_builder = $crate::task::builder::DAGBuilder::from(task).set_meta($task_name);
                 builder = builder.merge(tmp_builder);
             )+
             builder
@@ -243,4 +379,26 @@ mod tests {
         .unwrap();
         d.print_tree();
     }
+
+    #[test]
+    fn print_sub_dag() {
+        let sub = dag!("Extract" => || {} => dag!("Fetch" => || {}))
+            .build()
+            .unwrap();
+        let d = DAGBuilder::new()
+            .task("Start", || {})
+            .child(|bd| {
+                bd.sub_dag("Ingest", sub.clone())
+                    .child(|bd2| bd2.task("Report", || {}))
+            })
+            .build()
+            .unwrap();
+
+        assert_eq!("Start\n  Ingest [...]\n    Report\n", d.tree(false));
+        assert_eq!(
+            "Start\n  Ingest [\n    Fetch\n      Extract\n  ]\n    Report\n",
+            d.tree(true)
+        );
+        d.print_expanded_tree();
+    }
 }
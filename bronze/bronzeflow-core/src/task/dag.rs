@@ -1,27 +1,42 @@
 use crate::prelude::{RuntimeJoinHandle, SyncFn};
-use crate::runtime::{
-    BuildFromRunnable, Runnable, RunnableMetadata, RunnableMetadataBuilder, SafeMetadata,
-};
+use crate::runtime::context::RunContext;
+use crate::runtime::limit::{OverlapPolicy, ParallelismPolicy};
+use crate::runtime::sla::Sla;
+use crate::runtime::{BuildFromRunnable, Runnable, RunnableMetadata, SafeMetadata};
+use crate::task::state::TriggerRule;
 use crate::task::{TaskInfo, TryIntoTask, WrappedTask};
 use bronzeflow_time::prelude::ScheduleExpr;
 use bronzeflow_time::schedule_time::ScheduleTimeHolder;
 use bronzeflow_utils::{BronzeError, Result};
-use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard};
 
 pub type DepTaskNode = Arc<Mutex<TaskNode>>;
 pub type TimeHoldType = Arc<Mutex<ScheduleTimeHolder>>;
 
+/// All nodes of a DAG flattened into one list, the dependencies are stored as indexes of the list
+pub(crate) struct DAGIndex {
+    pub(crate) nodes: Vec<DepTaskNode>,
+    pub(crate) parents: Vec<Vec<usize>>,
+    pub(crate) children: Vec<Vec<usize>>,
+}
+
 // #[derive(Debug)]
 pub struct TaskNode {
     pub(crate) task: TaskInfo,
     pub(crate) meta: Option<RunnableMetadata>,
+    pub(crate) trigger_rule: TriggerRule,
+    /// The name of the upstream task whose output is mapped over
+    pub(crate) map_over: Option<String>,
+    /// The DAG which is run as this task
+    pub(crate) sub_dag: Option<DAG>,
     pub(crate) parents: Vec<DepTaskNode>,
     pub(crate) children: Vec<DepTaskNode>,
 }
 
 #[derive(Clone)]
 pub struct DAG {
-    // name: Option<String>,
+    name: Option<String>,
     root_tasks: Vec<DepTaskNode>,
     schedule: Option<ScheduleExpr>,
     pub(crate) meta: Option<SafeMetadata>,
@@ -34,6 +49,21 @@ impl<T: TryIntoTask> From<T> for DAG {
     }
 }
 
+/// Run a DAG as one task of another DAG
+///
+/// A [`DAGRun`] runs the sub-DAG with its own executor calls, this runnable is only used when
+/// the parent DAG runs in the current thread.
+///
+/// [`DAGRun`]: crate::task::dag_run::DAGRun
+pub(crate) struct SubDAG(pub(crate) DAG);
+
+impl Runnable for SubDAG {
+    fn run_async(&self) -> Self::Handle {
+        self.0.clone().run();
+        RuntimeJoinHandle::SyncJobHandle
+    }
+}
+
 impl BuildFromRunnable for DAG {
     type Type = DAG;
     fn build_from(runnable: impl Runnable<Handle = RuntimeJoinHandle<()>> + Send + 'static) -> DAG {
@@ -48,13 +78,16 @@ impl TaskNode {
         TaskNode {
             task,
             meta: Some(RunnableMetadata::default()),
+            trigger_rule: TriggerRule::default(),
+            map_over: None,
+            sub_dag: None,
             parents: vec![],
             children: vec![],
         }
     }
 
     pub fn run(&mut self) {
-        self.task.0.as_ref().lock().unwrap().0.run();
+        self.task.run();
     }
 
     pub fn with_meta<T>(meta: T, task: TaskInfo) -> Self
@@ -64,6 +97,9 @@ impl TaskNode {
         TaskNode {
             task,
             meta: Some(meta.into()),
+            trigger_rule: TriggerRule::default(),
+            map_over: None,
+            sub_dag: None,
             parents: vec![],
             children: vec![],
         }
@@ -72,20 +108,161 @@ impl TaskNode {
 
 unsafe impl Send for TaskNode {}
 
+impl DAGIndex {
+    /// Indexes of the nodes which have no parent
+    pub(crate) fn roots(&self) -> Vec<usize> {
+        (0..self.nodes.len())
+            .filter(|i| self.parents[*i].is_empty())
+            .collect()
+    }
+
+    /// Sort the nodes topologically, nodes which are in a cycle are left out
+    pub(crate) fn topological_order(&self) -> Vec<usize> {
+        let mut pending: Vec<usize> = self.parents.iter().map(Vec::len).collect();
+        let mut queue: VecDeque<usize> = self.roots().into();
+        let mut order = Vec::with_capacity(self.nodes.len());
+        while let Some(i) = queue.pop_front() {
+            order.push(i);
+            for c in &self.children[i] {
+                pending[*c] -= 1;
+                if pending[*c] == 0 {
+                    queue.push_back(*c);
+                }
+            }
+        }
+        order
+    }
+}
+
 impl DAG {
     pub fn new(root_tasks: Vec<DepTaskNode>) -> Self {
         DAG {
             root_tasks,
             schedule: None,
-            // name: None,
+            name: None,
             meta: None,
         }
     }
 
+    /// Create a DAG which contains `nodes` and validate it, only the root tasks are kept
+    pub(crate) fn validated(nodes: Vec<DepTaskNode>, require_names: bool) -> Result<DAG> {
+        let index = DAG::new(nodes).index();
+        index.validate(require_names)?;
+        let roots = index
+            .roots()
+            .into_iter()
+            .map(|i| index.nodes[i].clone())
+            .collect();
+        Ok(DAG::new(roots))
+    }
+
+    pub fn set_name(&mut self, name: &str) {
+        self.name = Some(name.to_string());
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Stop scheduling the DAG after it has run `maximum_run_times` times
+    pub fn set_maximum_run_times(&mut self, maximum_run_times: u64) -> &mut Self {
+        self.metadata().set_maximum_run_times(maximum_run_times);
+        self
+    }
+
+    /// Allow at most `maximum_parallelism` runs of the DAG in progress at the same time, the
+    /// other runs wait or are skipped according to `policy`
+    pub fn set_maximum_parallelism(
+        &mut self,
+        maximum_parallelism: u32,
+        policy: ParallelismPolicy,
+    ) -> &mut Self {
+        self.metadata()
+            .set_maximum_parallelism(maximum_parallelism)
+            .set_parallelism_policy(policy);
+        self
+    }
+
+    /// What to do when the DAG is triggered while its previous runs are active, it only
+    /// applies to a DAG submitted to a session
+    pub fn set_overlap_policy(&mut self, policy: OverlapPolicy) -> &mut Self {
+        self.metadata().set_overlap_policy(policy);
+        self
+    }
+
+    /// The priority weight of the DAG, it is added to the priorities of all its tasks
+    pub fn set_priority_weight(&mut self, weight: i64) -> &mut Self {
+        self.metadata().set_priority_weight(weight);
+        self
+    }
+
+    /// Called when no task of the run failed
+    pub fn set_on_success<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&RunContext, Option<&BronzeError>) + Send + Sync + 'static,
+    {
+        self.metadata().set_on_success(f);
+        self
+    }
+
+    /// Called when a task of the run failed, with the error
+    pub fn set_on_failure<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&RunContext, Option<&BronzeError>) + Send + Sync + 'static,
+    {
+        self.metadata().set_on_failure(f);
+        self
+    }
+
+    /// Called when the run is skipped as a whole
+    pub fn set_on_skip<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&RunContext, Option<&BronzeError>) + Send + Sync + 'static,
+    {
+        self.metadata().set_on_skip(f);
+        self
+    }
+
+    /// The deadline of every run of the DAG
+    pub fn set_sla(&mut self, sla: Sla) -> &mut Self {
+        self.metadata().set_sla(sla);
+        self
+    }
+
+    /// Called when a run has not finished by its SLA deadline
+    pub fn set_on_sla_miss<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&RunContext, Option<&BronzeError>) + Send + Sync + 'static,
+    {
+        self.metadata().set_on_sla_miss(f);
+        self
+    }
+
+    /// The metadata of the DAG itself, it is created when first used
+    fn metadata(&mut self) -> MutexGuard<'_, RunnableMetadata> {
+        self.meta
+            .get_or_insert_with(Default::default)
+            .lock()
+            .unwrap()
+    }
+
     pub fn set_schedule(&mut self, schedule: ScheduleExpr) {
         self.schedule = Some(schedule);
     }
 
+    pub(crate) fn has_schedule(&self) -> bool {
+        self.schedule.is_some()
+    }
+
+    /// The schedule of the DAG, before or after it is prepared
+    pub(crate) fn schedule_expr(&self) -> Option<ScheduleExpr> {
+        if let Some(ref schedule) = self.schedule {
+            return Some(schedule.clone());
+        }
+        let meta = self.meta.as_ref()?.lock().unwrap();
+        meta.schedule.as_ref().map(|s| s.expr().clone())
+    }
+
     pub fn handle_top_node<F>(nodes: &Vec<DepTaskNode>, f: &mut F)
     where
         F: FnMut(DepTaskNode),
@@ -103,58 +280,115 @@ impl DAG {
     pub fn prepare(&mut self) {
         let mut time_holder = ScheduleTimeHolder::new(self.schedule.take().unwrap());
         time_holder.init();
-        self.meta = Some(Arc::new(Mutex::new(
-            RunnableMetadataBuilder::default()
-                .id(None)
-                .name(None)
-                .maximum_run_times(None)
-                .maximum_parallelism(None)
-                .schedule(Some(time_holder))
-                .build()
-                .unwrap(),
-        )));
+        self.prepare_with(time_holder);
+    }
+
+    /// Prepare the DAG with a schedule which is already initialized
+    pub(crate) fn prepare_with(&mut self, time_holder: ScheduleTimeHolder) {
+        let name = self.name.clone();
+        let mut meta = self.metadata();
+        meta.name = name.or(meta.name.take());
+        meta.set_schedule(time_holder);
     }
 
+    /// Run all tasks one by one in the current thread, parents always run before their children
     pub fn run(&mut self) {
-        self.for_all_task(|t| {
-            t.as_ref().lock().unwrap().run();
-        })
+        let index = self.index();
+        for i in index.topological_order() {
+            index.nodes[i].as_ref().lock().unwrap().run();
+        }
     }
 
+    /// Call `f` on every task of the DAG exactly once
     pub fn for_all_task<F>(&self, f: F)
     where
         F: Fn(DepTaskNode),
     {
-        for t in &self.root_tasks {
-            DAG::handle(false, t.clone(), |task| {
-                // task.as_ref().lock().unwrap().run();
-                f(task)
-            })
+        for t in self.index().nodes {
+            f(t)
+        }
+    }
+
+    /// Collect every node which is connected to the root tasks, through parents or children
+    pub(crate) fn index(&self) -> DAGIndex {
+        let mut nodes: Vec<DepTaskNode> = vec![];
+        let mut links: Vec<(Vec<DepTaskNode>, Vec<DepTaskNode>)> = vec![];
+        let mut positions: HashMap<*const Mutex<TaskNode>, usize> = HashMap::new();
+
+        let mut queue: VecDeque<DepTaskNode> = self.root_tasks.iter().cloned().collect();
+        while let Some(node) = queue.pop_front() {
+            if positions.contains_key(&Arc::as_ptr(&node)) {
+                continue;
+            }
+            positions.insert(Arc::as_ptr(&node), nodes.len());
+            let (parents, children) = {
+                let task = node.as_ref().lock().unwrap();
+                (task.parents.clone(), task.children.clone())
+            };
+            queue.extend(parents.iter().cloned());
+            queue.extend(children.iter().cloned());
+            links.push((parents, children));
+            nodes.push(node);
+        }
+
+        let to_index = |list: &Vec<DepTaskNode>| -> Vec<usize> {
+            list.iter().map(|n| positions[&Arc::as_ptr(n)]).collect()
+        };
+        let (parents, children) = links
+            .iter()
+            .map(|(p, c)| (to_index(p), to_index(c)))
+            .unzip();
+        DAGIndex {
+            nodes,
+            parents,
+            children,
         }
     }
 
+    /// Print the tree of the tasks, a sub-DAG is shown as one task
     pub fn print_tree(&self) {
+        println!("{}", self.tree(false));
+    }
+
+    /// Print the tree of the tasks, the tasks of a sub-DAG are shown under it
+    pub fn print_expanded_tree(&self) {
+        println!("{}", self.tree(true));
+    }
+
+    /// The tree of the tasks as text, a collapsed sub-DAG is shown as `name [...]`, an
+    /// expanded one lists its tasks between `name [` and `]`
+    pub fn tree(&self, expand: bool) -> String {
         let mut s = vec![];
+        self.write_tree(&mut s, 0, expand);
+        s.join("")
+    }
+
+    fn write_tree(&self, s: &mut Vec<String>, indent: usize, expand: bool) {
         let f = |node: DepTaskNode, level| {
-            for _ in 0..level {
-                s.push(String::from("  "));
-            }
+            let node = node.as_ref().lock().unwrap();
+            let prefix = "  ".repeat(indent + level);
+            s.push(prefix.clone());
             s.push(
-                node.as_ref()
-                    .lock()
-                    .unwrap()
-                    .meta
+                node.meta
                     .as_ref()
                     .unwrap()
                     .name
                     .as_ref()
                     .map_or_else(|| "".to_string(), |r| r.to_string()),
             );
+            match node.sub_dag {
+                Some(ref sub) if expand => {
+                    s.push(" [\n".to_string());
+                    sub.write_tree(s, indent + level + 1, expand);
+                    s.push(prefix);
+                    s.push("]".to_string());
+                },
+                Some(_) => s.push(" [...]".to_string()),
+                None => {},
+            }
             s.push("\n".to_string());
         };
         self.handle_with_level(f);
-        let tree_str = s.join("");
-        println!("{}", tree_str);
     }
 
     // TODO
@@ -203,10 +437,7 @@ impl DAG {
     }
 
     pub fn cal_task_nums(&self) -> usize {
-        // TODO use better method
-        let mut v = vec![];
-        self.handle_with_level(|t, _| v.push(t));
-        v.len()
+        self.index().nodes.len()
     }
 
     pub fn to_single_task(mut self) -> Result<WrappedTask> {
@@ -0,0 +1,2382 @@
+// This is a part of bronze.
+
+//! DAGRun, run all tasks of a DAG according to their dependencies
+//!
+//! A task is submitted to the executor only when all its parents have finished, the tasks
+//! which do not depend on each other are submitted together, so they could run in parallel
+//! if the executor supports it.
+//!
+//! The state of every task is recorded, by default a failed task stops its downstream tasks
+//! from running, the [`TriggerRule`] of a task could change it. A branch task chooses the
+//! children to follow, the other children and the tasks which only depend on them are skipped.
+//! A mapped task runs once for every item in the output of an upstream task, its instances are
+//! recorded separately. A sub-DAG task runs all tasks of its DAG in a nested run, the states of
+//! these tasks are reported with the name of the sub-DAG task as prefix, like `ingest.extract`.
+//! A task with a [`RetryPolicy`] is submitted again after it failed, until it succeeds or the
+//! policy gives up. A task which runs longer than its timeout is marked as timed out, it is
+//! not retried. A task in a resource pool waits until enough slots of the pool are free, it is
+//! woken when slots are given back, the waiting tasks get the slots by their priorities and
+//! the ready tasks are also submitted by their priorities, the same goes for the slots of
+//! their maximum parallelism. A task which reschedules itself, like a sensor in reschedule
+//! mode, gives its slot back and is submitted again after the delay it asked. A cancelled run submits no more tasks, the tasks which have not started are
+//! skipped.
+//!
+//! [`RetryPolicy`]: crate::runtime::retry::RetryPolicy
+
+use crate::runtime::callback::{Callback, CallbackRunner, Callbacks};
+use crate::runtime::context::{CancellationToken, RunContext, RunInfo, TaskOutputs};
+use crate::runtime::history::{RunHistory, RunState};
+use crate::runtime::limit::{
+    ActiveRuns, Admission, OverlapPolicy, ParallelismPolicy, RunSlot, RunSlots, SlotAdmission,
+    SlotTicket, Waker,
+};
+use crate::runtime::pool::{PoolSlot, PoolTicket, ResourcePools};
+use crate::runtime::sla::{Sla, SlaMiss};
+use crate::runtime::{Runnable, RunnableMetadata, RuntimeJoinHandle};
+use crate::task::dag::{DAGIndex, DAG};
+use crate::task::state::{DAGRunReport, TaskState, TriggerRule, WeightRule};
+use crate::task::TaskInfo;
+use crate::trigger::{TriggerCaller, TriggerCallerType};
+use bronzeflow_time::schedule_time::{ScheduleTime, ScheduleTimeOp};
+use bronzeflow_utils::{ayn_error, debug, info, warn, BronzeError, Result};
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use std::any::TypeId;
+use std::cmp::Reverse;
+use std::collections::{HashMap, VecDeque};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, Builder as StdThreadBuilder, JoinHandle};
+use std::time::{Duration, Instant};
+
+pub struct DAGRun {
+    index: DAGIndex,
+    outputs: TaskOutputs,
+    configs: Vec<NodeConfig>,
+    info: Arc<RunInfo>,
+    limit: Option<RunLimit>,
+    overlap: Option<RunOverlap>,
+    pools: ResourcePools,
+    /// The effective priorities of the nodes
+    priorities: Vec<i64>,
+    /// Where the run is recorded, a sub-DAG run is not recorded by itself
+    history: Option<RunHistory>,
+    /// The callbacks of the DAG
+    callbacks: Callbacks,
+    /// The deadline of the run
+    sla: Option<Sla>,
+}
+
+/// The overlap policy of the DAG, with the active runs of all DAGs
+struct RunOverlap {
+    id: u64,
+    policy: OverlapPolicy,
+    runs: ActiveRuns,
+}
+
+/// The maximum parallelism of the DAG
+struct RunLimit {
+    max: u32,
+    policy: ParallelismPolicy,
+    priority: i64,
+    slots: RunSlots,
+}
+
+/// The settings of a node, read when the run is created
+struct NodeConfig {
+    meta: RunnableMetadata,
+    trigger_rule: TriggerRule,
+    map_over: Option<String>,
+    sub_dag: Option<DAG>,
+}
+
+/// A task to run, the index of the node and the index of the instance if the node is mapped
+type TaskKey = (usize, Option<usize>);
+
+/// When to check an SLA deadline, the node it belongs to, `None` for the run, and the deadline
+type SlaDeadline = (Instant, Option<usize>, DateTime<Utc>);
+
+/// The final states of the nodes, of the instances of mapped nodes, and the reports of sub-DAGs
+type NodeResults = (
+    Vec<TaskState>,
+    Vec<Vec<TaskState>>,
+    Vec<Option<DAGRunReport>>,
+);
+
+/// The slots taken by an attempt of a task
+type HeldSlots = (Option<PoolSlot>, Option<RunSlot>);
+
+/// The result of one attempt of a task
+enum AttemptResult {
+    /// With the children chosen by a branch task
+    Success(Option<Vec<String>>),
+    Failed(Arc<BronzeError>),
+    /// With where to keep the slots of a blocking task, which is still running in its thread
+    TimedOut(Option<Sender<HeldSlots>>),
+    /// A sub-DAG has finished
+    SubDAG(DAGRunReport),
+    /// The task asked to be submitted again after the delay
+    Rescheduled(Duration),
+    /// The maximum parallelism of the task was reached
+    Skipped,
+}
+
+/// A message to the loop of a run
+enum RunEvent {
+    /// An attempt of a task has finished
+    Finished(TaskKey, AttemptResult),
+    /// Slots were given back, the tasks waiting for them could try again
+    Freed,
+}
+
+impl RunEvent {
+    /// Send [`RunEvent::Freed`] to the run when it is called
+    fn waker(sender: &Sender<RunEvent>) -> Waker {
+        let sender = sender.clone();
+        Box::new(move || {
+            sender.send(RunEvent::Freed).ok();
+        })
+    }
+}
+
+/// The progress of a run, seen by the thread which watches its SLA deadlines
+struct SlaWatch {
+    progress: Mutex<SlaProgress>,
+    changed: Condvar,
+}
+
+struct SlaProgress {
+    /// The last attempt of every node, 0 if it has not started
+    attempts: Vec<u32>,
+    finished: Vec<bool>,
+    run_finished: bool,
+}
+
+impl SlaWatch {
+    fn new(n: usize) -> Self {
+        SlaWatch {
+            progress: Mutex::new(SlaProgress {
+                attempts: vec![0; n],
+                finished: vec![false; n],
+                run_finished: false,
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    fn start_attempt(&self, i: usize, attempt: u32) {
+        self.progress.lock().unwrap().attempts[i] = attempt;
+    }
+
+    /// Node `i` has finished, or the run if it is `None`
+    fn finish(&self, i: Option<usize>) {
+        let mut progress = self.progress.lock().unwrap();
+        match i {
+            Some(i) => progress.finished[i] = true,
+            None => progress.run_finished = true,
+        }
+        self.changed.notify_all();
+    }
+}
+
+impl DAGRun {
+    pub fn new(dag: &DAG) -> Self {
+        let index = dag.index();
+        let configs: Vec<_> = index
+            .nodes
+            .iter()
+            .map(|n| {
+                let node = n.lock().unwrap();
+                NodeConfig {
+                    meta: node.meta.clone().unwrap_or_default(),
+                    trigger_rule: node.trigger_rule,
+                    map_over: node.map_over.clone(),
+                    sub_dag: node.sub_dag.clone(),
+                }
+            })
+            .collect();
+        let mut info = RunInfo {
+            dag_name: dag.name().map(str::to_string),
+            run_id: RunInfo::new_run_id(),
+            ..RunInfo::default()
+        };
+        let (mut limit, mut overlap, mut pools) = (None, None, ResourcePools::new());
+        let (mut callbacks, mut sla) = (Callbacks::default(), None);
+        let mut dag_weight = 0;
+        if let Some(ref meta) = dag.meta {
+            let meta = meta.lock().unwrap();
+            info.dag_id = meta.id;
+            info.dag_name = meta.name.clone().or(info.dag_name);
+            info.logical_time = meta.schedule.as_ref().and_then(|s| s.last_run());
+            limit = meta.maximum_parallelism.map(|max| RunLimit {
+                max,
+                policy: meta.parallelism_policy.unwrap_or_default(),
+                priority: meta.priority_weight.unwrap_or(0),
+                slots: meta.slots.clone(),
+            });
+            pools = meta.pools.clone().unwrap_or(pools);
+            dag_weight = meta.priority_weight.unwrap_or(0);
+            info.history = meta.history.clone();
+            callbacks = meta.callbacks.clone();
+            sla = meta.sla;
+            if let (Some(id), Some(runs)) = (meta.id, meta.active_runs.clone()) {
+                overlap = Some(RunOverlap {
+                    id,
+                    policy: meta.overlap_policy.unwrap_or_default(),
+                    runs,
+                });
+            }
+        }
+        let priorities = DAGRun::priorities(&index, &configs, dag_weight);
+        let history = info.history.clone();
+        DAGRun {
+            index,
+            outputs: TaskOutputs::new(),
+            configs,
+            info: Arc::new(info),
+            limit,
+            overlap,
+            pools,
+            priorities,
+            history,
+            callbacks,
+            sla,
+        }
+    }
+
+    /// The effective priority of every node, the weight of a task is 1 by default
+    fn priorities(index: &DAGIndex, configs: &[NodeConfig], dag_weight: i64) -> Vec<i64> {
+        let weight = |i: usize| configs[i].meta.priority_weight.unwrap_or(1);
+        (0..index.nodes.len())
+            .map(|i| {
+                let own = match configs[i].meta.weight_rule.unwrap_or_default() {
+                    WeightRule::Absolute => weight(i),
+                    WeightRule::Downstream => {
+                        let mut seen = vec![false; index.nodes.len()];
+                        let mut stack = vec![i];
+                        let mut sum = 0;
+                        while let Some(n) = stack.pop() {
+                            if !std::mem::replace(&mut seen[n], true) {
+                                sum += weight(n);
+                                stack.extend(&index.children[n]);
+                            }
+                        }
+                        sum
+                    },
+                };
+                dag_weight + own
+            })
+            .collect()
+    }
+
+    /// Use the resource pools in `pools`, the pools of the manager are used by default
+    pub fn with_pools(mut self, pools: ResourcePools) -> Self {
+        self.pools = pools;
+        self
+    }
+
+    /// Record the run in `history`, the history of the manager is used by default
+    pub fn with_history(mut self, history: RunHistory) -> Self {
+        Arc::make_mut(&mut self.info).history = Some(history.clone());
+        self.history = Some(history);
+        self
+    }
+
+    /// Set the parameters of the run, they are read by [`RunContext::params`]
+    pub fn with_params(mut self, params: Value) -> Self {
+        Arc::make_mut(&mut self.info).params = params;
+        self
+    }
+
+    /// Run for `logical_time` instead of the last schedule time of the DAG
+    pub fn with_logical_time(mut self, logical_time: ScheduleTime) -> Self {
+        Arc::make_mut(&mut self.info).logical_time = Some(logical_time);
+        self
+    }
+
+    /// Do not check the SLAs of the DAG and its tasks, like in a run for a past logical time
+    pub fn without_sla(mut self) -> Self {
+        self.sla = None;
+        for config in &mut self.configs {
+            config.meta.sla = None;
+        }
+        self
+    }
+
+    /// Mark the run as triggered by hand, it has no logical time unless one is set with
+    /// [`DAGRun::with_logical_time`] after it
+    pub fn manual(mut self) -> Self {
+        let info = Arc::make_mut(&mut self.info);
+        info.manual = true;
+        info.logical_time = None;
+        self
+    }
+
+    pub fn run_id(&self) -> &str {
+        &self.info.run_id
+    }
+
+    /// Cancel the run with the token, the tasks which have not started are skipped
+    pub fn cancel_token(&self) -> CancellationToken {
+        self.info.cancel.clone()
+    }
+
+    /// The outputs of the tasks in this run
+    pub fn outputs(&self) -> TaskOutputs {
+        self.outputs.clone()
+    }
+
+    /// Run the DAG in a new thread
+    ///
+    /// If the previous runs of the DAG are active, the run starts, waits or is skipped as a
+    /// whole according to the [`OverlapPolicy`] of the DAG. The maximum parallelism of the DAG
+    /// applies like in [`run`](Self::run).
+    pub fn start<TC>(
+        self,
+        trigger_caller: TriggerCallerType<TC>,
+        report_msg: bool,
+    ) -> JoinHandle<DAGRunReport>
+    where
+        TC: TriggerCaller + 'static,
+    {
+        // Decide now, so the runs triggered later could not go first
+        let admission = self.overlap.as_ref().map(|o| {
+            o.runs
+                .admit(o.id, o.policy, &self.info.run_id, &self.info.cancel)
+        });
+        let slot = self.admit_slot();
+        StdThreadBuilder::new()
+            .name("dag_run".into())
+            .spawn(move || {
+                let _active = match admission {
+                    Some(Admission::Skip) => {
+                        info!("Skip run {}, the previous run is active", self.info.run_id);
+                        return self.skipped(&trigger_caller);
+                    },
+                    Some(Admission::Wait(queued)) => Some(queued.wait()),
+                    Some(Admission::Run(active)) => Some(active),
+                    None => None,
+                };
+                self.run_admitted(slot, trigger_caller, report_msg)
+            })
+            .expect("dag_run can't start.")
+    }
+
+    /// Take a slot of the maximum parallelism of the DAG, or a place in its queue
+    fn admit_slot(&self) -> Option<SlotAdmission> {
+        self.limit
+            .as_ref()
+            .map(|l| l.slots.admit(l.max, l.policy, l.priority))
+    }
+
+    /// The report of a run which was skipped as a whole, it is recorded as skipped
+    fn skipped<TC>(&self, trigger_caller: &TriggerCallerType<TC>) -> DAGRunReport
+    where
+        TC: TriggerCaller + 'static,
+    {
+        let tasks = (0..self.index.nodes.len())
+            .map(|i| (self.index.display_name(i), TaskState::Skipped))
+            .collect();
+        let report = DAGRunReport::new(tasks);
+        self.record_start();
+        self.record_finish(RunState::Skipped, &report);
+        let on_skip = &self.callbacks.on_skip;
+        self.call(on_skip, self.run_context(), None, trigger_caller);
+        report
+    }
+
+    /// The SLA deadlines of the run and of the nodes, computed from the logical time of the
+    /// run, or from now if the run was not scheduled
+    fn deadlines(&self) -> Vec<SlaDeadline> {
+        let now = Utc::now();
+        let base = self
+            .info
+            .logical_time
+            .as_ref()
+            .map_or(now, ScheduleTime::datetime);
+        let nodes = self
+            .configs
+            .iter()
+            .enumerate()
+            .filter_map(|(i, c)| c.meta.sla.map(|sla| (Some(i), sla)));
+        self.sla
+            .map(|sla| (None, sla))
+            .into_iter()
+            .chain(nodes)
+            .filter_map(|(node, sla)| {
+                let deadline = sla.deadline(base);
+                let left = (deadline - now).to_std().unwrap_or_default();
+                // A deadline too far away is never checked
+                Some((Instant::now().checked_add(left)?, node, deadline))
+            })
+            .collect()
+    }
+
+    /// Wait for the `deadlines` until the run has finished, report the deadlines which pass
+    /// before their nodes or the run have finished
+    ///
+    /// It runs in its own thread, so a miss is reported in time even if the executor is busy
+    /// with a slow task
+    fn watch_sla(&self, mut deadlines: Vec<SlaDeadline>, watch: &SlaWatch) {
+        deadlines.sort_by_key(|d| d.0);
+        let mut progress = watch.progress.lock().unwrap();
+        for (at, node, deadline) in deadlines {
+            loop {
+                if progress.run_finished {
+                    return;
+                }
+                let now = Instant::now();
+                if now >= at {
+                    break;
+                }
+                progress = watch.changed.wait_timeout(progress, at - now).unwrap().0;
+            }
+            if node.is_none_or(|i| !progress.finished[i]) {
+                let attempt = node.map(|i| progress.attempts[i].max(1));
+                drop(progress);
+                self.miss_sla(node, attempt, deadline);
+                progress = watch.progress.lock().unwrap();
+            }
+        }
+    }
+
+    /// The deadline of the run, or of `node` if it is set, passed before it finished. The
+    /// callback is called in the current thread, it is not submitted to the executor
+    fn miss_sla(&self, node: Option<usize>, attempt: Option<u32>, deadline: DateTime<Utc>) {
+        let task = node.map(|i| self.index.display_name(i));
+        warn!(
+            "Run {} missed the SLA deadline {} of {}",
+            self.info.run_id,
+            deadline,
+            task.as_deref().unwrap_or("the run")
+        );
+        if let Some(ref history) = self.history {
+            history.add_sla_miss(SlaMiss {
+                run_id: self.info.run_id.clone(),
+                task,
+                deadline,
+                detected_at: Utc::now(),
+            });
+        }
+        let (callback, ctx) = match node {
+            Some(i) => (
+                &self.configs[i].meta.callbacks.on_sla_miss,
+                self.context((i, None), attempt.unwrap_or(1), None),
+            ),
+            None => (&self.callbacks.on_sla_miss, self.run_context()),
+        };
+        if let Some(ref callback) = callback {
+            let error = ayn_error!("Missed the SLA deadline {}", deadline);
+            CallbackRunner {
+                callback: Arc::clone(callback),
+                ctx,
+                error: Some(Arc::new(error)),
+            }
+            .run();
+        }
+    }
+
+    /// The context passed to the callbacks of the DAG
+    fn run_context(&self) -> RunContext {
+        RunContext {
+            run: Arc::clone(&self.info),
+            ..RunContext::new(None, self.outputs.clone())
+        }
+    }
+
+    /// Submit `callback` with its arguments to the executor if it is set
+    fn call<TC>(
+        &self,
+        callback: &Option<Callback>,
+        ctx: RunContext,
+        error: Option<Arc<BronzeError>>,
+        trigger_caller: &TriggerCallerType<TC>,
+    ) where
+        TC: TriggerCaller + 'static,
+    {
+        if let Some(ref callback) = callback {
+            let runner = CallbackRunner {
+                callback: Arc::clone(callback),
+                ctx,
+                error,
+            };
+            trigger_caller.lock().unwrap().trigger_safe(runner, false);
+        }
+    }
+
+    /// Call the callback of task `key` for its final `state`, `error` is the error of a
+    /// failed attempt
+    fn notify<TC>(
+        &self,
+        (key, attempt, item): (TaskKey, u32, Option<Value>),
+        state: &TaskState,
+        error: Option<Arc<BronzeError>>,
+        trigger_caller: &TriggerCallerType<TC>,
+    ) where
+        TC: TriggerCaller + 'static,
+    {
+        let callbacks = &self.configs[key.0].meta.callbacks;
+        let (callback, error) = match state {
+            TaskState::Success => (&callbacks.on_success, None),
+            TaskState::Skipped => (&callbacks.on_skip, None),
+            TaskState::Failed(e) => (
+                &callbacks.on_failure,
+                error.or_else(|| Some(Arc::new(BronzeError::msg(e.clone())))),
+            ),
+            TaskState::TimedOut => (
+                &callbacks.on_failure,
+                Some(Arc::new(ayn_error!("The task timed out"))),
+            ),
+            _ => return,
+        };
+        let ctx = self.context(key, attempt, item);
+        self.call(callback, ctx, error, trigger_caller);
+    }
+
+    fn record_start(&self) {
+        if let Some(ref history) = self.history {
+            let info = &self.info;
+            history.start(
+                info.dag_id,
+                info.dag_name.clone(),
+                &info.run_id,
+                info.logical_time.clone(),
+                info.manual,
+            );
+        }
+    }
+
+    fn record_finish(&self, state: RunState, report: &DAGRunReport) {
+        if let Some(ref history) = self.history {
+            history.finish(&self.info.run_id, state, report);
+        }
+    }
+
+    /// Node `i` has finished with `state`
+    fn record_task(&self, i: usize, state: &TaskState) {
+        if let Some(ref history) = self.history {
+            history.set_task(&self.info.run_id, &self.index.display_name(i), state);
+        }
+    }
+
+    /// Run the DAG and block until all tasks have finished
+    ///
+    /// A task runs when all its parents have finished and its [`TriggerRule`] is satisfied,
+    /// otherwise it is marked as [`TaskState::UpstreamFailed`] or [`TaskState::Skipped`]. A
+    /// failed task is submitted again if its retry policy allows, the task is marked as failed
+    /// after the last attempt.
+    ///
+    /// If the maximum parallelism of the DAG is reached, the run waits until another run has
+    /// finished, or it is skipped if the DAG uses [`ParallelismPolicy::Skip`] or too many runs
+    /// are waiting. The maximum parallelism of a task in the DAG limits the instances of the
+    /// task running at the same time in all runs, the others wait or are skipped in the same way.
+    pub fn run<TC>(self, trigger_caller: TriggerCallerType<TC>, report_msg: bool) -> DAGRunReport
+    where
+        TC: TriggerCaller + 'static,
+    {
+        let slot = self.admit_slot();
+        self.run_admitted(slot, trigger_caller, report_msg)
+    }
+
+    /// Run the DAG with the result of [`admit_slot`](Self::admit_slot)
+    fn run_admitted<TC>(
+        self,
+        slot: Option<SlotAdmission>,
+        trigger_caller: TriggerCallerType<TC>,
+        report_msg: bool,
+    ) -> DAGRunReport
+    where
+        TC: TriggerCaller + 'static,
+    {
+        let _slot = match slot {
+            Some(SlotAdmission::Skip) => {
+                info!(
+                    "Skip run {}, the maximum parallelism of the DAG is reached",
+                    self.info.run_id
+                );
+                return self.skipped(&trigger_caller);
+            },
+            Some(SlotAdmission::Wait(queued)) => Some(queued.wait()),
+            Some(SlotAdmission::Run(slot)) => Some(slot),
+            None => None,
+        };
+        self.record_start();
+        let watch = SlaWatch::new(self.index.nodes.len());
+        let (states, instances, sub_reports) = thread::scope(|scope| {
+            let deadlines = self.deadlines();
+            if !deadlines.is_empty() {
+                scope.spawn(|| self.watch_sla(deadlines, &watch));
+            }
+            let results = self.run_nodes(&trigger_caller, report_msg, &watch);
+            watch.finish(None);
+            results
+        });
+        debug!("DAG run finished");
+        let mut tasks: Vec<(String, TaskState)> = states
+            .into_iter()
+            .enumerate()
+            .map(|(i, s)| (self.index.display_name(i), s))
+            .collect();
+        for (i, list) in instances.into_iter().enumerate() {
+            tasks.extend(
+                list.into_iter()
+                    .enumerate()
+                    .map(|(k, s)| (self.instance_name(i, Some(k)), s)),
+            );
+        }
+        for (i, report) in sub_reports.into_iter().enumerate() {
+            let prefix = self.index.display_name(i);
+            tasks.extend(
+                report
+                    .iter()
+                    .flat_map(DAGRunReport::states)
+                    .map(|(name, s)| (format!("{}.{}", prefix, name), s.clone())),
+            );
+        }
+        let report = DAGRunReport::new(tasks);
+        let state = match report.is_success() {
+            true => RunState::Success,
+            false => RunState::Failed,
+        };
+        self.record_finish(state, &report);
+        let (callback, error) = match state {
+            RunState::Success => (&self.callbacks.on_success, None),
+            _ => {
+                let failed = report.states().iter().filter(|(_, s)| s.is_failed());
+                let error = ayn_error!("{} task(s) of the run failed", failed.count());
+                (&self.callbacks.on_failure, Some(Arc::new(error)))
+            },
+        };
+        self.call(callback, self.run_context(), error, &trigger_caller);
+        report
+    }
+
+    /// Submit the nodes until all of them have finished, return their results
+    fn run_nodes<TC>(
+        &self,
+        trigger_caller: &TriggerCallerType<TC>,
+        report_msg: bool,
+        watch: &SlaWatch,
+    ) -> NodeResults
+    where
+        TC: TriggerCaller + 'static,
+    {
+        let (tx, rx) = mpsc::channel();
+        let n = self.index.nodes.len();
+        let mut states = vec![TaskState::Pending; n];
+        // The states and the items of the instances of mapped tasks
+        let mut instances: Vec<Vec<TaskState>> = vec![vec![]; n];
+        let mut items: Vec<Vec<Value>> = vec![vec![]; n];
+        let mut sub_reports: Vec<Option<DAGRunReport>> = vec![None; n];
+        let mut pending: Vec<usize> = self.index.parents.iter().map(Vec::len).collect();
+        let mut ready: VecDeque<usize> = self.index.roots().into();
+        let mut attempts: HashMap<TaskKey, u32> = HashMap::new();
+        // The tasks which are not followed by a branch task
+        let mut skipped = vec![false; n];
+        // The tasks waiting to be submitted, with the time to submit them
+        let mut waiting: Vec<(Instant, TaskKey)> = vec![];
+        let mut running = 0;
+        // The pool slots taken by the running tasks
+        let mut held: HashMap<TaskKey, PoolSlot> = HashMap::new();
+        // The slots of the maximum parallelism of the tasks, taken by the running tasks
+        let mut task_slots: HashMap<TaskKey, RunSlot> = HashMap::new();
+        // The tasks waiting for a slot of their maximum parallelism or their pool, they are
+        // submitted again when the run is woken
+        let mut blocked: Vec<TaskKey> = vec![];
+        // The places of the tasks waiting in the queues of the pools
+        let mut tickets: HashMap<TaskKey, PoolTicket> = HashMap::new();
+        // The places of the tasks waiting in the queues of their maximum parallelism
+        let mut slot_tickets: HashMap<TaskKey, SlotTicket> = HashMap::new();
+        // The time the tasks were first submitted, kept while they reschedule themselves
+        let mut first_pokes: HashMap<TaskKey, Instant> = HashMap::new();
+
+        loop {
+            while let Some(i) = ready.pop_front() {
+                let parents = &self.index.parents[i];
+                skipped[i] |= !parents.is_empty() && parents.iter().all(|p| skipped[*p]);
+                let state = match skipped[i] || self.info.cancel.is_cancelled() {
+                    true => Some(TaskState::Skipped),
+                    false => self.configs[i]
+                        .trigger_rule
+                        .check(parents.iter().map(|p| &states[*p])),
+                };
+                let state = match (state, self.map_items(i)) {
+                    (Some(state), _) => state,
+                    (None, Ok(None)) => {
+                        waiting.push((Instant::now(), (i, None)));
+                        TaskState::Running
+                    },
+                    (None, Ok(Some(list))) if list.is_empty() => self.finish_mapped(i, &[]),
+                    (None, Ok(Some(list))) => {
+                        instances[i] = vec![TaskState::Running; list.len()];
+                        waiting.extend((0..list.len()).map(|k| (Instant::now(), (i, Some(k)))));
+                        items[i] = list;
+                        TaskState::Running
+                    },
+                    (None, Err(e)) => TaskState::Failed(e.to_string()),
+                };
+                if state.is_finished() {
+                    self.notify(((i, None), 1, None), &state, None, trigger_caller);
+                    self.record_task(i, &state);
+                    watch.finish(Some(i));
+                    self.release_children(i, &mut pending, &mut ready);
+                }
+                states[i] = state;
+            }
+            let now = Instant::now();
+            let (mut due, later): (Vec<_>, Vec<_>) =
+                waiting.into_iter().partition(|(t, _)| *t <= now);
+            waiting = later;
+            // The tasks with higher priorities are submitted first
+            due.sort_by_key(|(_, key)| Reverse(self.priorities[key.0]));
+            for (_, key) in due {
+                let mut failure = None;
+                let ticket = tickets.remove(&key);
+                let slot_ticket = slot_tickets.remove(&key);
+                let meta = &self.configs[key.0].meta;
+                if self.info.cancel.is_cancelled() {
+                    failure = Some(AttemptResult::Failed(Arc::new(ayn_error!(
+                        "The DAG run was cancelled"
+                    ))));
+                } else if let (Some(max), false) =
+                    (meta.maximum_parallelism, task_slots.contains_key(&key))
+                {
+                    let policy = meta.parallelism_policy.unwrap_or_default();
+                    let slot_ticket =
+                        slot_ticket.unwrap_or_else(|| meta.slots.request(self.priorities[key.0]));
+                    let slot = match policy {
+                        ParallelismPolicy::Skip => slot_ticket.try_take(max),
+                        ParallelismPolicy::Queue(_) => {
+                            slot_ticket.try_take_or_wake(max, RunEvent::waker(&tx))
+                        },
+                    };
+                    match slot {
+                        Ok(slot) => {
+                            task_slots.insert(key, slot);
+                        },
+                        Err(_) if policy == ParallelismPolicy::Skip => {
+                            failure = Some(AttemptResult::Skipped);
+                        },
+                        Err(slot_ticket) => {
+                            slot_tickets.insert(key, slot_ticket);
+                            blocked.push(key);
+                            continue;
+                        },
+                    }
+                }
+                if let (None, Some((pool, cost))) = (&failure, &meta.pool) {
+                    let ticket = ticket.map_or_else(
+                        || self.pools.request(pool, *cost, self.priorities[key.0]),
+                        Ok,
+                    );
+                    match ticket.map(|t| t.try_take_or_wake(RunEvent::waker(&tx))) {
+                        Ok(Ok(slot)) => {
+                            held.insert(key, slot);
+                        },
+                        Ok(Err(ticket)) => {
+                            tickets.insert(key, ticket);
+                            blocked.push(key);
+                            continue;
+                        },
+                        Err(e) => failure = Some(AttemptResult::Failed(Arc::new(e))),
+                    }
+                }
+                let attempt = attempts.entry(key).or_insert(0);
+                *attempt += 1;
+                if key.1.is_none() {
+                    watch.start_attempt(key.0, *attempt);
+                }
+                running += 1;
+                if let Some(result) = failure {
+                    tx.send(RunEvent::Finished(key, result)).ok();
+                    continue;
+                }
+                match self.configs[key.0].sub_dag {
+                    Some(ref sub) => {
+                        self.start_sub_dag(key.0, sub, &tx, trigger_caller, report_msg)
+                    },
+                    None => {
+                        let item = key.1.map(|k| items[key.0][k].clone());
+                        let first_poke = *first_pokes.entry(key).or_insert(now);
+                        self.submit(
+                            key,
+                            (*attempt, first_poke),
+                            item,
+                            &tx,
+                            trigger_caller,
+                            report_msg,
+                        );
+                    },
+                }
+            }
+
+            let next = waiting.iter().map(|(t, _)| *t).min();
+            if next.is_none() && running == 0 && blocked.is_empty() {
+                break;
+            }
+            let received = match next {
+                Some(next) => match rx.recv_timeout(next.saturating_duration_since(now)) {
+                    Ok(r) => r,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                },
+                None => match rx.recv() {
+                    Ok(r) => r,
+                    Err(_) => break,
+                },
+            };
+            let ((i, instance), result) = match received {
+                RunEvent::Finished(key, result) => (key, result),
+                RunEvent::Freed => {
+                    waiting.extend(blocked.drain(..).map(|key| (Instant::now(), key)));
+                    continue;
+                },
+            };
+            running -= 1;
+            let slots = (
+                held.remove(&(i, instance)),
+                task_slots.remove(&(i, instance)),
+            );
+            if let AttemptResult::TimedOut(Some(ref keeper)) = result {
+                // The slots are given back when the thread of the task exits
+                keeper.send(slots).ok();
+            }
+            if let AttemptResult::Rescheduled(delay) = result {
+                // Rescheduling is not a new attempt
+                *attempts.get_mut(&(i, instance)).unwrap() -= 1;
+                waiting.push((Instant::now() + delay, (i, instance)));
+                continue;
+            }
+            first_pokes.remove(&(i, instance));
+            let attempt = attempts[&(i, instance)];
+            let item = instance.map(|k| items[i][k].clone());
+            let mut error = None;
+            let result = match result {
+                AttemptResult::SubDAG(report) => {
+                    let failed = report
+                        .states()
+                        .iter()
+                        .filter(|(_, s)| s.is_failed())
+                        .count();
+                    sub_reports[i] = Some(report);
+                    match failed {
+                        0 => AttemptResult::Success(None),
+                        n => AttemptResult::Failed(Arc::new(ayn_error!(
+                            "{} task(s) of the sub-DAG failed",
+                            n
+                        ))),
+                    }
+                },
+                r => r,
+            };
+            let state = match result {
+                AttemptResult::Success(Some(followed)) if instance.is_none() => {
+                    match self.follow(i, &followed, &mut skipped) {
+                        Ok(()) => TaskState::Success,
+                        Err(e) => TaskState::Failed(e.to_string()),
+                    }
+                },
+                AttemptResult::Success(_) => TaskState::Success,
+                // A rescheduled task is waiting again and a sub-DAG report is turned into the
+                // result of the task above
+                AttemptResult::SubDAG(_) | AttemptResult::Rescheduled(_) => {
+                    unreachable!("The attempt result of task {} is not final", i)
+                },
+                AttemptResult::Skipped => {
+                    info!(
+                        "Skip task {}, its maximum parallelism is reached",
+                        self.instance_name(i, instance)
+                    );
+                    TaskState::Skipped
+                },
+                AttemptResult::TimedOut(_) => {
+                    warn!(
+                        "Task {} timed out in attempt {}",
+                        self.instance_name(i, instance),
+                        attempt
+                    );
+                    TaskState::TimedOut
+                },
+                AttemptResult::Failed(e) => match self.configs[i].meta.retry {
+                    Some(ref policy)
+                        if !self.info.cancel.is_cancelled() && policy.should_retry(attempt, &e) =>
+                    {
+                        let delay = policy.delay(attempt);
+                        info!(
+                            "Task {} failed in attempt {}, retry after {:?}: {}",
+                            self.instance_name(i, instance),
+                            attempt,
+                            delay,
+                            e
+                        );
+                        waiting.push((Instant::now() + delay, (i, instance)));
+                        let on_retry = &self.configs[i].meta.callbacks.on_retry;
+                        let ctx = self.context((i, instance), attempt, item);
+                        self.call(on_retry, ctx, Some(e), trigger_caller);
+                        continue;
+                    },
+                    _ => {
+                        let state = TaskState::Failed(e.to_string());
+                        error = Some(e);
+                        state
+                    },
+                },
+            };
+            let key = ((i, instance), attempt, item);
+            self.notify(key, &state, error, trigger_caller);
+            match instance {
+                None => states[i] = state,
+                Some(k) => {
+                    instances[i][k] = state;
+                    if !instances[i].iter().all(TaskState::is_finished) {
+                        continue;
+                    }
+                    states[i] = self.finish_mapped(i, &instances[i]);
+                },
+            }
+            self.record_task(i, &states[i]);
+            watch.finish(Some(i));
+            self.release_children(i, &mut pending, &mut ready);
+        }
+        (states, instances, sub_reports)
+    }
+
+    /// Run the sub-DAG of node `i` in a new thread, send its report when it has finished
+    fn start_sub_dag<TC>(
+        &self,
+        i: usize,
+        sub: &DAG,
+        sender: &Sender<RunEvent>,
+        trigger_caller: &TriggerCallerType<TC>,
+        report_msg: bool,
+    ) where
+        TC: TriggerCaller + 'static,
+    {
+        let mut run = DAGRun::new(sub);
+        // The tasks of the sub-DAG are part of the same run
+        run.info = Arc::clone(&self.info);
+        run.pools = self.pools.clone();
+        run.history = None;
+        let (sub_outputs, outputs) = (run.outputs(), self.outputs.clone());
+        let (name, sender, trigger_caller) = (
+            self.index.name_of(i),
+            sender.clone(),
+            Arc::clone(trigger_caller),
+        );
+        StdThreadBuilder::new()
+            .name("sub_dag_run".into())
+            .spawn(move || {
+                let result = match panic::catch_unwind(AssertUnwindSafe(|| {
+                    run.run(trigger_caller, report_msg)
+                })) {
+                    Ok(report) => AttemptResult::SubDAG(report),
+                    Err(_) => AttemptResult::Failed(Arc::new(ayn_error!("Sub-DAG panicked"))),
+                };
+                if let Some(name) = name {
+                    outputs.set(&name, sub_outputs.to_value()).ok();
+                }
+                sender.send(RunEvent::Finished((i, None), result)).ok();
+            })
+            .expect("sub_dag_run can't start.");
+    }
+
+    /// The name of a task in logs and reports, a mapped instance is named like `task[0]`
+    fn instance_name(&self, i: usize, instance: Option<usize>) -> String {
+        match instance {
+            Some(k) => format!("{}[{}]", self.index.display_name(i), k),
+            None => self.index.display_name(i),
+        }
+    }
+
+    /// The items to map over if node `i` is a mapped task
+    fn map_items(&self, i: usize) -> Result<Option<Vec<Value>>> {
+        match self.configs[i].map_over {
+            Some(ref over) => {
+                self.outputs.get::<Vec<Value>>(over).map(Some).map_err(|e| {
+                    ayn_error!("Could not map over the output of task `{}`: {}", over, e)
+                })
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// All instances of mapped task `i` have finished, collect their outputs into a list as
+    /// the output of the task
+    fn finish_mapped(&self, i: usize, instances: &[TaskState]) -> TaskState {
+        let failed = instances.iter().filter(|s| s.is_failed()).count();
+        if failed > 0 {
+            return TaskState::Failed(format!(
+                "{} of {} mapped instances failed",
+                failed,
+                instances.len()
+            ));
+        }
+        let results: Vec<Value> = (0..instances.len())
+            .map(|k| {
+                self.outputs
+                    .get::<Value>(&self.instance_name(i, Some(k)))
+                    .unwrap_or(Value::Null)
+            })
+            .collect();
+        match self
+            .index
+            .name_of(i)
+            .map(|name| self.outputs.set(&name, results))
+        {
+            Some(Err(e)) => TaskState::Failed(e.to_string()),
+            _ => TaskState::Success,
+        }
+    }
+
+    /// Branch task `i` chose to follow `followed`, skip its other children
+    fn follow(&self, i: usize, followed: &[String], skipped: &mut [bool]) -> Result<()> {
+        let children = &self.index.children[i];
+        let names: Vec<Option<String>> = children.iter().map(|c| self.index.name_of(*c)).collect();
+        if let Some(unknown) = followed
+            .iter()
+            .find(|f| !names.iter().any(|n| n.as_ref() == Some(*f)))
+        {
+            return Err(ayn_error!(
+                "Task `{}` is not a child of branch task {}",
+                unknown,
+                self.index.display_name(i)
+            ));
+        }
+        for (c, name) in children.iter().zip(names) {
+            if !name.is_some_and(|n| followed.contains(&n)) {
+                skipped[*c] = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// The context of an attempt of task `key`, `item` is the item of a mapped instance
+    fn context(&self, key: TaskKey, attempt: u32, item: Option<Value>) -> RunContext {
+        let (i, instance) = key;
+        let name = match instance {
+            Some(_) => Some(self.instance_name(i, instance)),
+            None => self.index.name_of(i),
+        };
+        RunContext {
+            run: Arc::clone(&self.info),
+            task_id: self.configs[i].meta.id,
+            attempt,
+            map_index: instance,
+            map_item: item,
+            ..RunContext::new(name, self.outputs.clone())
+        }
+    }
+
+    /// Submit an attempt of a task to the executor, `item` is the item of a mapped instance,
+    /// `first_poke` is the time a rescheduled task was first submitted
+    fn submit<TC>(
+        &self,
+        key: TaskKey,
+        (attempt, first_poke): (u32, Instant),
+        item: Option<Value>,
+        sender: &Sender<RunEvent>,
+        trigger_caller: &TriggerCallerType<TC>,
+        report_msg: bool,
+    ) where
+        TC: TriggerCaller + 'static,
+    {
+        let task = self.index.nodes[key.0]
+            .as_ref()
+            .lock()
+            .unwrap()
+            .task
+            .clone();
+        let runner = NodeRunner {
+            key,
+            task,
+            ctx: RunContext {
+                reschedule: Some(Default::default()),
+                first_poke: Some(first_poke),
+                ..self.context(key, attempt, item)
+            },
+            timeout: self.configs[key.0].meta.timeout,
+            sender: sender.clone(),
+        };
+        trigger_caller
+            .lock()
+            .unwrap()
+            .trigger_safe(runner, report_msg);
+    }
+
+    /// Node `i` has finished, queue its children whose parents have all finished
+    fn release_children(&self, i: usize, pending: &mut [usize], ready: &mut VecDeque<usize>) {
+        for c in &self.index.children[i] {
+            pending[*c] -= 1;
+            if pending[*c] == 0 {
+                ready.push_back(*c);
+            }
+        }
+    }
+}
+
+/// Wrap a task of the DAG, tell the [`DAGRun`] when the task has finished
+struct NodeRunner {
+    key: TaskKey,
+    task: TaskInfo,
+    ctx: RunContext,
+    timeout: Option<Duration>,
+    sender: Sender<RunEvent>,
+}
+
+impl NodeRunner {
+    fn result_of(ctx: &RunContext) -> AttemptResult {
+        match (ctx.failure(), ctx.rescheduled()) {
+            (Some(e), _) => AttemptResult::Failed(e),
+            (None, Some(delay)) => AttemptResult::Rescheduled(delay),
+            (None, None) => AttemptResult::Success(ctx.followed()),
+        }
+    }
+
+    /// Run the task, a panic is reported as the error of the task
+    fn run_task(task: &TaskInfo, ctx: &RunContext) -> RuntimeJoinHandle<()> {
+        panic::catch_unwind(AssertUnwindSafe(|| task.run_with_context(ctx.clone()))).unwrap_or_else(
+            |_| {
+                ctx.report::<()>(Err(BronzeError::msg("Task panicked")));
+                RuntimeJoinHandle::SyncJobHandle
+            },
+        )
+    }
+
+    /// Run a blocking task in a new thread and stop waiting for it after `timeout`, the result
+    /// is sent from another thread, so the executor is not blocked
+    ///
+    /// The thread could not be killed, it is left running in the background and keeps the task
+    /// busy, the pool slots and the parallelism slot of the task are kept until it exits. The
+    /// time waiting for an earlier attempt which is still running counts in `timeout`
+    fn run_with_timeout(&self, timeout: Duration) {
+        let (key, task, ctx) = (self.key, self.task.clone(), self.ctx.clone());
+        let sender = self.sender.clone();
+        StdThreadBuilder::new()
+            .name("task_timer".into())
+            .spawn(move || {
+                let (done_tx, done_rx) = mpsc::channel();
+                let (keep_tx, keep_rx) = mpsc::channel::<HeldSlots>();
+                let task_ctx = ctx.clone();
+                StdThreadBuilder::new()
+                    .name("timed_task".into())
+                    .spawn(move || {
+                        NodeRunner::run_task(&task, &task_ctx);
+                        done_tx.send(()).ok();
+                        // The slots kept for a timed out attempt are given back
+                        drop(keep_rx);
+                    })
+                    .expect("timed_task can't start.");
+                let result = match done_rx.recv_timeout(timeout) {
+                    Err(RecvTimeoutError::Timeout) => AttemptResult::TimedOut(Some(keep_tx)),
+                    _ => NodeRunner::result_of(&ctx),
+                };
+                sender.send(RunEvent::Finished(key, result)).ok();
+            })
+            .expect("task_timer can't start.");
+    }
+}
+
+impl Runnable for NodeRunner {
+    type Handle = RuntimeJoinHandle<()>;
+
+    fn run_async(&self) -> Self::Handle {
+        let (key, sender) = (self.key, self.sender.clone());
+        match self.timeout {
+            // An async task on tokio is aborted in time, others have to run in their own thread
+            Some(timeout) if !(cfg!(feature = "async_tokio") && self.task.is_async()) => {
+                self.run_with_timeout(timeout);
+                return RuntimeJoinHandle::SyncJobHandle;
+            },
+            _ => {},
+        }
+
+        let ctx = self.ctx.clone();
+        match NodeRunner::run_task(&self.task, &ctx) {
+            #[cfg(feature = "async_tokio")]
+            RuntimeJoinHandle::AsyncTokioJoinHandle(mut h) => {
+                // The task is still running, wait for it in another tokio task
+                let timeout = self.timeout;
+                RuntimeJoinHandle::AsyncTokioJoinHandle(tokio::spawn(async move {
+                    let joined = match timeout {
+                        Some(t) => tokio::time::timeout(t, &mut h).await.ok(),
+                        None => Some((&mut h).await),
+                    };
+                    let result = match joined {
+                        Some(Ok(_)) => NodeRunner::result_of(&ctx),
+                        Some(Err(e)) => AttemptResult::Failed(Arc::new(BronzeError::new(e))),
+                        None => {
+                            h.abort();
+                            AttemptResult::TimedOut(None)
+                        },
+                    };
+                    sender.send(RunEvent::Finished(key, result)).ok();
+                }))
+            },
+            h => {
+                sender
+                    .send(RunEvent::Finished(key, NodeRunner::result_of(&ctx)))
+                    .ok();
+                h
+            },
+        }
+    }
+
+    #[inline(always)]
+    fn is_async(&self) -> bool {
+        self.task.is_async()
+    }
+
+    #[inline(always)]
+    fn run_type_name(&self) -> String {
+        self.task.run_type_name()
+    }
+
+    #[inline(always)]
+    fn run_type_id(&self) -> TypeId {
+        self.task.run_type_id()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::*;
+    use crate::task::TryIntoTask;
+    use bronzeflow_time::schedule_time::ScheduleTime;
+    use bronzeflow_utils::ayn_error;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    type Records = Arc<Mutex<Vec<String>>>;
+
+    fn record(records: &Records, name: &str) -> impl Fn() + Send + Clone + 'static {
+        let records = Arc::clone(records);
+        let name = name.to_string();
+        move || records.lock().unwrap().push(name.clone())
+    }
+
+    fn position(records: &Records, name: &str) -> usize {
+        records
+            .lock()
+            .unwrap()
+            .iter()
+            .position(|r| r == name)
+            .unwrap_or_else(|| panic!("Task {} not run", name))
+    }
+
+    #[test]
+    fn run_dag_in_topological_order() {
+        let records: Records = Arc::new(Mutex::new(vec![]));
+        let d = DAGBuilder::new()
+            .task("C", record(&records, "C"))
+            .parent(|bd| {
+                bd.task("D", record(&records, "D"))
+                    .task("D1", record(&records, "D1"))
+                    .parent(|bd2| bd2.task("E1", record(&records, "E1")))
+            })
+            .child(|bd| {
+                bd.task("C1", record(&records, "C1"))
+                    .child(|bd2| bd2.task("B1", record(&records, "B1")))
+            })
+            .build()
+            .unwrap();
+
+        DAGRun::new(&d).run(Arc::new(Mutex::new(DefaultExecutor::new())), false);
+
+        assert_eq!(6, records.lock().unwrap().len());
+        assert!(position(&records, "E1") < position(&records, "D1"));
+        assert!(position(&records, "D") < position(&records, "C"));
+        assert!(position(&records, "D1") < position(&records, "C"));
+        assert!(position(&records, "C") < position(&records, "C1"));
+        assert!(position(&records, "C1") < position(&records, "B1"));
+    }
+
+    #[test]
+    fn pass_output_to_downstream_task() {
+        let records: Records = Arc::new(Mutex::new(vec![]));
+        let rs = Arc::clone(&records);
+        let d = DAGBuilder::new()
+            .task("Count", SyncContextFn(|_: &RunContext| Ok(vec![1, 2, 3])))
+            .child(|bd| {
+                let rs = Arc::clone(&rs);
+                bd.task(
+                    "Sum",
+                    SyncContextFn(move |ctx: &RunContext| {
+                        let sum: i32 = ctx.output::<Vec<i32>>("Count")?.iter().sum();
+                        rs.lock().unwrap().push(sum.to_string());
+                        Ok(sum)
+                    }),
+                )
+            })
+            .build()
+            .unwrap();
+
+        let run = DAGRun::new(&d);
+        let outputs = run.outputs();
+        run.run(Arc::new(Mutex::new(DefaultExecutor::new())), false);
+
+        assert_eq!(vec!["6".to_string()], *records.lock().unwrap());
+        assert_eq!(6, outputs.get::<i32>("Sum").unwrap());
+    }
+
+    #[test]
+    fn pass_run_information_to_tasks() {
+        let info = |ctx: &RunContext| {
+            Ok(serde_json::json!({
+                "dag_id": ctx.dag_id(),
+                "dag_name": ctx.dag_name(),
+                "task_id": ctx.task_id(),
+                "run_id": ctx.run_id(),
+                "time": ctx.logical_time().map(|t| t.to_string()),
+                "date": ctx.param::<String>("date")?,
+            }))
+        };
+        let mut d = DAGBuilder::new()
+            .task("A", SyncContextFn(info))
+            .child(|bd| bd.task("B", SyncContextFn(info)))
+            .build()
+            .unwrap();
+        d.set_name("etl");
+        d.set_schedule("1/10 * * * * *".try_into().unwrap());
+        d.prepare();
+        let t: ScheduleTime = "2024-01-02T03:04:05Z".parse().unwrap();
+        {
+            let mut meta = d.meta.as_ref().unwrap().lock().unwrap();
+            meta.set_id(3);
+            meta.schedule.as_mut().unwrap().set_last_run(&t);
+        }
+        d.for_all_task(|node| {
+            let mut node = node.lock().unwrap();
+            let id = if node.meta.as_ref().unwrap().name.as_deref() == Some("A") {
+                10
+            } else {
+                11
+            };
+            node.meta.as_mut().unwrap().set_id(id);
+        });
+
+        let run = DAGRun::new(&d).with_params(serde_json::json!({"date": "2024-01-02"}));
+        let (outputs, run_id) = (run.outputs(), run.run_id().to_string());
+        let report = run.run(Arc::new(Mutex::new(DefaultExecutor::new())), false);
+
+        assert!(report.is_success());
+        let (a, b) = (
+            outputs.get::<Value>("A").unwrap(),
+            outputs.get::<Value>("B").unwrap(),
+        );
+        assert_eq!(3, a["dag_id"]);
+        assert_eq!("etl", a["dag_name"]);
+        assert_eq!(10, a["task_id"]);
+        assert_eq!(11, b["task_id"]);
+        assert_eq!(run_id, a["run_id"]);
+        assert_eq!(a["run_id"], b["run_id"]);
+        assert_eq!("2024-01-02T03:04:05+00:00", a["time"]);
+        assert_eq!("2024-01-02", b["date"]);
+    }
+
+    #[test]
+    fn cancel_run() {
+        let d = DAGBuilder::new()
+            .task(
+                "A",
+                SyncContextFn(|ctx: &RunContext| {
+                    ctx.cancel_token().cancel();
+                    Ok(())
+                }),
+            )
+            .child(|bd| bd.task("B", || panic!("Should not run")))
+            .build()
+            .unwrap();
+
+        let run = DAGRun::new(&d);
+        let token = run.cancel_token();
+        let report = run.run(Arc::new(Mutex::new(DefaultExecutor::new())), false);
+
+        assert!(token.is_cancelled());
+        assert_eq!(Some(&TaskState::Success), report.state("A"));
+        assert_eq!(Some(&TaskState::Skipped), report.state("B"));
+    }
+
+    #[test]
+    fn failed_task_stops_downstream_tasks() {
+        let d = DAGBuilder::new()
+            .task("Extract", || {})
+            .child(|bd| {
+                bd.task("Ok", TrySyncFn(|| Ok(1)))
+                    .task("Fail", TrySyncFn(|| Err::<(), _>(ayn_error!("no data"))))
+                    .child(|bd2| {
+                        bd2.task("Load", || panic!("Should not run"))
+                            .child(|bd3| bd3.task("Report", || {}))
+                    })
+            })
+            .build()
+            .unwrap();
+
+        let report = DAGRun::new(&d).run(Arc::new(Mutex::new(DefaultExecutor::new())), false);
+
+        assert!(!report.is_success());
+        assert_eq!(Some(&TaskState::Success), report.state("Extract"));
+        assert_eq!(Some(&TaskState::Success), report.state("Ok"));
+        assert_eq!(
+            Some(&TaskState::Failed("no data".to_string())),
+            report.state("Fail")
+        );
+        assert_eq!(Some(&TaskState::UpstreamFailed), report.state("Load"));
+        assert_eq!(Some(&TaskState::UpstreamFailed), report.state("Report"));
+    }
+
+    #[test]
+    fn run_cleanup_task_after_failure() {
+        let records: Records = Arc::new(Mutex::new(vec![]));
+        let d = DAGBuilder::new()
+            .task("Extract", || {})
+            .child(|bd| {
+                bd.task("Fail", TrySyncFn(|| Err::<(), _>(ayn_error!("no data"))))
+                    .child(|bd2| {
+                        bd2.task("Cleanup", record(&records, "Cleanup"))
+                            .trigger_rule(TriggerRule::AllDone)
+                            .task("Alert", record(&records, "Alert"))
+                            .trigger_rule(TriggerRule::OneFailed)
+                            .task("Load", record(&records, "Load"))
+                            .child(|bd3| {
+                                bd3.task("Report", record(&records, "Report"))
+                                    .trigger_rule(TriggerRule::NoneFailed)
+                            })
+                    })
+            })
+            .child(|bd| {
+                bd.task("Check", || {}).child(|bd2| {
+                    bd2.task("Notify", record(&records, "Notify"))
+                        .trigger_rule(TriggerRule::OneFailed)
+                        .child(|bd3| bd3.task("Archive", record(&records, "Archive")))
+                })
+            })
+            .build()
+            .unwrap();
+
+        let report = DAGRun::new(&d).run(Arc::new(Mutex::new(DefaultExecutor::new())), false);
+
+        let mut run = records.lock().unwrap().clone();
+        run.sort();
+        assert_eq!(vec!["Alert".to_string(), "Cleanup".to_string()], run);
+        assert_eq!(Some(&TaskState::UpstreamFailed), report.state("Load"));
+        assert_eq!(Some(&TaskState::UpstreamFailed), report.state("Report"));
+        assert_eq!(Some(&TaskState::Skipped), report.state("Notify"));
+        assert_eq!(Some(&TaskState::Skipped), report.state("Archive"));
+        assert!(!report.is_success());
+    }
+
+    #[test]
+    fn follow_branch() {
+        let records: Records = Arc::new(Mutex::new(vec![]));
+        let d = DAGBuilder::new()
+            .task("Check", BranchFn(|_: &RunContext| Ok(vec!["Incremental"])))
+            .child(|bd| {
+                bd.task("Full", record(&records, "Full"))
+                    .child(|bd2| {
+                        bd2.task("FullLoad", record(&records, "FullLoad"))
+                            .trigger_rule(TriggerRule::AllDone)
+                    })
+                    .task("Incremental", record(&records, "Incremental"))
+            })
+            .build()
+            .unwrap();
+        // Join depends on both FullLoad and Incremental, which could not be built by DAGBuilder
+        let index = d.index();
+        let join_node = DAGBuilder::new()
+            .task("Join", record(&records, "Join"))
+            .trigger_rule(TriggerRule::NoneFailed)
+            .build()
+            .unwrap()
+            .index()
+            .nodes[0]
+            .clone();
+        for name in ["FullLoad", "Incremental"] {
+            let i = (0..index.nodes.len())
+                .find(|i| index.name_of(*i).as_deref() == Some(name))
+                .unwrap();
+            index.nodes[i]
+                .lock()
+                .unwrap()
+                .children
+                .push(join_node.clone());
+            join_node
+                .lock()
+                .unwrap()
+                .parents
+                .push(index.nodes[i].clone());
+        }
+
+        let report = DAGRun::new(&d).run(Arc::new(Mutex::new(DefaultExecutor::new())), false);
+
+        assert_eq!(
+            vec!["Incremental".to_string(), "Join".to_string()],
+            *records.lock().unwrap()
+        );
+        assert_eq!(Some(&TaskState::Skipped), report.state("Full"));
+        assert_eq!(Some(&TaskState::Skipped), report.state("FullLoad"));
+        assert!(report.is_success());
+    }
+
+    #[test]
+    fn follow_unknown_branch() {
+        let d = DAGBuilder::new()
+            .task("Check", BranchFn(|_: &RunContext| Ok(vec!["Missing"])))
+            .child(|bd| bd.task("Next", || {}))
+            .build()
+            .unwrap();
+
+        let report = DAGRun::new(&d).run(Arc::new(Mutex::new(DefaultExecutor::new())), false);
+        assert!(matches!(report.state("Check"), Some(TaskState::Failed(_))));
+        assert_eq!(Some(&TaskState::UpstreamFailed), report.state("Next"));
+    }
+
+    #[test]
+    fn map_over_upstream_output() {
+        let d = DAGBuilder::new()
+            .task(
+                "Find",
+                SyncContextFn(|_: &RunContext| Ok(vec!["a.csv", "bb.csv", "ccc.csv"])),
+            )
+            .child(|bd| {
+                bd.map(
+                    "Count",
+                    "Find",
+                    SyncContextFn(|ctx: &RunContext| {
+                        let file: String = ctx.map_item()?;
+                        Ok(file.len() * 10 + ctx.map_index().unwrap())
+                    }),
+                )
+                .child(|bd2| {
+                    bd2.task(
+                        "Sum",
+                        SyncContextFn(|ctx: &RunContext| {
+                            Ok(ctx.output::<Vec<usize>>("Count")?.iter().sum::<usize>())
+                        }),
+                    )
+                })
+            })
+            .build()
+            .unwrap();
+
+        let run = DAGRun::new(&d);
+        let outputs = run.outputs();
+        let report = run.run(Arc::new(Mutex::new(DefaultExecutor::new())), false);
+
+        assert!(report.is_success());
+        assert_eq!(
+            vec![50, 61, 72],
+            outputs.get::<Vec<usize>>("Count").unwrap()
+        );
+        assert_eq!(183, outputs.get::<usize>("Sum").unwrap());
+        assert_eq!(Some(&TaskState::Success), report.state("Count[2]"));
+    }
+
+    #[test]
+    fn failed_mapped_instance() {
+        let d = DAGBuilder::new()
+            .task("Find", SyncContextFn(|_: &RunContext| Ok(vec![1, 0, 2])))
+            .child(|bd| {
+                bd.map(
+                    "Divide",
+                    "Find",
+                    SyncContextFn(|ctx: &RunContext| {
+                        let n: i32 = ctx.map_item()?;
+                        match n {
+                            0 => Err(ayn_error!("divide by zero")),
+                            n => Ok(10 / n),
+                        }
+                    }),
+                )
+                .child(|bd2| bd2.task("Reduce", || panic!("Should not run")))
+                .map("Empty", "Find", SyncContextFn(|_: &RunContext| Ok(())))
+            })
+            .task(
+                "Nothing",
+                SyncContextFn(|_: &RunContext| Ok(Vec::<i32>::new())),
+            )
+            .child(|bd| bd.map("None", "Nothing", || panic!("Should not run")))
+            .build()
+            .unwrap();
+
+        let run = DAGRun::new(&d);
+        let outputs = run.outputs();
+        let report = run.run(Arc::new(Mutex::new(DefaultExecutor::new())), false);
+
+        assert_eq!(Some(&TaskState::Success), report.state("Divide[0]"));
+        assert_eq!(
+            Some(&TaskState::Failed("divide by zero".to_string())),
+            report.state("Divide[1]")
+        );
+        assert!(matches!(report.state("Divide"), Some(TaskState::Failed(_))));
+        assert_eq!(Some(&TaskState::UpstreamFailed), report.state("Reduce"));
+        assert_eq!(Some(&TaskState::Success), report.state("Empty"));
+        assert_eq!(Some(&TaskState::Success), report.state("None"));
+        assert!(outputs.get::<Vec<i32>>("None").unwrap().is_empty());
+    }
+
+    fn ingest(records: &Records, fail: bool) -> DAG {
+        DAGBuilder::new()
+            .task("Extract", SyncContextFn(|_: &RunContext| Ok(3)))
+            .child(|bd| {
+                let rs = Arc::clone(records);
+                bd.task(
+                    "Validate",
+                    SyncContextFn(move |ctx: &RunContext| {
+                        rs.lock().unwrap().push("Validate".to_string());
+                        match fail {
+                            true => Err(ayn_error!("invalid data")),
+                            false => ctx.output::<i32>("Extract"),
+                        }
+                    }),
+                )
+            })
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn run_sub_dag() {
+        let records: Records = Arc::new(Mutex::new(vec![]));
+        let d = DAGBuilder::new()
+            .task("Extract", record(&records, "Extract"))
+            .child(|bd| {
+                bd.sub_dag("Ingest", ingest(&records, false)).child(|bd2| {
+                    bd2.task(
+                        "Load",
+                        SyncContextFn(|ctx: &RunContext| {
+                            let ingested: serde_json::Value = ctx.output("Ingest")?;
+                            Ok(ingested["Validate"].clone())
+                        }),
+                    )
+                })
+            })
+            .build()
+            .unwrap();
+
+        let run = DAGRun::new(&d);
+        let outputs = run.outputs();
+        let report = run.run(Arc::new(Mutex::new(DefaultExecutor::new())), false);
+
+        assert!(report.is_success());
+        assert_eq!(
+            vec!["Extract".to_string(), "Validate".to_string()],
+            *records.lock().unwrap()
+        );
+        assert_eq!(Some(&TaskState::Success), report.state("Ingest"));
+        assert_eq!(Some(&TaskState::Success), report.state("Ingest.Extract"));
+        assert_eq!(3, outputs.get::<i32>("Load").unwrap());
+    }
+
+    #[test]
+    fn failed_sub_dag() {
+        let records: Records = Arc::new(Mutex::new(vec![]));
+        let d = DAGBuilder::new()
+            .sub_dag("Ingest", ingest(&records, true))
+            .child(|bd| bd.task("Load", || panic!("Should not run")))
+            .build()
+            .unwrap();
+
+        let report = DAGRun::new(&d).run(Arc::new(Mutex::new(DefaultExecutor::new())), false);
+
+        assert!(matches!(report.state("Ingest"), Some(TaskState::Failed(_))));
+        assert_eq!(
+            Some(&TaskState::Failed("invalid data".to_string())),
+            report.state("Ingest.Validate")
+        );
+        assert_eq!(Some(&TaskState::UpstreamFailed), report.state("Load"));
+    }
+
+    #[test]
+    fn panicked_task_is_failed() {
+        let d = DAGBuilder::new()
+            .task("Panic", || panic!("Task panicked on purpose"))
+            .build()
+            .unwrap();
+
+        let report = DAGRun::new(&d).run(Arc::new(Mutex::new(DefaultExecutor::new())), false);
+        assert!(matches!(report.state("Panic"), Some(TaskState::Failed(_))));
+    }
+
+    fn flaky(failures: u32, attempts: &Arc<Mutex<Vec<u32>>>) -> impl TryIntoTask {
+        let attempts = Arc::clone(attempts);
+        SyncContextFn(move |ctx: &RunContext| {
+            attempts.lock().unwrap().push(ctx.attempt());
+            if ctx.attempt() <= failures {
+                Err(ayn_error!("connect timeout"))
+            } else {
+                Ok(ctx.attempt())
+            }
+        })
+    }
+
+    #[test]
+    fn retry_failed_task() {
+        let attempts = Arc::new(Mutex::new(vec![]));
+        let d = DAGBuilder::new()
+            .task("Fetch", flaky(2, &attempts))
+            .retry(RetryPolicy::fixed(3, Duration::from_millis(20)))
+            .child(|bd| bd.task("Load", || {}))
+            .build()
+            .unwrap();
+
+        let run = DAGRun::new(&d);
+        let outputs = run.outputs();
+        let report = run.run(Arc::new(Mutex::new(DefaultExecutor::new())), false);
+
+        assert!(report.is_success());
+        assert_eq!(vec![1, 2, 3], *attempts.lock().unwrap());
+        assert_eq!(3, outputs.get::<u32>("Fetch").unwrap());
+    }
+
+    #[test]
+    fn give_up_retry() {
+        let attempts = Arc::new(Mutex::new(vec![]));
+        let not_matched = Arc::new(Mutex::new(vec![]));
+        let d = dag!(
+            RunnableMetadata::from("Fetch").with_retry(RetryPolicy::exponential(
+                2,
+                Duration::from_millis(10),
+                Duration::from_millis(20)
+            )) => flaky(5, &attempts),
+            RunnableMetadata::from("Other").with_retry(
+                RetryPolicy::fixed(5, Duration::ZERO).retry_if(|e| e.to_string() == "refused")
+            ) => flaky(5, &not_matched)
+        )
+        .build()
+        .unwrap();
+
+        let report = DAGRun::new(&d).run(Arc::new(Mutex::new(DefaultExecutor::new())), false);
+
+        assert_eq!(vec![1, 2], *attempts.lock().unwrap());
+        assert_eq!(vec![1], *not_matched.lock().unwrap());
+        assert_eq!(
+            Some(&TaskState::Failed("connect timeout".to_string())),
+            report.state("Fetch")
+        );
+        assert!(matches!(report.state("Other"), Some(TaskState::Failed(_))));
+    }
+
+    #[test]
+    fn sync_task_timed_out() {
+        let d = DAGBuilder::new()
+            .task("Hang", || thread::sleep(Duration::from_secs(3)))
+            .timeout(Duration::from_millis(100))
+            .child(|bd| bd.task("Next", || panic!("Should not run")))
+            .build()
+            .unwrap();
+
+        let start = Instant::now();
+        let report = DAGRun::new(&d).run(Arc::new(Mutex::new(DefaultExecutor::new())), false);
+
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(Some(&TaskState::TimedOut), report.state("Hang"));
+        assert_eq!(Some(&TaskState::UpstreamFailed), report.state("Next"));
+    }
+
+    #[test]
+    fn keep_pool_slot_of_timed_out_task() {
+        let pools = ResourcePools::new();
+        pools.set_pool("db", 1).unwrap();
+        let d = DAGBuilder::new()
+            .task("Hang", || thread::sleep(Duration::from_millis(600)))
+            .timeout(Duration::from_millis(100))
+            .pool("db", 1)
+            .build()
+            .unwrap();
+
+        let report = DAGRun::new(&d)
+            .with_pools(pools.clone())
+            .run(Arc::new(Mutex::new(DefaultExecutor::new())), false);
+        assert_eq!(Some(&TaskState::TimedOut), report.state("Hang"));
+        // The task is still running in its thread
+        assert_eq!(Some(1), pools.used("db"));
+        thread::sleep(Duration::from_millis(1000));
+        assert_eq!(Some(0), pools.used("db"));
+    }
+
+    #[test]
+    fn run_again_while_timed_out_task_is_running() {
+        let d = DAGBuilder::new()
+            .task("Hang", || thread::sleep(Duration::from_secs(3)))
+            .timeout(Duration::from_millis(100))
+            .build()
+            .unwrap();
+        let executor = Arc::new(Mutex::new(DefaultExecutor::new()));
+
+        let start = Instant::now();
+        for _ in 0..2 {
+            let report = DAGRun::new(&d)
+                .start(Arc::clone(&executor), false)
+                .join()
+                .unwrap();
+            assert_eq!(Some(&TaskState::TimedOut), report.state("Hang"));
+        }
+        // The second run does not wait for the first attempt, which is still running
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[cfg(feature = "async_tokio")]
+    #[test]
+    fn async_task_aborted_after_timeout() {
+        let finished = Arc::new(Mutex::new(false));
+        let f = Arc::clone(&finished);
+        let d = DAGBuilder::new()
+            .task(
+                "Hang",
+                AsyncFn(move || {
+                    let f = Arc::clone(&f);
+                    async move {
+                        tokio::time::sleep(Duration::from_millis(500)).await;
+                        *f.lock().unwrap() = true;
+                    }
+                }),
+            )
+            .timeout(Duration::from_millis(100))
+            .build()
+            .unwrap();
+
+        let rt = Arc::new(TokioRuntime::new());
+        let report = DAGRun::new(&d)
+            .start(Arc::new(Mutex::new(TokioExecutor::new(rt))), false)
+            .join()
+            .unwrap();
+        assert_eq!(Some(&TaskState::TimedOut), report.state("Hang"));
+
+        thread::sleep(Duration::from_millis(800));
+        assert!(!*finished.lock().unwrap());
+    }
+
+    #[cfg(feature = "async_tokio")]
+    #[test]
+    fn failed_async_task() {
+        let d = DAGBuilder::new()
+            .task(
+                "Fail",
+                TryAsyncFn(|| async { Err::<(), _>(ayn_error!("timeout")) }),
+            )
+            .child(|bd| bd.task("Next", AsyncFn(|| async {})))
+            .build()
+            .unwrap();
+
+        let rt = Arc::new(TokioRuntime::new());
+        let report = DAGRun::new(&d)
+            .start(Arc::new(Mutex::new(TokioExecutor::new(rt))), false)
+            .join()
+            .unwrap();
+
+        assert_eq!(
+            Some(&TaskState::Failed("timeout".to_string())),
+            report.state("Fail")
+        );
+        assert_eq!(Some(&TaskState::UpstreamFailed), report.state("Next"));
+    }
+
+    #[cfg(feature = "async_tokio")]
+    #[test]
+    fn pass_output_between_async_tasks() {
+        let d = DAGBuilder::new()
+            .task(
+                "Fetch",
+                AsyncContextFn(|_: RunContext| async {
+                    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                    Ok("data".to_string())
+                }),
+            )
+            .child(|bd| {
+                bd.task(
+                    "Upper",
+                    AsyncContextFn(|ctx: RunContext| async move {
+                        Ok(ctx.output::<String>("Fetch")?.to_uppercase())
+                    }),
+                )
+            })
+            .build()
+            .unwrap();
+
+        let run = DAGRun::new(&d);
+        let outputs = run.outputs();
+        let rt = Arc::new(TokioRuntime::new());
+        run.start(Arc::new(Mutex::new(TokioExecutor::new(rt))), false)
+            .join()
+            .unwrap();
+
+        assert_eq!("DATA", outputs.get::<String>("Upper").unwrap());
+    }
+
+    fn limited_dag(records: &Records, policy: ParallelismPolicy) -> DAG {
+        let records = Arc::clone(records);
+        let mut d = DAG::from(SyncContextFn(move |ctx: &RunContext| {
+            records
+                .lock()
+                .unwrap()
+                .push(format!("start {}", ctx.run_id()));
+            thread::sleep(Duration::from_millis(100));
+            records
+                .lock()
+                .unwrap()
+                .push(format!("end {}", ctx.run_id()));
+            Ok(())
+        }));
+        d.set_maximum_parallelism(1, policy);
+        d
+    }
+
+    #[test]
+    fn skip_run_over_maximum_parallelism() {
+        let records: Records = Arc::new(Mutex::new(vec![]));
+        let d = limited_dag(&records, ParallelismPolicy::Skip);
+        let executor = Arc::new(Mutex::new(DefaultExecutor::new()));
+
+        let first = DAGRun::new(&d).start(Arc::clone(&executor), false);
+        // A run in the current thread is limited in the same way
+        let second = DAGRun::new(&d).run(executor, false);
+
+        assert!(first.join().unwrap().is_success());
+        assert_eq!(&[("#0".to_string(), TaskState::Skipped)], second.states());
+        assert_eq!(2, records.lock().unwrap().len());
+    }
+
+    #[test]
+    fn queue_run_over_maximum_parallelism() {
+        let records: Records = Arc::new(Mutex::new(vec![]));
+        let d = limited_dag(&records, ParallelismPolicy::Queue(2));
+        let executor = Arc::new(Mutex::new(DefaultExecutor::new()));
+
+        let runs: Vec<DAGRun> = (0..4).map(|_| DAGRun::new(&d)).collect();
+        let handles: Vec<_> = runs
+            .into_iter()
+            .map(|r| r.start(Arc::clone(&executor), false))
+            .collect();
+        let reports: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert!(reports[..3].iter().all(DAGRunReport::is_success));
+        // Only two runs could wait at the same time
+        assert_eq!(Some(&TaskState::Skipped), reports[3].state("#0"));
+
+        // The runs never overlap
+        let records = records.lock().unwrap();
+        assert_eq!(6, records.len());
+        for pair in records.chunks(2) {
+            assert_eq!(pair[0].replace("start", "end"), pair[1]);
+        }
+    }
+
+    #[cfg(feature = "async_tokio")]
+    #[test]
+    fn limit_task_parallelism_across_runs() {
+        let limited = |policy: ParallelismPolicy| {
+            let records: Records = Arc::new(Mutex::new(vec![]));
+            let rs = Arc::clone(&records);
+            let task = AsyncFn(move || {
+                let rs = Arc::clone(&rs);
+                async move {
+                    rs.lock().unwrap().push("start".to_string());
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    rs.lock().unwrap().push("end".to_string());
+                }
+            });
+            let mut meta = RunnableMetadata::default();
+            meta.set_maximum_parallelism(1)
+                .set_parallelism_policy(policy);
+            let d = DAGBuilder::new()
+                .task("load", task)
+                .set_meta(meta)
+                .build()
+                .unwrap();
+            let rt = Arc::new(TokioRuntime::new());
+            let executor = Arc::new(Mutex::new(TokioExecutor::new(rt)));
+            let handles: Vec<_> = (0..2)
+                .map(|_| DAGRun::new(&d).start(Arc::clone(&executor), false))
+                .collect();
+            let reports: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+            let records = records.lock().unwrap().clone();
+            (reports, records)
+        };
+
+        // The async tasks of the two runs would overlap without the limit
+        let (reports, records) = limited(ParallelismPolicy::Queue(1));
+        assert!(reports.iter().all(DAGRunReport::is_success));
+        assert_eq!(vec!["start", "end", "start", "end"], records);
+
+        let (reports, records) = limited(ParallelismPolicy::Skip);
+        let states: Vec<_> = reports.iter().map(|r| r.state("load").unwrap()).collect();
+        assert!(states.contains(&&TaskState::Success));
+        assert!(states.contains(&&TaskState::Skipped));
+        assert_eq!(vec!["start", "end"], records);
+    }
+
+    #[test]
+    fn limit_tasks_by_resource_pool() {
+        let (running, peak) = (Arc::new(Mutex::new(0)), Arc::new(Mutex::new(0)));
+        let task = |name: &str| {
+            let (running, peak) = (Arc::clone(&running), Arc::clone(&peak));
+            DAGBuilder::new()
+                .task(name, move || {
+                    let now = {
+                        let mut running = running.lock().unwrap();
+                        *running += 1;
+                        *running
+                    };
+                    let mut peak_now = peak.lock().unwrap();
+                    *peak_now = now.max(*peak_now);
+                    drop(peak_now);
+                    thread::sleep(Duration::from_millis(50));
+                    *running.lock().unwrap() -= 1;
+                })
+                .pool("db", 2)
+                .build()
+                .unwrap()
+        };
+        let pools = ResourcePools::new();
+        pools.set_pool("db", 3).unwrap();
+        let executor = Arc::new(Mutex::new(DefaultExecutor::new()));
+
+        let handles: Vec<_> = ["A", "B", "C"]
+            .iter()
+            .map(|name| {
+                DAGRun::new(&task(name))
+                    .with_pools(pools.clone())
+                    .start(Arc::clone(&executor), false)
+            })
+            .collect();
+        for h in handles {
+            assert!(h.join().unwrap().is_success());
+        }
+
+        // Only one task could take 2 of the 3 slots at the same time
+        assert_eq!(1, *peak.lock().unwrap());
+        assert_eq!(Some(0), pools.used("db"));
+
+        let d = DAGBuilder::new()
+            .task("Big", || panic!("Should not run"))
+            .pool("db", 4)
+            .build()
+            .unwrap();
+        let report = DAGRun::new(&d)
+            .with_pools(pools)
+            .run(Arc::new(Mutex::new(DefaultExecutor::new())), false);
+        assert!(matches!(report.state("Big"), Some(TaskState::Failed(_))));
+    }
+
+    #[test]
+    fn order_ready_tasks_by_priority() {
+        let order = Arc::new(Mutex::new(vec![]));
+        let task = |name: &'static str| {
+            let order = Arc::clone(&order);
+            move || order.lock().unwrap().push(name)
+        };
+        let d = DAGBuilder::new()
+            .task("low", task("low"))
+            .task("high", task("high"))
+            .priority_weight(5)
+            .task("load", task("load"))
+            .weight_rule(WeightRule::Downstream)
+            .child(|bd| {
+                bd.task("clean", task("clean"))
+                    .priority_weight(2)
+                    .child(|bd2| bd2.task("report", task("report")).priority_weight(3))
+            })
+            .build()
+            .unwrap();
+        let run = DAGRun::new(&d);
+        let priority = |name: &str| {
+            let i = (0..run.index.nodes.len())
+                .find(|i| run.index.display_name(*i) == name)
+                .unwrap();
+            run.priorities[i]
+        };
+        assert_eq!(
+            vec![1, 5, 6, 2, 3],
+            ["low", "high", "load", "clean", "report"].map(priority)
+        );
+
+        assert!(run
+            .run(Arc::new(Mutex::new(DefaultExecutor::new())), false)
+            .is_success());
+        assert_eq!(
+            vec!["load", "high", "low", "clean", "report"],
+            *order.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn order_pool_queue_by_priority() {
+        let pools = ResourcePools::new();
+        pools.set_pool("db", 1).unwrap();
+        let held = pools.try_acquire("db", 1).unwrap().unwrap();
+        let order = Arc::new(Mutex::new(vec![]));
+        let executor = Arc::new(Mutex::new(DefaultExecutor::new()));
+        let start = |name: &'static str, weight: i64| {
+            let order = Arc::clone(&order);
+            let d = DAGBuilder::new()
+                .task(name, move || order.lock().unwrap().push(name))
+                .pool("db", 1)
+                .priority_weight(weight)
+                .build()
+                .unwrap();
+            let handle = DAGRun::new(&d)
+                .with_pools(pools.clone())
+                .start(Arc::clone(&executor), false);
+            // Queue the tasks in turn
+            thread::sleep(Duration::from_millis(100));
+            handle
+        };
+        let handles = [start("low", 1), start("middle", 5), start("high", 10)];
+        assert_eq!(Some(3), pools.waiting("db"));
+
+        drop(held);
+        for h in handles {
+            assert!(h.join().unwrap().is_success());
+        }
+        assert_eq!(vec!["high", "middle", "low"], *order.lock().unwrap());
+        assert_eq!(Some(0), pools.waiting("db"));
+    }
+
+    #[test]
+    fn reschedule_sensor() {
+        let pools = ResourcePools::new();
+        pools.set_pool("worker", 1).unwrap();
+        let ready = Arc::new(AtomicBool::new(false));
+        let sensor = {
+            let ready = Arc::clone(&ready);
+            Sensor::new(move |ctx| {
+                // Rescheduling does not start a new attempt
+                assert_eq!(1, ctx.attempt());
+                Ok(ready.load(Ordering::SeqCst))
+            })
+            .interval(Duration::from_millis(20))
+            .timeout(Duration::from_secs(5))
+            .mode(SensorMode::Reschedule)
+        };
+        let d = DAGBuilder::new()
+            .task("wait", sensor)
+            .pool("worker", 1)
+            .priority_weight(10)
+            .task("prepare", move || ready.store(true, Ordering::SeqCst))
+            .pool("worker", 1)
+            .build()
+            .unwrap();
+        // The sensor gives the only slot back, so the task it waits for could run
+        let report = DAGRun::new(&d)
+            .with_pools(pools)
+            .run(Arc::new(Mutex::new(DefaultExecutor::new())), false);
+        assert!(report.is_success());
+
+        let d = DAG::from(
+            Sensor::new(|_| Ok(false))
+                .interval(Duration::from_millis(20))
+                .timeout(Duration::from_millis(100))
+                .mode(SensorMode::Reschedule),
+        );
+        let report = DAGRun::new(&d).run(Arc::new(Mutex::new(DefaultExecutor::new())), false);
+        assert!(!report.is_success());
+    }
+
+    #[test]
+    fn wait_for_run_of_other_dag() {
+        let history = RunHistory::new();
+        // The executor is shared, so the dependency has to give it back while waiting
+        let executor = Arc::new(Mutex::new(DefaultExecutor::new()));
+        let mut report_dag = DAG::from(
+            ExternalDependency::new("ingest")
+                .task("load")
+                .interval(Duration::from_millis(10))
+                .timeout(Duration::from_secs(5))
+                .mode(SensorMode::Reschedule),
+        );
+        report_dag.set_name("report");
+        let handle = DAGRun::new(&report_dag)
+            .with_history(history.clone())
+            .start(Arc::clone(&executor), false);
+        thread::sleep(Duration::from_millis(50));
+        assert!(!handle.is_finished());
+
+        let mut ingest = DAGBuilder::new()
+            .task("load", || thread::sleep(Duration::from_millis(20)))
+            .build()
+            .unwrap();
+        ingest.set_name("ingest");
+        let ingest_run = DAGRun::new(&ingest).with_history(history.clone());
+        let run_id = ingest_run.run_id().to_string();
+        assert!(ingest_run.run(executor, false).is_success());
+        assert!(handle.join().unwrap().is_success());
+
+        let record = history.get(&run_id).unwrap();
+        assert_eq!(RunState::Success, record.state);
+        assert_eq!(Some(&TaskState::Success), record.task_state("load"));
+        assert_eq!(1, history.runs("report").len());
+    }
+
+    #[test]
+    fn call_callbacks() {
+        let events = Arc::new(Mutex::new(vec![]));
+        let log = |event: &'static str| {
+            let events = Arc::clone(&events);
+            move |ctx: &RunContext, e: Option<&BronzeError>| {
+                let name = ctx.task_name().unwrap_or("dag");
+                let error = e.map(|e| format!(": {}", e)).unwrap_or_default();
+                events
+                    .lock()
+                    .unwrap()
+                    .push(format!("{} {}{}", event, name, error));
+            }
+        };
+        let tries = Arc::new(Mutex::new(0));
+        let flaky = TrySyncFn(move || {
+            let mut tries = tries.lock().unwrap();
+            *tries += 1;
+            match *tries {
+                1 => Err(ayn_error!("try again")),
+                _ => Ok(()),
+            }
+        });
+        let mut d = DAGBuilder::new()
+            .task("flaky", flaky)
+            .retry(RetryPolicy::fixed(2, Duration::ZERO))
+            .on_success(log("success"))
+            .on_retry(log("retry"))
+            .child(|bd| {
+                bd.task("cleanup", || {})
+                    .trigger_rule(TriggerRule::OneFailed)
+                    .on_skip(log("skip"))
+            })
+            .task("bad", TrySyncFn(|| Err::<(), _>(ayn_error!("broken"))))
+            .on_failure(log("failure"))
+            .on_success(log("success"))
+            .build()
+            .unwrap();
+        d.set_on_failure(log("failure"));
+        d.set_on_success(log("success"));
+
+        let report = DAGRun::new(&d).run(Arc::new(Mutex::new(DefaultExecutor::new())), false);
+        assert!(!report.is_success());
+        assert_eq!(
+            vec![
+                "retry flaky: try again",
+                "failure bad: broken",
+                "success flaky",
+                "skip cleanup",
+                "failure dag: 1 task(s) of the run failed",
+            ],
+            *events.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn detect_sla_misses() {
+        let started = Instant::now();
+        let missed = Arc::new(Mutex::new(vec![]));
+        let on_miss = {
+            let missed = Arc::clone(&missed);
+            move |ctx: &RunContext, _: Option<&BronzeError>| {
+                let name = ctx.task_name().unwrap_or("dag").to_string();
+                missed.lock().unwrap().push((name, started.elapsed()));
+            }
+        };
+        let mut d = DAGBuilder::new()
+            .task("fast", || {})
+            .sla(Sla::Within(Duration::from_millis(50)))
+            .on_sla_miss(on_miss.clone())
+            .child(|bd| {
+                bd.task("slow", || thread::sleep(Duration::from_millis(500)))
+                    .sla(Sla::Within(Duration::from_millis(50)))
+                    .on_sla_miss(on_miss.clone())
+            })
+            .build()
+            .unwrap();
+        d.set_name("etl");
+        d.set_sla(Sla::Within(Duration::from_millis(100)));
+        d.set_on_sla_miss(on_miss);
+
+        let history = RunHistory::new();
+        let report = DAGRun::new(&d)
+            .with_history(history.clone())
+            .run(Arc::new(Mutex::new(DefaultExecutor::new())), false);
+        // The tasks are not stopped by the misses
+        assert!(report.is_success());
+        let missed = missed.lock().unwrap();
+        assert_eq!(
+            vec!["slow", "dag"],
+            missed.iter().map(|(name, _)| name).collect::<Vec<_>>()
+        );
+        // The misses are reported while the slow task blocks the executor
+        assert!(missed
+            .iter()
+            .all(|(_, at)| *at < Duration::from_millis(400)));
+        let misses = history.sla_misses("etl");
+        assert_eq!(
+            vec![Some("slow".to_string()), None],
+            misses.iter().map(|m| m.task.clone()).collect::<Vec<_>>()
+        );
+        assert!(misses.iter().all(|m| m.detected_at >= m.deadline));
+    }
+
+    #[test]
+    fn cancel_previous_run() {
+        let mut d = DAG::from(SyncContextFn(|ctx: &RunContext| {
+            let started = Instant::now();
+            while !ctx.is_cancelled() && started.elapsed() < Duration::from_secs(5) {
+                thread::sleep(Duration::from_millis(10));
+            }
+            Ok(ctx.is_cancelled())
+        }));
+        d.set_overlap_policy(OverlapPolicy::CancelPrevious);
+        {
+            let mut meta = d.meta.as_ref().unwrap().lock().unwrap();
+            meta.set_id(1);
+            meta.active_runs = Some(ActiveRuns::new());
+        }
+        let executor = Arc::new(Mutex::new(DefaultExecutor::new()));
+
+        let (first, second) = (DAGRun::new(&d), DAGRun::new(&d));
+        let first_token = first.cancel_token();
+        let first = first.start(Arc::clone(&executor), false);
+        thread::sleep(Duration::from_millis(50));
+        let second_token = second.cancel_token();
+        let _second = second.start(executor, false);
+
+        first.join().unwrap();
+        assert!(first_token.is_cancelled());
+        assert!(!second_token.is_cancelled());
+        second_token.cancel();
+    }
+
+    #[cfg(feature = "async_tokio")]
+    #[test]
+    fn run_dag_with_tokio_executor() {
+        let records: Records = Arc::new(Mutex::new(vec![]));
+        let async_task = |name: &str| {
+            let records = Arc::clone(&records);
+            let name = name.to_string();
+            AsyncFn(move || {
+                let records = Arc::clone(&records);
+                let name = name.clone();
+                async move {
+                    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                    records.lock().unwrap().push(name);
+                }
+            })
+        };
+        let d = DAGBuilder::new()
+            .task("A", async_task("A"))
+            .child(|bd| {
+                bd.task("A1", async_task("A1"))
+                    .task("A2", async_task("A2"))
+                    .child(|bd2| bd2.task("A21", async_task("A21")))
+            })
+            .build()
+            .unwrap();
+
+        let rt = Arc::new(TokioRuntime::new());
+        DAGRun::new(&d)
+            .start(Arc::new(Mutex::new(TokioExecutor::new(rt))), false)
+            .join()
+            .unwrap();
+
+        assert_eq!(4, records.lock().unwrap().len());
+        assert_eq!(0, position(&records, "A"));
+        assert!(position(&records, "A2") < position(&records, "A21"));
+    }
+}
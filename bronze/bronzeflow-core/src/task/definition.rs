@@ -0,0 +1,379 @@
+// This is a part of bronze.
+
+//! Declarative DAG definitions, loaded from JSON, YAML or TOML
+//!
+//! A definition only names the runnable of every task, the runnables are created by the
+//! factories registered in a [`TaskRegistry`], so the shape of a DAG could be changed without
+//! recompiling.
+//!
+//! ```yaml
+//! name: etl
+//! schedule: "0 0 * * * *"
+//! tasks:
+//!   - name: extract
+//!     runnable: http_get
+//!     params: { url: "https://example.com/data" }
+//!     retry: { max_attempts: 3, delay: 1.5 }
+//!   - name: load
+//!     runnable: load_db
+//!     depends_on: [extract]
+//!     timeout: 60
+//! ```
+
+use crate::runtime::retry::RetryPolicy;
+use crate::runtime::RunnableMetadata;
+use crate::task::dag::{DepTaskNode, TaskNode, DAG};
+use crate::task::state::{TriggerRule, WeightRule};
+use crate::task::validate::DAGError;
+use crate::task::{TaskInfo, TryIntoTask};
+use bronzeflow_utils::{ayn_error, BronzeError, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+pub type TaskFactory = Box<dyn Fn(&Value) -> Result<TaskInfo> + Send + Sync>;
+
+/// The factories to create runnables by name, a factory receives the `params` of the task
+#[derive(Default)]
+pub struct TaskRegistry {
+    factories: HashMap<String, TaskFactory>,
+}
+
+impl TaskRegistry {
+    pub fn new() -> Self {
+        TaskRegistry::default()
+    }
+
+    pub fn register<F, T>(&mut self, name: &str, factory: F) -> &mut Self
+    where
+        F: Fn(&Value) -> Result<T> + Send + Sync + 'static,
+        T: TryIntoTask,
+    {
+        self.factories.insert(
+            name.to_string(),
+            Box::new(move |params| factory(params).map(TryIntoTask::try_into_task)),
+        );
+        self
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.factories.contains_key(name)
+    }
+
+    pub fn create(&self, name: &str, params: &Value) -> Result<TaskInfo> {
+        let factory = self
+            .factories
+            .get(name)
+            .ok_or_else(|| ayn_error!("No runnable `{}` in the registry", name))?;
+        factory(params)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DAGDefinition {
+    #[serde(default)]
+    pub name: Option<String>,
+    /// The schedule expression, like a cron expression
+    #[serde(default)]
+    pub schedule: Option<String>,
+    pub tasks: Vec<TaskDefinition>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TaskDefinition {
+    pub name: String,
+    /// The name of the runnable factory in the registry
+    pub runnable: String,
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+    /// Passed to the runnable factory
+    #[serde(default)]
+    pub params: Value,
+    #[serde(default)]
+    pub retry: Option<RetryDefinition>,
+    /// The timeout of every attempt, in seconds
+    #[serde(default)]
+    pub timeout: Option<f64>,
+    #[serde(default)]
+    pub trigger_rule: Option<TriggerRule>,
+    /// The name of the upstream task whose output is mapped over
+    #[serde(default)]
+    pub map_over: Option<String>,
+    /// The resource pool of the task
+    #[serde(default)]
+    pub pool: Option<String>,
+    /// The number of pool slots the task takes, 1 by default
+    #[serde(default)]
+    pub pool_slots: Option<u32>,
+    #[serde(default)]
+    pub priority_weight: Option<i64>,
+    #[serde(default)]
+    pub weight_rule: Option<WeightRule>,
+}
+
+/// See [`RetryPolicy`], the delays are in seconds
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetryDefinition {
+    pub max_attempts: u32,
+    #[serde(default)]
+    pub delay: f64,
+    /// Double the delay after every attempt, up to `max_delay`
+    #[serde(default)]
+    pub exponential: bool,
+    #[serde(default)]
+    pub max_delay: Option<f64>,
+    #[serde(default)]
+    pub jitter: bool,
+}
+
+fn seconds(value: f64) -> Result<Duration> {
+    Duration::try_from_secs_f64(value).map_err(|_| ayn_error!("Invalid duration: {}", value))
+}
+
+impl RetryDefinition {
+    pub fn to_policy(&self) -> Result<RetryPolicy> {
+        let delay = seconds(self.delay)?;
+        let policy = match self.exponential {
+            true => {
+                let max_delay = self.max_delay.map_or(Ok(Duration::MAX), seconds)?;
+                RetryPolicy::exponential(self.max_attempts, delay, max_delay)
+            },
+            false => RetryPolicy::fixed(self.max_attempts, delay),
+        };
+        Ok(policy.jitter(self.jitter))
+    }
+}
+
+impl TaskDefinition {
+    pub fn metadata(&self) -> Result<RunnableMetadata> {
+        let mut meta = RunnableMetadata::from(self.name.as_str());
+        if let Some(ref retry) = self.retry {
+            meta.set_retry(retry.to_policy()?);
+        }
+        if let Some(timeout) = self.timeout {
+            meta.set_timeout(seconds(timeout)?);
+        }
+        if let Some(ref pool) = self.pool {
+            meta.set_pool(pool, self.pool_slots.unwrap_or(1));
+        }
+        if let Some(weight) = self.priority_weight {
+            meta.set_priority_weight(weight);
+        }
+        if let Some(rule) = self.weight_rule {
+            meta.set_weight_rule(rule);
+        }
+        Ok(meta)
+    }
+}
+
+impl DAGDefinition {
+    pub fn from_json(s: &str) -> Result<Self> {
+        serde_json::from_str(s).map_err(BronzeError::new)
+    }
+
+    #[cfg(feature = "yaml")]
+    pub fn from_yaml(s: &str) -> Result<Self> {
+        serde_yaml::from_str(s).map_err(BronzeError::new)
+    }
+
+    #[cfg(feature = "toml")]
+    pub fn from_toml(s: &str) -> Result<Self> {
+        toml::from_str(s).map_err(BronzeError::new)
+    }
+
+    /// Load a definition file, the format is decided by the extension of the file
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)?;
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => DAGDefinition::from_json(&content),
+            #[cfg(feature = "yaml")]
+            Some("yaml" | "yml") => DAGDefinition::from_yaml(&content),
+            #[cfg(feature = "toml")]
+            Some("toml") => DAGDefinition::from_toml(&content),
+            _ => Err(ayn_error!(
+                "Unsupported DAG definition file: {}",
+                path.display()
+            )),
+        }
+    }
+
+    /// Create the runnables by `registry` and build the DAG, the DAG is validated like
+    /// [`DAGBuilder::build`], and all tasks must have names
+    ///
+    /// [`DAGBuilder::build`]: crate::task::builder::DAGBuilder::build
+    pub fn build(&self, registry: &TaskRegistry) -> Result<DAG> {
+        let mut nodes: HashMap<&str, DepTaskNode> = HashMap::new();
+        let mut all = vec![];
+        for t in &self.tasks {
+            let task = registry.create(&t.runnable, &t.params)?;
+            let mut node = TaskNode::with_meta(t.metadata()?, task);
+            node.trigger_rule = t.trigger_rule.unwrap_or_default();
+            node.map_over = t.map_over.clone();
+            let node = Arc::new(Mutex::new(node));
+            if nodes.insert(t.name.as_str(), node.clone()).is_some() {
+                return Err(DAGError::DuplicateName(t.name.clone()).into());
+            }
+            all.push(node);
+        }
+        for t in &self.tasks {
+            let node = &nodes[t.name.as_str()];
+            for dep in &t.depends_on {
+                let parent = nodes.get(dep.as_str()).ok_or_else(|| {
+                    ayn_error!("Task `{}` depends on an unknown task `{}`", t.name, dep)
+                })?;
+                parent.lock().unwrap().children.push(node.clone());
+                node.lock().unwrap().parents.push(parent.clone());
+            }
+        }
+        let mut dag = DAG::validated(all, true)?;
+        if let Some(ref name) = self.name {
+            dag.set_name(name);
+        }
+        Ok(dag)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::*;
+    use crate::task::dag_run::DAGRun;
+
+    fn registry() -> TaskRegistry {
+        let mut registry = TaskRegistry::new();
+        registry
+            .register("numbers", |params| {
+                let count = params["count"].as_u64().unwrap_or(3);
+                Ok(SyncContextFn(move |_: &RunContext| {
+                    Ok((1..=count).collect::<Vec<_>>())
+                }))
+            })
+            .register("sum", |params| {
+                let of = params["of"]
+                    .as_str()
+                    .ok_or_else(|| ayn_error!("`of` is required"))?
+                    .to_string();
+                Ok(SyncContextFn(move |ctx: &RunContext| {
+                    Ok(ctx.output::<Vec<u64>>(&of)?.iter().sum::<u64>())
+                }))
+            });
+        registry
+    }
+
+    fn run(definition: &DAGDefinition) -> TaskOutputs {
+        let d = definition.build(&registry()).unwrap();
+        let run = DAGRun::new(&d);
+        let outputs = run.outputs();
+        let report = run.run(Arc::new(Mutex::new(DefaultExecutor::new())), false);
+        assert!(report.is_success());
+        outputs
+    }
+
+    #[test]
+    fn load_json() {
+        let definition = DAGDefinition::from_json(
+            r#"{
+                "name": "numbers",
+                "schedule": "1/10 * * * * *",
+                "tasks": [
+                    {"name": "gen", "runnable": "numbers", "params": {"count": 4}},
+                    {"name": "total", "runnable": "sum", "depends_on": ["gen"],
+                     "params": {"of": "gen"}, "timeout": 1.5}
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(Some("1/10 * * * * *"), definition.schedule.as_deref());
+        assert_eq!(10, run(&definition).get::<u64>("total").unwrap());
+    }
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn load_yaml() {
+        let definition = DAGDefinition::from_yaml(
+            r#"
+name: numbers
+tasks:
+  - name: gen
+    runnable: numbers
+    retry: { max_attempts: 3, delay: 0.5, exponential: true, max_delay: 10, jitter: true }
+  - name: total
+    runnable: sum
+    depends_on: [gen]
+    params: { of: gen }
+  - name: alert
+    runnable: numbers
+    depends_on: [total]
+    trigger_rule: one_failed
+"#,
+        )
+        .unwrap();
+        let retry = definition.tasks[0].retry.as_ref().unwrap();
+        assert_eq!(3, retry.to_policy().unwrap().max_attempts());
+        assert_eq!(
+            Some(TriggerRule::OneFailed),
+            definition.tasks[2].trigger_rule
+        );
+        assert_eq!(6, run(&definition).get::<u64>("total").unwrap());
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn load_toml() {
+        let definition = DAGDefinition::from_toml(
+            r#"
+schedule = "@daily"
+
+[[tasks]]
+name = "gen"
+runnable = "numbers"
+params = { count = 2 }
+
+[[tasks]]
+name = "total"
+runnable = "sum"
+depends_on = ["gen"]
+params = { of = "gen" }
+"#,
+        )
+        .unwrap();
+        assert_eq!(3, run(&definition).get::<u64>("total").unwrap());
+    }
+
+    #[test]
+    fn reject_invalid_definition() {
+        let build = |json: &str| {
+            DAGDefinition::from_json(json)
+                .and_then(|d| d.build(&registry()))
+                .err()
+                .expect("Definition should be invalid")
+                .to_string()
+        };
+        assert!(
+            build(r#"{"tasks": [{"name": "a", "runnable": "missing"}]}"#)
+                .contains("No runnable `missing`")
+        );
+        assert!(
+            build(r#"{"tasks": [{"name": "a", "runnable": "numbers", "depends_on": ["b"]}]}"#)
+                .contains("unknown task `b`")
+        );
+        assert!(
+            build(r#"{"tasks": [{"name": "a", "runnable": "numbers", "retries": 3}]}"#)
+                .contains("unknown field `retries`")
+        );
+        assert!(build(
+            r#"{"tasks": [
+                {"name": "a", "runnable": "numbers", "depends_on": ["b"]},
+                {"name": "b", "runnable": "numbers", "depends_on": ["a"]}
+            ]}"#
+        )
+        .contains("cycle"));
+    }
+}
@@ -0,0 +1,166 @@
+// This is a part of bronze.
+
+//! Render a DAG as Graphviz DOT or Mermaid flowchart text
+//!
+//! Nodes are named `n0`, `n1`... in the order they are found from the root tasks, so rendering
+//! the same DAG twice gives the same text. With a [`DAGRunReport`], every node is filled with
+//! the colour of its state in the run.
+
+use crate::task::dag::DAG;
+use crate::task::state::{DAGRunReport, TaskState};
+
+/// The fill colour of a task in `state`
+fn state_color(state: &TaskState) -> &'static str {
+    match state {
+        TaskState::Pending => "#e0e0e0",
+        TaskState::Running => "#90caf9",
+        TaskState::Success => "#a5d6a7",
+        TaskState::Failed(_) => "#ef9a9a",
+        TaskState::TimedOut => "#ffcc80",
+        TaskState::UpstreamFailed => "#ffe082",
+        TaskState::Skipped => "#f8bbd0",
+    }
+}
+
+/// One node of the rendered graph
+struct GraphNode {
+    label: String,
+    sub_dag: bool,
+    color: Option<&'static str>,
+}
+
+impl DAG {
+    fn graph(&self, report: Option<&DAGRunReport>) -> (Vec<GraphNode>, Vec<(usize, usize)>) {
+        let index = self.index();
+        let nodes = (0..index.nodes.len())
+            .map(|i| {
+                let name = index.display_name(i);
+                let node = index.nodes[i].lock().unwrap();
+                let label = match node.meta.as_ref().and_then(|m| m.id) {
+                    Some(id) => format!("{}\nid: {}", name, id),
+                    None => name.clone(),
+                };
+                GraphNode {
+                    label,
+                    sub_dag: node.sub_dag.is_some(),
+                    color: report.and_then(|r| r.state(&name)).map(state_color),
+                }
+            })
+            .collect();
+        let edges = index
+            .children
+            .iter()
+            .enumerate()
+            .flat_map(|(i, children)| children.iter().map(move |c| (i, *c)))
+            .collect();
+        (nodes, edges)
+    }
+
+    /// The DAG in Graphviz DOT, nodes are coloured by their states in `report`
+    pub fn to_dot(&self, report: Option<&DAGRunReport>) -> String {
+        let (nodes, edges) = self.graph(report);
+        let mut s = String::from("digraph dag {\n  rankdir=LR;\n");
+        s.push_str("  node [shape=box, style=\"rounded,filled\", fillcolor=\"#ffffff\"];\n");
+        for (i, node) in nodes.iter().enumerate() {
+            let label = node
+                .label
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            s.push_str(&format!("  n{} [label=\"{}\"", i, label));
+            if node.sub_dag {
+                s.push_str(", shape=box3d");
+            }
+            if let Some(color) = node.color {
+                s.push_str(&format!(", fillcolor=\"{}\"", color));
+            }
+            s.push_str("];\n");
+        }
+        for (from, to) in edges {
+            s.push_str(&format!("  n{} -> n{};\n", from, to));
+        }
+        s.push_str("}\n");
+        s
+    }
+
+    /// The DAG as a Mermaid flowchart, nodes are coloured by their states in `report`
+    pub fn to_mermaid(&self, report: Option<&DAGRunReport>) -> String {
+        let (nodes, edges) = self.graph(report);
+        let mut s = String::from("flowchart LR\n");
+        for (i, node) in nodes.iter().enumerate() {
+            let label = node.label.replace('"', "#quot;").replace('\n', "<br/>");
+            // A sub-DAG is drawn as a subroutine
+            let (open, close) = if node.sub_dag {
+                ("[[", "]]")
+            } else {
+                ("[", "]")
+            };
+            s.push_str(&format!("  n{}{}\"{}\"{}\n", i, open, label, close));
+        }
+        for (from, to) in edges {
+            s.push_str(&format!("  n{} --> n{}\n", from, to));
+        }
+        for (i, node) in nodes.iter().enumerate() {
+            if let Some(color) = node.color {
+                s.push_str(&format!("  style n{} fill:{}\n", i, color));
+            }
+        }
+        s
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::prelude::*;
+
+    fn build_dag() -> DAG {
+        let meta = || {
+            let mut meta = RunnableMetadata::default();
+            meta.set_id(7).set_name("load \"db\"".to_string());
+            meta
+        };
+        let sub = dag!("inner" => || {}).build().unwrap();
+        DAGBuilder::new()
+            .task("extract", || {})
+            .child(|bd| {
+                bd.task(meta(), || {})
+                    .child(|bd2| bd2.sub_dag("sub", sub.clone()))
+            })
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn render_dot() {
+        assert_eq!(
+            "digraph dag {\n  rankdir=LR;\n  \
+             node [shape=box, style=\"rounded,filled\", fillcolor=\"#ffffff\"];\n  \
+             n0 [label=\"extract\"];\n  \
+             n1 [label=\"load \\\"db\\\"\\nid: 7\"];\n  \
+             n2 [label=\"sub\", shape=box3d];\n  \
+             n0 -> n1;\n  n1 -> n2;\n}\n",
+            build_dag().to_dot(None)
+        );
+    }
+
+    #[test]
+    fn render_mermaid_with_report() {
+        let report = DAGRunReport::new(vec![
+            ("extract".to_string(), TaskState::Success),
+            (
+                "load \"db\"".to_string(),
+                TaskState::Failed("oops".to_string()),
+            ),
+            ("sub".to_string(), TaskState::UpstreamFailed),
+        ]);
+        assert_eq!(
+            "flowchart LR\n  \
+             n0[\"extract\"]\n  \
+             n1[\"load #quot;db#quot;<br/>id: 7\"]\n  \
+             n2[[\"sub\"]]\n  \
+             n0 --> n1\n  n1 --> n2\n  \
+             style n0 fill:#a5d6a7\n  style n1 fill:#ef9a9a\n  style n2 fill:#ffe082\n",
+            build_dag().to_mermaid(Some(&report))
+        );
+    }
+}
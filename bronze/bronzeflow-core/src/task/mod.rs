@@ -1,10 +1,19 @@
+pub mod backfill;
 pub mod builder;
 pub mod dag;
+pub mod dag_run;
+pub mod definition;
+pub mod graph;
+pub mod state;
+pub mod validate;
 
 use crate::prelude::{Runnable, RuntimeJoinHandle};
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 
 use crate::runtime::{BuildFromRunnable, SafeMetadata, SafeWrappedRunner, WrappedRunner};
+use crate::store::RunnableKey;
+use bronzeflow_time::schedule_time::ScheduleTime;
+use bronzeflow_utils::info;
 
 pub type TaskInfo = SafeWrappedRunner;
 
@@ -13,7 +22,7 @@ impl BuildFromRunnable for TaskInfo {
     fn build_from(
         runnable: impl Runnable<Handle = RuntimeJoinHandle<()>> + Send + 'static,
     ) -> TaskInfo {
-        SafeWrappedRunner(Arc::new(Mutex::new(WrappedRunner(Box::new(runnable)))))
+        SafeWrappedRunner::new(WrappedRunner(Box::new(runnable)))
     }
 }
 
@@ -23,6 +32,7 @@ pub struct WrappedTask {
     pub(crate) meta: Option<SafeMetadata>,
 }
 
+#[allow(clippy::large_enum_variant)]
 #[derive(Clone)]
 pub enum RunnableHolder {
     Task(WrappedTask),
@@ -36,6 +46,48 @@ impl RunnableHolder {
             RunnableHolder::Dag(d) => d.meta.as_ref().map(Arc::clone),
         }
     }
+
+    /// Whether the runnable is the one of `key`
+    pub(crate) fn matches(&self, key: &RunnableKey) -> bool {
+        let meta = match self {
+            RunnableHolder::Task(t) => t.meta.as_ref(),
+            RunnableHolder::Dag(d) => d.meta.as_ref(),
+        };
+        meta.is_some_and(|m| {
+            let m = m.lock().unwrap();
+            match key {
+                RunnableKey::Id(id) => m.id == Some(*id),
+                RunnableKey::Name(name) => m.name.as_ref() == Some(name),
+            }
+        })
+    }
+
+    /// Whether the runnable should be triggered at `now`, move its schedule to the next time
+    /// if so. A runnable which has run `maximum_run_times` times is never triggered again, a
+    /// paused one is not triggered until it is resumed
+    pub(crate) fn is_due(&mut self, now: &ScheduleTime) -> bool {
+        let Some(meta) = self.time_holder() else {
+            return false;
+        };
+        let mut meta = meta.lock().unwrap();
+        if meta.paused || meta.is_retired() {
+            return false;
+        }
+        let due = meta
+            .schedule
+            .as_mut()
+            .is_some_and(|s| s.cmp_and_to_next(now));
+        if due {
+            meta.run_times += 1;
+            if meta.is_retired() {
+                info!(
+                    "{:?} reached its maximum run times {}, retired",
+                    meta.name, meta.run_times
+                );
+            }
+        }
+        due
+    }
 }
 
 impl WrappedTask {
@@ -90,6 +142,28 @@ where
     type TaskDetail = WrappedRunner;
 
     fn try_into_task(self) -> TaskInfo {
-        SafeWrappedRunner(Arc::new(Mutex::new(WrappedRunner(Box::new(self)))))
+        SafeWrappedRunner::new(WrappedRunner(Box::new(self)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::*;
+    use chrono::{Duration, Utc};
+
+    #[test]
+    fn retire_after_maximum_run_times() {
+        let mut d = DAG::from(|| {});
+        d.set_schedule("* * * * * *".try_into().unwrap());
+        d.set_maximum_run_times(2);
+        d.prepare();
+        let mut holder = RunnableHolder::Dag(d);
+
+        let later = ScheduleTime::new(Utc::now() + Duration::days(1));
+        assert!(holder.is_due(&later));
+        assert!(holder.is_due(&later));
+        assert!(!holder.is_due(&later));
+        assert!(holder.time_holder().unwrap().lock().unwrap().is_retired());
     }
 }
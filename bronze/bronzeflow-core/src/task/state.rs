@@ -0,0 +1,165 @@
+// This is a part of bronze.
+
+//! The states of the tasks in a DAG run, and the rules to decide whether a task should run
+//! by the states of its parents
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TaskState {
+    /// Waiting for the upstream tasks
+    Pending,
+    Running,
+    Success,
+    /// The task returned an error or panicked, with the error message
+    Failed(String),
+    /// The task did not finish in its timeout
+    TimedOut,
+    /// The task was not run because an upstream task did not succeed
+    UpstreamFailed,
+    /// The task was not run because its trigger rule was not satisfied
+    Skipped,
+}
+
+impl TaskState {
+    #[inline(always)]
+    pub fn is_success(&self) -> bool {
+        matches!(self, TaskState::Success)
+    }
+
+    /// The task or one of its upstream tasks failed
+    #[inline(always)]
+    pub fn is_failed(&self) -> bool {
+        matches!(
+            self,
+            TaskState::Failed(_) | TaskState::TimedOut | TaskState::UpstreamFailed
+        )
+    }
+
+    /// Whether the task will not change its state any more in this run
+    #[inline(always)]
+    pub fn is_finished(&self) -> bool {
+        !matches!(self, TaskState::Pending | TaskState::Running)
+    }
+}
+
+/// When a task runs, decided by the states of its parents after they have all finished
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TriggerRule {
+    /// All parents succeeded
+    #[default]
+    AllSuccess,
+    /// All parents finished, whatever their states are
+    AllDone,
+    /// At least one parent failed
+    OneFailed,
+    /// No parent failed, the parents could be skipped
+    NoneFailed,
+}
+
+impl TriggerRule {
+    /// Return `None` if the task should run, otherwise the state of the task which is not run
+    pub(crate) fn check<'a>(
+        &self,
+        parents: impl Iterator<Item = &'a TaskState>,
+    ) -> Option<TaskState> {
+        let (mut failed, mut succeeded) = (false, true);
+        for state in parents {
+            failed |= state.is_failed();
+            succeeded &= state.is_success();
+        }
+        match self {
+            TriggerRule::AllSuccess | TriggerRule::NoneFailed if failed => {
+                Some(TaskState::UpstreamFailed)
+            },
+            TriggerRule::AllSuccess if !succeeded => Some(TaskState::Skipped),
+            TriggerRule::OneFailed if !failed => Some(TaskState::Skipped),
+            _ => None,
+        }
+    }
+}
+
+/// How the effective priority of a task is computed from the priority weights
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WeightRule {
+    /// The weight of the task itself
+    #[default]
+    Absolute,
+    /// The sum of the weights of the task and all its downstream tasks, so the tasks which
+    /// many others depend on run first
+    Downstream,
+}
+
+/// The final states of all tasks in a DAG run
+#[derive(Debug, Clone)]
+pub struct DAGRunReport {
+    tasks: Vec<(String, TaskState)>,
+}
+
+impl DAGRunReport {
+    pub(crate) fn new(tasks: Vec<(String, TaskState)>) -> Self {
+        DAGRunReport { tasks }
+    }
+
+    /// The state of task `task_name`, nameless tasks are named by their position like `#0`
+    pub fn state(&self, task_name: &str) -> Option<&TaskState> {
+        self.tasks
+            .iter()
+            .find(|(name, _)| name == task_name)
+            .map(|(_, state)| state)
+    }
+
+    pub fn states(&self) -> &[(String, TaskState)] {
+        &self.tasks
+    }
+
+    /// Whether no task in the run failed, the skipped tasks are ignored
+    pub fn is_success(&self) -> bool {
+        !self.tasks.iter().any(|(_, s)| s.is_failed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(rule: TriggerRule, parents: &[TaskState]) -> Option<TaskState> {
+        rule.check(parents.iter())
+    }
+
+    #[test]
+    fn check_trigger_rules() {
+        use TaskState::*;
+        let failed = Failed("error".to_string());
+
+        assert_eq!(None, check(TriggerRule::AllSuccess, &[Success, Success]));
+        assert_eq!(
+            Some(UpstreamFailed),
+            check(TriggerRule::AllSuccess, &[Success, TimedOut])
+        );
+        assert_eq!(
+            Some(Skipped),
+            check(TriggerRule::AllSuccess, &[Success, Skipped])
+        );
+
+        assert_eq!(
+            None,
+            check(TriggerRule::AllDone, &[failed.clone(), Skipped])
+        );
+
+        assert_eq!(
+            None,
+            check(TriggerRule::OneFailed, &[Success, UpstreamFailed])
+        );
+        assert_eq!(Some(Skipped), check(TriggerRule::OneFailed, &[Success]));
+        assert_eq!(Some(Skipped), check(TriggerRule::OneFailed, &[]));
+
+        assert_eq!(None, check(TriggerRule::NoneFailed, &[Success, Skipped]));
+        assert_eq!(
+            Some(UpstreamFailed),
+            check(TriggerRule::NoneFailed, &[Skipped, failed])
+        );
+    }
+}
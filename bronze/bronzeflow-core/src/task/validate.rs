@@ -0,0 +1,244 @@
+// This is a part of bronze.
+
+//! Validate the structure of a DAG before it could be scheduled
+//!
+//! A DAG is rejected if it contains a cycle, two tasks with the same name, tasks which could
+//! never be reached from a root task, nameless tasks when the names are required, or mapped
+//! tasks which do not depend on the task they map over.
+
+use crate::task::dag::DAGIndex;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DAGError {
+    /// The names of the tasks on the cycle, the first task is repeated at the end
+    Cycle(Vec<String>),
+    /// More than one task in the DAG has this name
+    DuplicateName(String),
+    /// The number of tasks without name
+    NamelessTask(usize),
+    /// The names of the tasks which could not be reached from any root task
+    Unreachable(Vec<String>),
+    /// The mapped task and the task it maps over, which is not its parent
+    InvalidMap(String, String),
+}
+
+impl Display for DAGError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DAGError::Cycle(path) => write!(f, "DAG has a cycle: {}", path.join(" -> ")),
+            DAGError::DuplicateName(name) => write!(f, "Task name `{}` is duplicated", name),
+            DAGError::NamelessTask(n) => write!(f, "{} task(s) without name", n),
+            DAGError::Unreachable(names) => {
+                write!(f, "Tasks could not be reached: {}", names.join(", "))
+            },
+            DAGError::InvalidMap(task, over) => {
+                write!(
+                    f,
+                    "Mapped task `{}` is not a child of task `{}`",
+                    task, over
+                )
+            },
+        }
+    }
+}
+
+impl Error for DAGError {}
+
+impl DAGIndex {
+    pub(crate) fn name_of(&self, i: usize) -> Option<String> {
+        self.nodes[i]
+            .as_ref()
+            .lock()
+            .unwrap()
+            .meta
+            .as_ref()
+            .and_then(|m| m.name.clone())
+            .filter(|n| !n.is_empty())
+    }
+
+    /// The name used to report a task, a nameless task is reported by its position
+    pub(crate) fn display_name(&self, i: usize) -> String {
+        self.name_of(i).unwrap_or_else(|| format!("#{}", i))
+    }
+
+    /// Run all checks, return the first error found
+    pub(crate) fn validate(&self, require_names: bool) -> Result<(), DAGError> {
+        if let Some(cycle) = self.find_cycle() {
+            return Err(DAGError::Cycle(
+                cycle.into_iter().map(|i| self.display_name(i)).collect(),
+            ));
+        }
+
+        let names: Vec<Option<String>> = (0..self.nodes.len()).map(|i| self.name_of(i)).collect();
+        let nameless = names.iter().filter(|n| n.is_none()).count();
+        if require_names && nameless > 0 {
+            return Err(DAGError::NamelessTask(nameless));
+        }
+        let mut seen = HashSet::new();
+        for name in names.into_iter().flatten() {
+            if !seen.insert(name.clone()) {
+                return Err(DAGError::DuplicateName(name));
+            }
+        }
+
+        for i in 0..self.nodes.len() {
+            let over = self.nodes[i].as_ref().lock().unwrap().map_over.clone();
+            if let Some(over) = over {
+                if !self.parents[i]
+                    .iter()
+                    .any(|p| self.name_of(*p).as_ref() == Some(&over))
+                {
+                    return Err(DAGError::InvalidMap(self.display_name(i), over));
+                }
+            }
+        }
+
+        let order = self.topological_order();
+        if order.len() != self.nodes.len() {
+            let mut reached = vec![false; self.nodes.len()];
+            order.into_iter().for_each(|i| reached[i] = true);
+            return Err(DAGError::Unreachable(
+                (0..self.nodes.len())
+                    .filter(|i| !reached[*i])
+                    .map(|i| self.display_name(i))
+                    .collect(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Depth first search through the children, return the first cycle found
+    fn find_cycle(&self) -> Option<Vec<usize>> {
+        // 0: not visited, 1: on the current path, 2: done
+        let mut state = vec![0u8; self.nodes.len()];
+        for start in 0..self.nodes.len() {
+            if state[start] != 0 {
+                continue;
+            }
+            let mut path = vec![start];
+            let mut next_child = vec![0usize];
+            state[start] = 1;
+            while let Some(&node) = path.last() {
+                let pos = next_child.last_mut().unwrap();
+                match self.children[node].get(*pos) {
+                    Some(&c) => {
+                        *pos += 1;
+                        if state[c] == 1 {
+                            let begin = path.iter().position(|p| *p == c).unwrap();
+                            let mut cycle = path[begin..].to_vec();
+                            cycle.push(c);
+                            return Some(cycle);
+                        }
+                        if state[c] == 0 {
+                            state[c] = 1;
+                            path.push(c);
+                            next_child.push(0);
+                        }
+                    },
+                    None => {
+                        state[node] = 2;
+                        path.pop();
+                        next_child.pop();
+                    },
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::*;
+    use crate::task::dag::DepTaskNode;
+
+    fn link(parent: &DepTaskNode, child: &DepTaskNode) {
+        parent.lock().unwrap().children.push(child.clone());
+        child.lock().unwrap().parents.push(parent.clone());
+    }
+
+    fn build_error(builder: DAGBuilder) -> DAGError {
+        let err = builder.build().err().expect("DAG should be invalid");
+        err.downcast::<DAGError>().unwrap()
+    }
+
+    #[test]
+    fn reject_cycle() {
+        let d = dag!("A" => || {}, "B" => || {}, "C" => || {})
+            .build()
+            .unwrap();
+        let index = d.index();
+        let node = |name: &str| {
+            let i = (0..index.nodes.len())
+                .find(|i| index.name_of(*i).as_deref() == Some(name))
+                .unwrap();
+            index.nodes[i].clone()
+        };
+        let (a, b, c) = (node("A"), node("B"), node("C"));
+        link(&a, &b);
+        link(&b, &c);
+        link(&c, &b);
+
+        match d.index().validate(false) {
+            Err(DAGError::Cycle(path)) => {
+                assert_eq!(3, path.len());
+                assert_eq!(path[0], path[2]);
+                assert!(path.contains(&"B".to_string()) && path.contains(&"C".to_string()));
+            },
+            r => panic!("Cycle not found: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn reject_duplicate_name() {
+        let err = build_error(dag!("A" => || {}, "B" => || {}, "A" => || {}));
+        assert_eq!(DAGError::DuplicateName("A".to_string()), err);
+
+        let err = build_error(
+            DAGBuilder::new()
+                .task("A", || {})
+                .child(|bd| bd.task("A", || {})),
+        );
+        assert_eq!(DAGError::DuplicateName("A".to_string()), err);
+    }
+
+    #[test]
+    fn require_names() {
+        let builder = || {
+            DAGBuilder::new()
+                .task("A", || {})
+                .child(|bd| bd.task("", || {}).task("", || {}))
+        };
+        assert!(builder().build().is_ok());
+        assert_eq!(
+            DAGError::NamelessTask(2),
+            build_error(builder().require_names(true))
+        );
+    }
+
+    #[test]
+    fn reject_unreachable() {
+        let d = dag!("A" => || {}, "B" => || {}).build().unwrap();
+        let index = d.index();
+        // B depends on A, but A does not know B as its child
+        let (a, b) = (index.nodes[0].clone(), index.nodes[1].clone());
+        b.lock().unwrap().parents.push(a);
+
+        let err = d.index().validate(false).unwrap_err();
+        assert!(matches!(err, DAGError::Unreachable(names) if names.len() == 1));
+    }
+
+    #[test]
+    fn reject_invalid_map() {
+        let err = build_error(
+            DAGBuilder::new()
+                .task("A", || {})
+                .child(|bd| bd.map("B", "C", || {})),
+        );
+        assert_eq!(DAGError::InvalidMap("B".to_string(), "C".to_string()), err);
+    }
+}
@@ -3,6 +3,8 @@ pub mod tokio_trigger;
 
 use crate::runtime::Runnable;
 use crate::store::Storage;
+use crate::task::dag::{TaskNode, DAG};
+use crate::task::dag_run::DAGRun;
 use crate::task::RunnableHolder;
 use bronzeflow_time::schedule_time::ScheduleTime;
 use bronzeflow_utils::info;
@@ -43,15 +45,23 @@ pub trait TriggerCaller: Send {
         self.trigger(&mut runnable, report_msg)
     }
 
+    /// Trigger a task or a DAG, the tasks of a DAG are run by a [`DAGRun`] in their dependency
+    /// order. A task is run as a DAG of one task with the metadata of the task, so its maximum
+    /// parallelism and overlap policy apply like the ones of a DAG
     #[inline(always)]
-    fn trigger_holder(&self, runnable: RunnableHolder, report_msg: bool) {
-        match runnable {
-            RunnableHolder::Task(task) => self.trigger_safe(task.task, report_msg),
-            RunnableHolder::Dag(dag) => dag.for_all_task(|task| {
-                let runner = task.as_ref().lock().unwrap().task.clone();
-                self.trigger_safe(runner, report_msg);
-            }),
-        }
+    fn trigger_holder(caller: &TriggerCallerType<Self>, runnable: RunnableHolder, report_msg: bool)
+    where
+        Self: Sized + 'static,
+    {
+        let dag = match runnable {
+            RunnableHolder::Task(task) => {
+                let mut dag = DAG::new(vec![Arc::new(Mutex::new(TaskNode::new(task.task)))]);
+                dag.meta = task.meta;
+                dag
+            },
+            RunnableHolder::Dag(dag) => dag,
+        };
+        DAGRun::new(&dag).start(Arc::clone(caller), report_msg);
     }
 }
 
@@ -96,17 +106,8 @@ impl Trigger for ThreadTrigger {
                 // info!("Get dags size: {}", dags.len());
                 let now = ScheduleTime::from_now();
                 for mut d in runs {
-                    if let Some(ref mut tm) = d.time_holder() {
-                        if tm
-                            .lock()
-                            .unwrap()
-                            .schedule
-                            .as_mut()
-                            .unwrap()
-                            .cmp_and_to_next(&now)
-                        {
-                            trigger_caller.lock().unwrap().trigger_holder(d, true);
-                        }
+                    if d.is_due(&now) {
+                        TC::trigger_holder(&trigger_caller, d, true);
                     }
                 }
                 thread::sleep(time::Duration::from_millis(500));
@@ -131,3 +132,39 @@ impl Drop for ThreadTrigger {
         self.stop();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::*;
+    use crate::runtime::limit::ParallelismPolicy;
+    use std::sync::atomic::AtomicUsize;
+    use std::time::Duration;
+
+    #[test]
+    fn limit_runs_of_task_holder() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let task = {
+            let count = Arc::clone(&count);
+            move || {
+                count.fetch_add(1, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(300));
+            }
+        };
+        let mut task = DAG::from(task).to_single_task().unwrap();
+        let mut meta = RunnableMetadata::default();
+        meta.set_maximum_parallelism(1)
+            .set_parallelism_policy(ParallelismPolicy::Skip);
+        task.meta = Some(Arc::new(Mutex::new(meta)));
+        let caller = Arc::new(Mutex::new(DefaultExecutor::new()));
+
+        DefaultExecutor::trigger_holder(&caller, RunnableHolder::Task(task.clone()), false);
+        DefaultExecutor::trigger_holder(&caller, RunnableHolder::Task(task.clone()), false);
+        thread::sleep(Duration::from_millis(800));
+        // The second run is skipped while the first one holds the only slot
+        assert_eq!(1, count.load(Ordering::SeqCst));
+        DefaultExecutor::trigger_holder(&caller, RunnableHolder::Task(task), false);
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(2, count.load(Ordering::SeqCst));
+    }
+}
@@ -80,27 +80,16 @@ impl Trigger for TokioTrigger {
                         // info!("Get dags size: {}", dags.len());
                         let now = ScheduleTime::from_now();
                         for mut d in runs {
-                            if let Some(ref mut tm) = d.time_holder() {
-                                if tm
-                                    .lock()
-                                    .unwrap()
-                                    .schedule
-                                    .as_mut()
-                                    .unwrap()
-                                    .cmp_and_to_next(&now)
-                                {
-                                    // d.run();
-                                    // trigger_caller.lock().unwrap().trigger_dag(d);
-                                    dag_sender
-                                        .send(DAGMessage::PayLoad(d))
-                                        .await
-                                        .map_err(|_| {
-                                            BronzeError::msg(
-                                                "Could not send dag to TriggerEventHandle",
-                                            )
-                                        })
-                                        .ok();
-                                }
+                            if d.is_due(&now) {
+                                // d.run();
+                                // trigger_caller.lock().unwrap().trigger_dag(d);
+                                dag_sender
+                                    .send(DAGMessage::PayLoad(d))
+                                    .await
+                                    .map_err(|_| {
+                                        BronzeError::msg("Could not send dag to TriggerEventHandle")
+                                    })
+                                    .ok();
                             }
                         }
                         sleep(Duration::from_millis(100)).await;
@@ -136,10 +125,7 @@ impl<TC: TriggerCaller + 'static> TriggerEventHandle<TC> {
             match event {
                 DAGMessage::PayLoad(dag) => {
                     // self.trigger_caller.lock().unwrap().trigger_dag(dag, false)
-                    self.trigger_caller
-                        .lock()
-                        .unwrap()
-                        .trigger_holder(dag, true)
+                    TC::trigger_holder(&self.trigger_caller, dag, true)
                     // self.trigger_caller.lock().unwrap().trigger(dag, false)
                 },
             }
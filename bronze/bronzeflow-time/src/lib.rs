@@ -1,4 +1,3 @@
-#![feature(assert_matches)]
 //! # Bronzeflow-Time: a common internal time crate for bronzeflow
 // #![deny(missing_docs)]
 
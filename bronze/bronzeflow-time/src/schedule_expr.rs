@@ -1,3 +1,4 @@
+use crate::schedule_time::ScheduleTime;
 use bronzeflow_utils::ayn_error;
 use chrono::Duration;
 use cron::Schedule;
@@ -30,9 +31,11 @@ pub enum SchedulePreset {
 }
 
 // TODO, support duration schedule
+#[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub struct ScheduleDuration(Duration);
 
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone)]
 pub enum ScheduleExpr {
     Cron(Schedule),
@@ -47,6 +50,38 @@ impl ScheduleExpr {
             _ => Err(BronzeError::msg("Can`t transform to schedule")),
         }
     }
+
+    /// The times of the cron schedule from `start` to `end`, both inclusive, the latest first
+    /// if `reverse` is set. The times are computed while they are iterated
+    pub fn times_between(
+        &self,
+        start: &ScheduleTime,
+        end: &ScheduleTime,
+        reverse: bool,
+    ) -> Result<Box<dyn Iterator<Item = ScheduleTime> + Send>> {
+        if start.dt > end.dt {
+            return Err(ayn_error!("The start {} is after the end {}", start, end));
+        }
+        let schedule = self.to_cron_schedule()?;
+        let (start, end) = (start.dt, end.dt);
+        // The cron times are strictly after or before the given time, in whole seconds
+        let times: Box<dyn Iterator<Item = _> + Send> = match reverse {
+            false => Box::new(
+                schedule
+                    .after_owned(start - Duration::seconds(1))
+                    .skip_while(move |t| *t < start)
+                    .take_while(move |t| *t <= end),
+            ),
+            true => Box::new(
+                schedule
+                    .after_owned(end + Duration::seconds(1))
+                    .rev()
+                    .skip_while(move |t| *t > end)
+                    .take_while(move |t| *t >= start),
+            ),
+        };
+        Ok(Box::new(times.map(ScheduleTime::from)))
+    }
 }
 
 impl<'a> TryFrom<&'a str> for ScheduleExpr {
@@ -120,7 +155,6 @@ impl FixPreset {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::assert_matches::assert_matches;
 
     #[test]
     fn test_parse_fix_reset_from_str() {
@@ -136,16 +170,50 @@ mod tests {
     #[test]
     fn test_parse_schedule_expr_from_str() {
         let not_run = ScheduleExpr::from_str("@none").ok();
-        assert_matches!(not_run, Some(ScheduleExpr::Preset(SchedulePreset::NotRun)));
+        assert!(matches!(
+            not_run,
+            Some(ScheduleExpr::Preset(SchedulePreset::NotRun))
+        ));
 
         let once = ScheduleExpr::from_str("@once").ok();
-        assert_matches!(once, Some(ScheduleExpr::Preset(SchedulePreset::Once)));
+        assert!(matches!(
+            once,
+            Some(ScheduleExpr::Preset(SchedulePreset::Once))
+        ));
 
-        assert_matches!(ScheduleExpr::from_str("error str expr").ok(), None);
+        assert!(ScheduleExpr::from_str("error str expr").is_err());
         let s1: Option<ScheduleExpr> = "0 0 0 * * 1 *".parse().ok();
-        assert_matches!(s1, Some(_));
+        assert!(s1.is_some());
 
         let s2 = "1/10 * * * * * *".parse().ok();
-        assert_matches!(s2, Some(ScheduleExpr::Cron(_)));
+        assert!(matches!(s2, Some(ScheduleExpr::Cron(_))));
+    }
+
+    #[test]
+    fn test_times_between() {
+        let at = |s: &str| s.parse::<ScheduleTime>().unwrap();
+        let daily = ScheduleExpr::from_str("0 0 0 * * *").unwrap();
+        let (start, end) = (at("2024-01-01T00:00:00Z"), at("2024-01-03T00:00:00Z"));
+        let days = vec![
+            at("2024-01-01T00:00:00Z"),
+            at("2024-01-02T00:00:00Z"),
+            at("2024-01-03T00:00:00Z"),
+        ];
+        let times: Vec<_> = daily.times_between(&start, &end, false).unwrap().collect();
+        assert_eq!(days, times);
+        let times: Vec<_> = daily.times_between(&start, &end, true).unwrap().collect();
+        assert_eq!(days.into_iter().rev().collect::<Vec<_>>(), times);
+        let times: Vec<_> = daily
+            .times_between(
+                &at("2024-01-01T00:00:01Z"),
+                &at("2024-01-02T23:00:00Z"),
+                false,
+            )
+            .unwrap()
+            .collect();
+        assert_eq!(vec![at("2024-01-02T00:00:00Z")], times);
+        assert!(daily.times_between(&end, &start, false).is_err());
+        let once = ScheduleExpr::from_str("@once").unwrap();
+        assert!(once.times_between(&times[0], &times[0], false).is_err());
     }
 }
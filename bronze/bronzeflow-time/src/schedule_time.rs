@@ -3,6 +3,7 @@ use bronzeflow_utils::{debug, BronzeError};
 use chrono::{DateTime, Duration, Local, Utc};
 use cron::ScheduleIterator;
 use std::cmp::Ordering;
+use std::fmt::{Display, Formatter};
 use std::iter::Take;
 use std::str::FromStr;
 
@@ -40,9 +41,19 @@ impl ScheduleTime {
     }
     pub fn from_now() -> Self {
         let local_time = Local::now();
-        let utc_time = DateTime::<Utc>::from_utc(local_time.naive_utc(), Utc);
+        let utc_time = DateTime::<Utc>::from_naive_utc_and_offset(local_time.naive_utc(), Utc);
         ScheduleTime::new(utc_time)
     }
+
+    pub fn datetime(&self) -> DateTime<Utc> {
+        self.dt
+    }
+}
+
+impl Display for ScheduleTime {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.dt.to_rfc3339())
+    }
 }
 
 impl From<InternalDateTime> for ScheduleTime {
@@ -83,27 +94,36 @@ impl ScheduleTimeHolder {
         }
     }
 
+    pub fn expr(&self) -> &ScheduleExpr {
+        &self.expr
+    }
+
     pub fn init(&mut self) {
+        self.try_init().unwrap()
+    }
+
+    /// Like [`init`](Self::init), but fails instead of panicking if the expression is not a
+    /// cron expression, or if it has too few schedule times after now
+    pub fn try_init(&mut self) -> bronzeflow_utils::Result<()> {
         let now = ScheduleTime::from_now();
-        match self.expr.clone() {
-            ScheduleExpr::Cron(c) => {
-                let mut times = c.after(&now.dt).take(21);
-                debug!("Now: {}", now.dt);
-                let next_run = times.next().unwrap();
-
-                // Get the minimum time interval from the 20 schedule times
-                let min_interval: Duration = times
-                    .collect::<Vec<InternalDateTime>>()
-                    .windows(2)
-                    .map(|x| x[1] - x[0])
-                    .min()
-                    .unwrap();
-                debug!("min interval is: {}", min_interval.num_seconds());
-                self.min_interval = Some(min_interval);
-                self.next_run = Some(ScheduleTime::from(next_run));
-            },
-            _ => panic!(),
-        }
+        let c = self.expr.to_cron_schedule()?;
+        let mut times = c.after(&now.dt).take(21);
+        debug!("Now: {}", now.dt);
+        let next_run = times
+            .next()
+            .ok_or_else(|| BronzeError::msg("No schedule time after now"))?;
+
+        // Get the minimum time interval from the 20 schedule times
+        let min_interval: Duration = times
+            .collect::<Vec<InternalDateTime>>()
+            .windows(2)
+            .map(|x| x[1] - x[0])
+            .min()
+            .ok_or_else(|| BronzeError::msg("Too few schedule times after now"))?;
+        debug!("min interval is: {}", min_interval.num_seconds());
+        self.min_interval = Some(min_interval);
+        self.next_run = Some(ScheduleTime::from(next_run));
+        Ok(())
     }
 
     fn get_next_times(
@@ -135,6 +155,26 @@ impl ScheduleTimeHolder {
     }
 }
 
+impl ScheduleTimeOp for ScheduleTimeHolder {
+    fn last_run(&self) -> Option<ScheduleTime> {
+        self.last_run.clone()
+    }
+
+    fn next_run(&self) -> Option<ScheduleTime> {
+        self.next_run.clone()
+    }
+
+    fn set_last_run(&mut self, t: &ScheduleTime) -> &mut Self {
+        self.last_run = Some(t.clone());
+        self
+    }
+
+    fn set_next_run(&mut self, t: &ScheduleTime) -> &mut Self {
+        self.next_run = Some(t.clone());
+        self
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::prelude::ScheduleExpr;
@@ -148,4 +188,13 @@ mod tests {
         let mut s = ScheduleTimeHolder::new(expr);
         s.init()
     }
+
+    #[test]
+    fn test_try_init() {
+        let mut once = ScheduleTimeHolder::new(ScheduleExpr::from_str("@once").unwrap());
+        assert!(once.try_init().is_err());
+
+        let past = ScheduleExpr::from_str("0 0 0 1 1 * 2000").unwrap();
+        assert!(ScheduleTimeHolder::new(past).try_init().is_err());
+    }
 }
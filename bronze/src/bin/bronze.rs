@@ -0,0 +1,10 @@
+use bronzeflow::cli;
+use std::process;
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Err(e) = cli::run(&args, &cli::registry()) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
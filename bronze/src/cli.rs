@@ -0,0 +1,229 @@
+// This is a part of bronze.
+
+//! The command line of bronze
+//!
+//! ```text
+//! bronze backfill <definition> --start <time> --end <time> [--max-active-runs <n>] [--reverse]
+//!                 [--params <json>]
+//! ```
+//!
+//! The DAG is built from a definition file, its runnables are created by a [`TaskRegistry`].
+//! The `bronze` binary only knows the `command` runnable of [`registry`], a program with its
+//! own runnables calls [`run`] with its own registry. The times are in RFC 3339, like
+//! `2024-01-01T00:00:00Z`.
+
+use bronzeflow_core::prelude::*;
+use bronzeflow_time::schedule_time::ScheduleTime;
+use bronzeflow_utils::{ayn_error, BronzeError, Result};
+use serde_json::Value;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+pub const USAGE: &str = "\
+Usage: bronze backfill <DEFINITION> --start <TIME> --end <TIME> [OPTIONS]
+
+Run the DAG of the definition file for its schedule times from --start to --end
+
+Options:
+  --start <TIME>            The first logical time, in RFC 3339
+  --end <TIME>              The last logical time, in RFC 3339
+  --max-active-runs <N>     The number of runs in progress at the same time [default: 1]
+  --reverse                 Run the latest logical time first
+  --params <JSON>           The parameters of every run";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BackfillArgs {
+    pub definition: PathBuf,
+    pub start: ScheduleTime,
+    pub end: ScheduleTime,
+    pub max_active_runs: usize,
+    pub reverse: bool,
+    pub params: Value,
+}
+
+impl BackfillArgs {
+    /// Parse the arguments after `backfill`
+    pub fn parse<S: AsRef<str>>(args: &[S]) -> Result<Self> {
+        let (mut definition, mut start, mut end) = (None, None, None);
+        let (mut max_active_runs, mut reverse, mut params) = (1, false, Value::Null);
+        let mut args = args.iter().map(AsRef::as_ref);
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| ayn_error!("Option `{}` needs a value", arg))
+            };
+            match arg {
+                "--start" => start = Some(value()?.parse::<ScheduleTime>()?),
+                "--end" => end = Some(value()?.parse::<ScheduleTime>()?),
+                "--max-active-runs" => {
+                    max_active_runs = value()?
+                        .parse()
+                        .map_err(|e| ayn_error!("Invalid `--max-active-runs`: {}", e))?
+                },
+                "--reverse" => reverse = true,
+                "--params" => params = serde_json::from_str(value()?).map_err(BronzeError::new)?,
+                a if a.starts_with("--") => return Err(ayn_error!("Unknown option `{}`", a)),
+                a if definition.is_none() => definition = Some(PathBuf::from(a)),
+                a => return Err(ayn_error!("Unexpected argument `{}`", a)),
+            }
+        }
+        Ok(BackfillArgs {
+            definition: definition.ok_or_else(|| BronzeError::msg("No definition file"))?,
+            start: start.ok_or_else(|| BronzeError::msg("No `--start` time"))?,
+            end: end.ok_or_else(|| BronzeError::msg("No `--end` time"))?,
+            max_active_runs,
+            reverse,
+            params,
+        })
+    }
+}
+
+/// The registry of the `bronze` binary
+///
+/// The params of a `command` are `{"script": "..."}` to run a shell script, or
+/// `{"program": "...", "args": [...]}`
+pub fn registry() -> TaskRegistry {
+    let mut registry = TaskRegistry::new();
+    registry.register("command", |params: &Value| {
+        if let Some(script) = params["script"].as_str() {
+            return Ok(CommandTask::shell(script));
+        }
+        let program = params["program"]
+            .as_str()
+            .ok_or_else(|| BronzeError::msg("A command needs a `script` or a `program`"))?;
+        let args = params["args"].as_array().cloned().unwrap_or_default();
+        let args = args.iter().map(|a| {
+            a.as_str()
+                .map(str::to_string)
+                .unwrap_or_else(|| a.to_string())
+        });
+        Ok(CommandTask::new(program).args(args))
+    });
+    registry
+}
+
+/// Run the command line `args`, without the name of the program
+pub fn run<S: AsRef<str>>(args: &[S], registry: &TaskRegistry) -> Result<()> {
+    match args.first().map(AsRef::as_ref) {
+        Some("backfill") => {
+            let reports = backfill(&BackfillArgs::parse(&args[1..])?, registry)?;
+            let failed = reports.iter().filter(|(_, r)| !r.is_success()).count();
+            for (time, report) in &reports {
+                let state = if report.is_success() {
+                    "success"
+                } else {
+                    "failed"
+                };
+                println!("{} {}", time, state);
+            }
+            match failed {
+                0 => Ok(()),
+                n => Err(ayn_error!("{} of {} run(s) failed", n, reports.len())),
+            }
+        },
+        None | Some("help" | "-h" | "--help") => {
+            println!("{}", USAGE);
+            Ok(())
+        },
+        Some(command) => Err(ayn_error!("Unknown command `{}`\n\n{}", command, USAGE)),
+    }
+}
+
+/// Backfill the DAG of the definition file, block until all runs have finished
+pub fn backfill(
+    args: &BackfillArgs,
+    registry: &TaskRegistry,
+) -> Result<Vec<(ScheduleTime, DAGRunReport)>> {
+    let definition = DAGDefinition::from_file(&args.definition)?;
+    let schedule = definition
+        .schedule
+        .as_deref()
+        .ok_or_else(|| BronzeError::msg("DAG definition has no schedule"))?;
+    let mut dag = definition.build(registry)?;
+    dag.set_schedule(schedule.try_into()?);
+    Backfill::new(args.start.clone(), args.end.clone())
+        .max_active_runs(args.max_active_runs)
+        .reverse(args.reverse)
+        .params(args.params.clone())
+        .run(&dag, Arc::new(Mutex::new(DefaultExecutor::new())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_backfill_args() {
+        let args = BackfillArgs::parse(&[
+            "etl.yaml",
+            "--start",
+            "2024-01-01T00:00:00Z",
+            "--end",
+            "2024-01-03T00:00:00Z",
+            "--max-active-runs",
+            "4",
+            "--reverse",
+            "--params",
+            r#"{"env": "prod"}"#,
+        ])
+        .unwrap();
+        assert_eq!(PathBuf::from("etl.yaml"), args.definition);
+        assert_eq!(
+            "2024-01-03T00:00:00Z".parse::<ScheduleTime>().unwrap(),
+            args.end
+        );
+        assert_eq!((4, true), (args.max_active_runs, args.reverse));
+        assert_eq!("prod", args.params["env"]);
+
+        assert!(BackfillArgs::parse(&["etl.yaml", "--start", "2024-01-01T00:00:00Z"]).is_err());
+        assert!(BackfillArgs::parse(&["etl.yaml", "--start"]).is_err());
+        assert!(BackfillArgs::parse(&["etl.yaml", "--unknown"]).is_err());
+        assert!(run(&["deploy"], &registry()).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn backfill_definition() {
+        let dir = std::env::temp_dir().join(format!("bronze-cli-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (file, out) = (dir.join("daily.json"), dir.join("times.txt"));
+        let definition = serde_json::json!({
+            "name": "daily",
+            "schedule": "0 0 0 * * *",
+            "tasks": [{
+                "name": "record",
+                "runnable": "command",
+                "params": {"script": format!("echo \"$BRONZE_LOGICAL_TIME\" >> {}", out.display())},
+            }],
+        });
+        std::fs::write(&file, definition.to_string()).unwrap();
+
+        let args = [
+            file.to_str().unwrap(),
+            "--start",
+            "2024-01-01T00:00:00Z",
+            "--end",
+            "2024-01-03T12:00:00Z",
+            "--max-active-runs",
+            "2",
+        ];
+        let mut args: Vec<_> = args.iter().map(|a| a.to_string()).collect();
+        args.insert(0, "backfill".to_string());
+        run(&args, &registry()).unwrap();
+        let mut times: Vec<_> = std::fs::read_to_string(&out)
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect();
+        times.sort();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            vec![
+                "2024-01-01T00:00:00+00:00",
+                "2024-01-02T00:00:00+00:00",
+                "2024-01-03T00:00:00+00:00"
+            ],
+            times
+        );
+    }
+}